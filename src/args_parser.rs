@@ -1,3 +1,8 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::lexer;
+
 enum State {
     Normal,
     InQuotes,
@@ -6,27 +11,71 @@ enum State {
 }
 
 
-pub fn parse_args (cmd: &str) -> Vec<String> {
+/// Reads the name after a `$`: `${name}`, a special parameter like `$!` or a plain `$name`.
+fn read_parameter (chars: &mut Peekable<Chars>) -> Option<String> {
+    match chars.peek()? {
+        '{' => {
+            chars.next();
+            let mut name = String::new();
+
+            for ch in chars.by_ref() {
+                if ch == '}' { break; }
+                name.push(ch);
+            }
+
+            Some(name)
+        }
+        '!' | '?' | '$' | '#' | '@' | '*' | '0'..='9' => chars.next().map(String::from),
+        ch if ch.is_ascii_alphabetic() || *ch == '_' => {
+            let mut name = String::new();
+
+            while let Some(&ch) = chars.peek() {
+                if !ch.is_ascii_alphanumeric() && ch != '_' { break; }
+                name.push(ch);
+                chars.next();
+            }
+
+            Some(name)
+        }
+        _ => None
+    }
+}
+
+/// Splits `cmd` into words, removing quotes and expanding `$parameters` through `vars`.
+pub fn parse_args (cmd: &str, vars: &dyn Fn(&str) -> Option<String>) -> Vec<String> {
     let mut tokens = Vec::new(); 
     let mut arg = String::new();
+    // `""` is still an argument, an empty `$var` isn't
+    let mut quoted = false;
     let mut state = State::Normal;
 
-    let cmd = cmd.replace("\n", " ");
+    let mut chars = cmd.chars().peekable();
+
+    while let Some(char) = chars.next() {
+        if char == '$' && matches!(state, State::Normal | State::InDoubleQuotes) {
+            match read_parameter(&mut chars) {
+                Some(name) => arg.push_str(&vars(&name).unwrap_or_default()),
+                None => arg.push('$'),
+            }
+            continue;
+        }
 
-    for char in cmd.chars() {
         match state {
             State::Normal => {
                 match char {
                     '\'' => {
                         state = State::InQuotes;
+                        quoted = true;
                     }
                     '"' => {
                         state = State::InDoubleQuotes;
+                        quoted = true;
                     }
-                    ' ' => {
-                        if arg.is_empty() { continue; }
+                    ' ' | '\n' => {
+                        if arg.is_empty() && !quoted { continue; }
                         tokens.push(arg);
                         arg = String::new();
+                        quoted = false;
                     }
                     '\\' => {
                         state = State::Escape(false);
@@ -71,14 +120,151 @@ pub fn parse_args (cmd: &str) -> Vec<String> {
         }
     };
 
-    if !arg.is_empty() { tokens.push(arg); };
+    if !arg.is_empty() || quoted { tokens.push(arg); };
 
     tokens
 }
 
+/// Expands a word that's matched against rather than used as it is: what was quoted
+/// gets a backslash in front of each of the `special` characters, so it only matches itself.
+fn expand_literally (word: &str, vars: &dyn Fn(&str) -> Option<String>, special: &str) -> String {
+    let mut result = String::new();
+    let mut state = State::Normal;
+    let mut chars = word.chars().peekable();
+
+    let literal = |result: &mut String, ch: char| {
+        if special.contains(ch) { result.push('\\'); }
+        result.push(ch);
+    };
+
+    while let Some(char) = chars.next() {
+        if char == '$' && matches!(state, State::Normal | State::InDoubleQuotes) {
+            let quoted = matches!(state, State::InDoubleQuotes);
+
+            match read_parameter(&mut chars) {
+                Some(name) if quoted => vars(&name).unwrap_or_default().chars().for_each(|ch| literal(&mut result, ch)),
+                Some(name) => result.push_str(&vars(&name).unwrap_or_default()),
+                None if quoted => literal(&mut result, '$'),
+                None => result.push('$'),
+            }
+            continue;
+        }
+
+        match state {
+            State::Normal => match char {
+                '\'' => state = State::InQuotes,
+                '"' => state = State::InDoubleQuotes,
+                '\\' => state = State::Escape(false),
+                ch => result.push(ch),
+            },
+            State::InQuotes => match char {
+                '\'' => state = State::Normal,
+                ch => literal(&mut result, ch),
+            },
+            State::InDoubleQuotes => match char {
+                '"' => state = State::Normal,
+                '\\' => state = State::Escape(true),
+                ch => literal(&mut result, ch),
+            },
+            State::Escape(quote) => {
+                state = if quote { State::InDoubleQuotes } else { State::Normal };
+
+                if !['\\', '"', '$', '`'].contains(&char) && quote {
+                    literal(&mut result, '\\');
+                }
+
+                literal(&mut result, char);
+            }
+        }
+    }
+
+    result
+}
+
+/// Expands the pattern on the right of `==` in `[[ ]]`, quoted parts match literally.
+pub fn expand_pattern (word: &str, vars: &dyn Fn(&str) -> Option<String>) -> String {
+    expand_literally(word, vars, "\\*?[]")
+}
+
+/// Expands the regex on the right of `=~` in `[[ ]]`, quoted parts match literally.
+pub fn expand_regex (word: &str, vars: &dyn Fn(&str) -> Option<String>) -> String {
+    expand_literally(word, vars, "\\.[]()*+?{}|^$")
+}
+
+/// Replaces every unquoted `<(cmd)` and `>(cmd)` in a raw word with what `spawn` returns for
+/// the command, `spawn` being told whether the command's output is read (`<`) or its input written.
+pub fn substitute_processes (word: &str, spawn: &mut dyn FnMut(&str, bool) -> Result<String, String>) -> Result<String, String> {
+    let chars: Vec<char> = word.chars().collect();
+    let mut result = String::new();
+    let mut state = State::Normal;
+    let mut idx = 0;
+
+    while idx < chars.len() {
+        let char = chars[idx];
+
+        match state {
+            State::Normal => match char {
+                '<' | '>' if chars.get(idx + 1) == Some(&'(') => {
+                    if let Some(end) = lexer::closing_paren(&chars, idx + 1) {
+                        let command: String = chars[idx + 2..end].iter().collect();
+                        result.push_str(&spawn(&command, char == '<')?);
+                        idx = end + 1;
+                        continue;
+                    }
+                }
+                '\'' => state = State::InQuotes,
+                '"' => state = State::InDoubleQuotes,
+                '\\' => state = State::Escape(false),
+                _ => {}
+            },
+            State::InQuotes => if char == '\'' { state = State::Normal },
+            State::InDoubleQuotes => match char {
+                '"' => state = State::Normal,
+                '\\' => state = State::Escape(true),
+                _ => {}
+            },
+            State::Escape(quote) => state = if quote { State::InDoubleQuotes } else { State::Normal },
+        }
+
+        result.push(char);
+        idx += 1;
+    }
+
+    Ok(result)
+}
+
+/// Expands the body of a here-document: `$parameters` are replaced, and a backslash only
+/// escapes `$`, `` ` ``, `\` and newlines. Quotes are kept as they are.
+pub fn expand_here_doc (body: &str, vars: &dyn Fn(&str) -> Option<String>) -> String {
+    let mut result = String::new();
+    let mut chars = body.chars().peekable();
+
+    while let Some(char) = chars.next() {
+        match char {
+            '$' => match read_parameter(&mut chars) {
+                Some(name) => result.push_str(&vars(&name).unwrap_or_default()),
+                None => result.push('$'),
+            },
+            '\\' => match chars.peek() {
+                Some('\n') => { chars.next(); }
+                Some(&ch) if ['\\', '$', '`'].contains(&ch) => {
+                    result.push(ch);
+                    chars.next();
+                }
+                _ => result.push('\\'),
+            },
+            ch => result.push(ch),
+        }
+    }
+
+    result
+}
+
 #[cfg(test)]
 mod tests {
-    use super::parse_args;
+    fn parse_args(cmd: &str) -> Vec<String> {
+        super::parse_args(cmd, &|_| None)
+    }
 
     #[test]
     fn test_basic() {
@@ -178,4 +364,61 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_empty_quoted_args() {
+        assert_eq!(parse_args("test \"\" = '' $unset"), vec!["test", "", "=", ""]);
+    }
+
+    #[test]
+    fn test_patterns_and_regexes() {
+        let vars = |name: &str| (name == "x").then(|| "a*".to_string());
+
+        assert_eq!(super::expand_pattern(r#"$x"$x"'?'\[[ab]"#, &vars), r"a*a\*\?\[[ab]");
+        assert_eq!(super::expand_regex(r#"^(a|b)"a.b"'$'$"#, &vars), r"^(a|b)a\.b\$$");
+    }
+
+    #[test]
+    fn test_quoted_newlines() {
+        assert_eq!(parse_args("echo 'a\nb' c\nd"), vec!["echo", "a\nb", "c", "d"]);
+    }
+
+    #[test]
+    fn test_parameter_expansion() {
+        let vars = |name: &str| match name {
+            "!" => Some("4242".to_string()),
+            "HOME" => Some("/home/nya".to_string()),
+            _ => None,
+        };
+
+        assert_eq!(
+            super::parse_args(r#"echo $! "$HOME/x" '$HOME' ${HOME}y \$HOME $MISSING $"#, &vars),
+            vec!["echo", "4242", "/home/nya/x", "$HOME", "/home/nyay", "$HOME", "$"]
+        );
+    }
+
+    #[test]
+    fn test_here_doc_expansion() {
+        let vars = |name: &str| (name == "USER").then(|| "nya".to_string());
+
+        assert_eq!(
+            super::expand_here_doc("hi $USER, \"$USER\" '${USER}'\n\\$USER costs \\\\ \\z\\\nend\n", &vars),
+            "hi nya, \"nya\" 'nya'\n$USER costs \\ \\zend\n"
+        );
+    }
+
+    #[test]
+    fn test_process_substitution() {
+        let mut spawned = Vec::new();
+        let mut spawn = |command: &str, input: bool| {
+            spawned.push((command.to_string(), input));
+            Ok(format!("/dev/fd/{}", 10 + spawned.len()))
+        };
+
+        assert_eq!(
+            super::substitute_processes(r#"--a=<(sort "x)") '<(no)' \<(no) >(tee (y))"#, &mut spawn),
+            Ok(r#"--a=/dev/fd/11 '<(no)' \<(no) /dev/fd/12"#.to_string())
+        );
+        assert_eq!(spawned, vec![(r#"sort "x)""#.to_string(), true), ("tee (y)".to_string(), false)]);
+    }
 }
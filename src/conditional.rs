@@ -0,0 +1,444 @@
+use std::ffi::CString;
+
+use crate::utils;
+
+/// How an operand of `[[ ]]` gets expanded. On the right of `==` and `=~` whatever was
+/// left unquoted keeps its meaning in the pattern or the regex.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Word,
+    Pattern,
+    Regex
+}
+
+const UNARY: &[&str] = &[
+    "-a", "-b", "-c", "-d", "-e", "-f", "-g", "-h", "-k", "-n", "-p", "-r",
+    "-s", "-t", "-u", "-w", "-x", "-z", "-G", "-L", "-N", "-O", "-S",
+];
+
+const BINARY: &[&str] = &[
+    "=", "==", "!=", "<", ">", "-eq", "-ne", "-lt", "-le", "-gt", "-ge", "-nt", "-ot", "-ef",
+];
+
+struct Evaluator<'a> {
+    args: &'a [&'a str],
+    pos: usize,
+    // `[[ ]]`: operands are still raw words, `&&` and `||` join and `==` matches patterns
+    extended: bool,
+    expand: &'a mut dyn FnMut(&str, Operand) -> String,
+    // the right side of an `&&` or `||` that's already decided is parsed but not evaluated
+    skipping: bool,
+    // the groups of the last `=~`, empty if it didn't match
+    rematch: Option<Vec<String>>
+}
+
+/// Evaluates the arguments of `test` (or `[` without its `]`).
+/// Up to four arguments their number decides what they mean, as POSIX wants it.
+pub fn test (args: &[&str]) -> Result<bool, String> {
+    let is_binary = |op: &str| BINARY.contains(&op) || op == "-a" || op == "-o";
+
+    match args {
+        [] => Ok(false),
+        [word] => Ok(!word.is_empty()),
+        ["!", word] => Ok(word.is_empty()),
+        [op, operand] if UNARY.contains(op) => Ok(unary(op, operand)),
+        [op, _] => Err(format!("{op}: unary operator expected")),
+        [lhs, op, rhs] if is_binary(op) => binary(lhs, op, rhs, false),
+        ["!", rest @ ..] if args.len() <= 4 => test(rest).map(|result| !result),
+        ["(", word, ")"] => Ok(!word.is_empty()),
+        ["(", lhs, rhs, ")"] => test(&[lhs, rhs]),
+        _ => {
+            let mut expand = |word: &str, _| word.to_string();
+            Evaluator::new(args, false, &mut expand).evaluate()
+        }
+    }
+}
+
+/// Evaluates the words between `[[` and `]]`, expanding operands through `expand` as they're needed.
+/// Also returns what BASH_REMATCH becomes if an `=~` ran.
+pub fn conditional (words: &[String], expand: &mut dyn FnMut(&str, Operand) -> String) -> Result<(bool, Option<Vec<String>>), String> {
+    let args: Vec<&str> = words.iter().map(String::as_str).collect();
+    let mut evaluator = Evaluator::new(&args, true, expand);
+    let result = evaluator.evaluate()?;
+
+    Ok((result, evaluator.rematch))
+}
+
+impl<'a> Evaluator<'a> {
+    fn new (args: &'a [&'a str], extended: bool, expand: &'a mut dyn FnMut(&str, Operand) -> String) -> Self {
+        Self { args, pos: 0, extended, expand, skipping: false, rematch: None }
+    }
+
+    fn peek (&self) -> Option<&'a str> {
+        self.args.get(self.pos).copied()
+    }
+
+    fn evaluate (&mut self) -> Result<bool, String> {
+        let result = self.or()?;
+
+        match self.peek() {
+            None => Ok(result),
+            Some(word) => Err(format!("{word}: unexpected argument")),
+        }
+    }
+
+    fn or (&mut self) -> Result<bool, String> {
+        let op = if self.extended { "||" } else { "-o" };
+        let mut result = self.and()?;
+
+        while self.peek() == Some(op) {
+            self.pos += 1;
+
+            let skipping = self.skipping;
+            self.skipping |= result;
+            let rhs = self.and()?;
+            self.skipping = skipping;
+
+            result = result || rhs;
+        }
+
+        Ok(result)
+    }
+
+    fn and (&mut self) -> Result<bool, String> {
+        let op = if self.extended { "&&" } else { "-a" };
+        let mut result = self.not()?;
+
+        while self.peek() == Some(op) {
+            self.pos += 1;
+
+            let skipping = self.skipping;
+            self.skipping |= !result;
+            let rhs = self.not()?;
+            self.skipping = skipping;
+
+            result = result && rhs;
+        }
+
+        Ok(result)
+    }
+
+    fn not (&mut self) -> Result<bool, String> {
+        if self.peek() == Some("!") {
+            self.pos += 1;
+            return self.not().map(|result| !result);
+        }
+
+        self.primary()
+    }
+
+    fn primary (&mut self) -> Result<bool, String> {
+        let Some(word) = self.peek() else {
+            return Err("argument expected".to_string());
+        };
+
+        if word == "(" {
+            self.pos += 1;
+            let result = self.or()?;
+
+            if self.peek() != Some(")") {
+                return Err("`)' expected".to_string());
+            }
+            self.pos += 1;
+            return Ok(result);
+        }
+
+        let op = self.args.get(self.pos + 1).copied();
+        let is_binary = |op: &str| BINARY.contains(&op) || (self.extended && op == "=~");
+
+        if let (Some(op), Some(rhs)) = (op, self.args.get(self.pos + 2).copied()) {
+            if is_binary(op) {
+                self.pos += 3;
+                if self.skipping { return Ok(false); }
+
+                let lhs = self.expand(word, Operand::Word);
+                let rhs = match op {
+                    "=" | "==" | "!=" => self.expand(rhs, Operand::Pattern),
+                    "=~" => self.expand(rhs, Operand::Regex),
+                    _ => self.expand(rhs, Operand::Word),
+                };
+
+                if op == "=~" {
+                    let groups = regex_match(&lhs, &rhs)?;
+                    let matched = groups.is_some();
+                    self.rematch = Some(groups.unwrap_or_default());
+                    return Ok(matched);
+                }
+
+                return binary(&lhs, op, &rhs, self.extended);
+            }
+        }
+
+        if let Some(operand) = op.filter(|_| UNARY.contains(&word)) {
+            self.pos += 2;
+            if self.skipping { return Ok(false); }
+
+            let operand = self.expand(operand, Operand::Word);
+            return Ok(unary(word, &operand));
+        }
+
+        self.pos += 1;
+        if self.skipping { return Ok(false); }
+
+        Ok(!self.expand(word, Operand::Word).is_empty())
+    }
+
+    fn expand (&mut self, word: &str, operand: Operand) -> String {
+        (self.expand)(word, operand)
+    }
+}
+
+fn lstat (path: &str) -> Option<libc::stat> {
+    let c_path = CString::new(path).ok()?;
+
+    unsafe {
+        let mut st: libc::stat = std::mem::zeroed();
+        if libc::lstat(c_path.as_ptr(), &mut st) != 0 {
+            return None;
+        }
+        Some(st)
+    }
+}
+
+fn access (path: &str, mode: i32) -> bool {
+    let Ok(c_path) = CString::new(path) else { return false };
+    unsafe { libc::access(c_path.as_ptr(), mode) == 0 }
+}
+
+fn unary (op: &str, operand: &str) -> bool {
+    use libc::{ S_IFMT, S_IFREG, S_IFDIR, S_IFBLK, S_IFCHR, S_IFIFO, S_IFSOCK, S_IFLNK };
+
+    match op {
+        "-z" => return operand.is_empty(),
+        "-n" => return !operand.is_empty(),
+        "-t" => return operand.parse::<i32>().is_ok_and(|fd| unsafe { libc::isatty(fd) } == 1),
+        "-r" => return access(operand, libc::R_OK),
+        "-w" => return access(operand, libc::W_OK),
+        "-x" => return access(operand, libc::X_OK),
+        "-h" | "-L" => return lstat(operand).is_some_and(|st| st.st_mode & S_IFMT == S_IFLNK),
+        _ => {}
+    }
+
+    let Some(st) = utils::stat(operand) else { return false };
+    let kind = st.st_mode & S_IFMT;
+
+    match op {
+        "-a" | "-e" => true,
+        "-f" => kind == S_IFREG,
+        "-d" => kind == S_IFDIR,
+        "-b" => kind == S_IFBLK,
+        "-c" => kind == S_IFCHR,
+        "-p" => kind == S_IFIFO,
+        "-S" => kind == S_IFSOCK,
+        "-s" => st.st_size > 0,
+        "-g" => st.st_mode & libc::S_ISGID != 0,
+        "-u" => st.st_mode & libc::S_ISUID != 0,
+        "-k" => st.st_mode & libc::S_ISVTX != 0,
+        "-O" => st.st_uid == unsafe { libc::geteuid() },
+        "-G" => st.st_gid == unsafe { libc::getegid() },
+        "-N" => (st.st_mtime, st.st_mtime_nsec) > (st.st_atime, st.st_atime_nsec),
+        _ => false,
+    }
+}
+
+fn integer (word: &str, extended: bool) -> Result<i64, String> {
+    let trimmed = word.trim();
+
+    // inside `[[ ]]` an empty operand counts as 0, like in arithmetic
+    if extended && trimmed.is_empty() {
+        return Ok(0);
+    }
+
+    trimmed.parse().map_err(|_| format!("{word}: integer expression expected"))
+}
+
+fn binary (lhs: &str, op: &str, rhs: &str, extended: bool) -> Result<bool, String> {
+    let result = match op {
+        "=" | "==" if extended => fnmatch(rhs, lhs),
+        "!=" if extended => !fnmatch(rhs, lhs),
+        "=" | "==" => lhs == rhs,
+        "!=" => lhs != rhs,
+        "<" => lhs < rhs,
+        ">" => lhs > rhs,
+        "-a" => !lhs.is_empty() && !rhs.is_empty(),
+        "-o" => !lhs.is_empty() || !rhs.is_empty(),
+        "-nt" => match (utils::get_mtime(lhs), utils::get_mtime(rhs)) {
+            (Some(lhs), Some(rhs)) => lhs > rhs,
+            (lhs, rhs) => lhs.is_some() && rhs.is_none(),
+        },
+        "-ot" => match (utils::get_mtime(lhs), utils::get_mtime(rhs)) {
+            (Some(lhs), Some(rhs)) => lhs < rhs,
+            (lhs, rhs) => lhs.is_none() && rhs.is_some(),
+        },
+        "-ef" => match (utils::stat(lhs), utils::stat(rhs)) {
+            (Some(lhs), Some(rhs)) => lhs.st_dev == rhs.st_dev && lhs.st_ino == rhs.st_ino,
+            _ => false,
+        },
+        op => {
+            let (lhs, rhs) = (integer(lhs, extended)?, integer(rhs, extended)?);
+
+            match op {
+                "-eq" => lhs == rhs,
+                "-ne" => lhs != rhs,
+                "-lt" => lhs < rhs,
+                "-le" => lhs <= rhs,
+                "-gt" => lhs > rhs,
+                "-ge" => lhs >= rhs,
+                op => return Err(format!("{op}: binary operator expected")),
+            }
+        }
+    };
+
+    Ok(result)
+}
+
+fn fnmatch (pattern: &str, text: &str) -> bool {
+    let (Ok(pattern), Ok(text)) = (CString::new(pattern), CString::new(text)) else { return false };
+    unsafe { libc::fnmatch(pattern.as_ptr(), text.as_ptr(), 0) == 0 }
+}
+
+/// Where the bracket expression starting at `start` ends: its `]`, not one that's the first
+/// character of the list or part of a `[:class:]`, `[=e=]` or `[.sym.]`.
+fn bracket_end (chars: &[char], start: usize) -> usize {
+    let mut idx = start + 1;
+    if chars.get(idx) == Some(&'^') { idx += 1; }
+    if chars.get(idx) == Some(&']') { idx += 1; }
+
+    while idx < chars.len() {
+        match (chars[idx], chars.get(idx + 1)) {
+            ('[', Some(&kind)) if ":=.".contains(kind) => {
+                let close = (idx + 2..chars.len().saturating_sub(1)).find(|&end| chars[end] == kind && chars[end + 1] == ']');
+                idx = close.map_or(chars.len(), |end| end + 2);
+            }
+            (']', _) => return idx,
+            _ => idx += 1,
+        }
+    }
+
+    chars.len()
+}
+
+/// How many groups the extended regex has. regex_t keeps its count private, an unescaped `(`
+/// outside a bracket expression is where one starts.
+fn group_count (regex: &str) -> usize {
+    let chars: Vec<char> = regex.chars().collect();
+    let mut groups = 0;
+    let mut idx = 0;
+
+    while idx < chars.len() {
+        match chars[idx] {
+            '\\' => idx += 1,
+            '(' => groups += 1,
+            '[' => idx = bracket_end(&chars, idx),
+            _ => {}
+        }
+        idx += 1;
+    }
+
+    groups
+}
+
+/// Matches `text` against the extended regex, returning the whole match followed by its groups.
+fn regex_match (text: &str, regex: &str) -> Result<Option<Vec<String>>, String> {
+    let invalid = || format!("{regex}: invalid regular expression");
+    let (Ok(c_regex), Ok(c_text)) = (CString::new(regex), CString::new(text)) else { return Err(invalid()) };
+
+    let groups = group_count(regex);
+
+    unsafe {
+        let mut compiled: libc::regex_t = std::mem::zeroed();
+        if libc::regcomp(&mut compiled, c_regex.as_ptr(), libc::REG_EXTENDED) != 0 {
+            return Err(invalid());
+        }
+
+        let mut matches = vec![libc::regmatch_t { rm_so: -1, rm_eo: -1 }; groups + 1];
+        let found = libc::regexec(&compiled, c_text.as_ptr(), matches.len(), matches.as_mut_ptr(), 0) == 0;
+        libc::regfree(&mut compiled);
+
+        if !found { return Ok(None); }
+
+        let bytes = text.as_bytes();
+        Ok(Some(matches.iter().map(|m| match (usize::try_from(m.rm_so), usize::try_from(m.rm_eo)) {
+            (Ok(start), Ok(end)) => String::from_utf8_lossy(&bytes[start..end]).to_string(),
+            _ => String::new(),
+        }).collect()))
+    }
+}
+
+#[cfg(test)]
+mod conditional_tests {
+    use super::*;
+    use crate::args_parser::{expand_pattern, expand_regex, parse_args};
+    use crate::lexer::{lex, Token};
+
+    fn conditional (input: &str) -> Result<(bool, Option<Vec<String>>), String> {
+        let vars = |name: &str| (name == "x").then(|| "a b*".to_string());
+        let mut words: Vec<String> = lex(&format!("[[ {input} ]]")).tokens.into_iter().filter_map(|token| match token {
+            Token::Word(word) => Some(word),
+            Token::Op(op) => Some(op.to_string()),
+            _ => None,
+        }).collect();
+        words.remove(0);
+        words.pop();
+
+        super::conditional(&words, &mut |word, operand| match operand {
+            Operand::Word => parse_args(word, &vars).join(" "),
+            Operand::Pattern => expand_pattern(word, &vars),
+            Operand::Regex => expand_regex(word, &vars),
+        })
+    }
+
+    #[test]
+    fn posix_argument_counts () {
+        assert_eq!(test(&[]), Ok(false));
+        assert_eq!(test(&["-n"]), Ok(true));
+        assert_eq!(test(&[""]), Ok(false));
+        assert_eq!(test(&["!", ""]), Ok(true));
+        assert_eq!(test(&["-z", ""]), Ok(true));
+        assert_eq!(test(&["!", "=", "x"]), Ok(false));
+        assert_eq!(test(&["=", "=", "="]), Ok(true));
+        assert_eq!(test(&["(", "-z", "x", ")"]), Ok(false));
+        assert_eq!(test(&["a", "-a", ""]), Ok(false));
+        assert_eq!(test(&["1", "-lt", "2", "-a", "!", "b", "=", "c"]), Ok(true));
+        assert_eq!(test(&["1", "-eq", "2", "-o", "(", "3", "-ge", "3", ")"]), Ok(true));
+        assert_eq!(test(&["x", "-eq", "1"]), Err("x: integer expression expected".to_string()));
+        assert_eq!(test(&["-q", "x"]), Err("-q: unary operator expected".to_string()));
+    }
+
+    #[test]
+    fn files () {
+        assert_eq!(test(&["-d", "/"]), Ok(true));
+        assert_eq!(test(&["-f", "/"]), Ok(false));
+        assert_eq!(test(&["-e", "/nonexistent/file"]), Ok(false));
+        assert_eq!(test(&["/", "-ef", "/."]), Ok(true));
+        assert_eq!(test(&["/", "-nt", "/nonexistent/file"]), Ok(true));
+    }
+
+    #[test]
+    fn double_brackets () {
+        assert_eq!(conditional("$x == a*"), Ok((true, None)));
+        assert_eq!(conditional("$x == 'a'*"), Ok((true, None)));
+        assert_eq!(conditional("$x == \"a*\""), Ok((false, None)));
+        assert_eq!(conditional("$x == \"$x\""), Ok((true, None)));
+        assert_eq!(conditional("-n $x && ( 1 -gt 2 || ! -z '' )"), Ok((false, None)));
+        assert_eq!(conditional("-z '' || 1 -eq x"), Ok((true, None)), "the right side isn't evaluated");
+        assert_eq!(conditional("b < c"), Ok((true, None)));
+    }
+
+    #[test]
+    fn regex_groups () {
+        let groups = |groups: &[&str]| Some(groups.iter().map(|g| g.to_string()).collect());
+
+        assert_eq!(conditional("foo-123 =~ ^([a-z]+)-([0-9]+)$"), Ok((true, groups(&["foo-123", "foo", "123"]))));
+        assert_eq!(conditional("ab =~ a(x)?b"), Ok((true, groups(&["ab", ""]))));
+        assert_eq!(conditional("a.c =~ 'a.c'"), Ok((true, groups(&["a.c"]))));
+        assert_eq!(conditional("abc =~ 'a.c'"), Ok((false, groups(&[]))));
+        assert!(conditional("a =~ (").is_err());
+
+        // a `(` in a bracket expression, or escaped, doesn't start a group
+        assert_eq!(regex_match("(ab", "[(]a(b)"), Ok(Some(vec!["(ab".to_string(), "b".to_string()])));
+        assert_eq!(group_count("[]()][^]()]\\(y(x)"), 1);
+        assert_eq!(group_count("[[:alpha:](]+(x)"), 1);
+        assert_eq!(group_count("[[.(.]](x)"), 1);
+    }
+}
@@ -0,0 +1,217 @@
+use std::ffi::CString;
+
+use crate::utils::{self, get_environment, get_pwd};
+
+/// Whether both paths name the same file.
+fn same_file (a: &str, b: &str) -> bool {
+    match (utils::stat(a), utils::stat(b)) {
+        (Some(a), Some(b)) => a.st_dev == b.st_dev && a.st_ino == b.st_ino,
+        _ => false,
+    }
+}
+
+pub fn is_directory (path: &str) -> bool {
+    utils::stat(path).is_some_and(|st| st.st_mode & libc::S_IFMT == libc::S_IFDIR)
+}
+
+/// The directory we're in as `cd` left it: $PWD keeps the symlinks we went through,
+/// unless it isn't the current directory anymore.
+pub fn current_dir () -> String {
+    match get_environment("PWD") {
+        Some(pwd) if pwd.starts_with('/') && same_file(pwd, ".") => pwd.to_string(),
+        _ => get_pwd(),
+    }
+}
+
+/// `dir` made absolute against `pwd`, with `.` and `..` removed without looking at the filesystem.
+/// `..` after a symlink goes back to where the symlink is, not to the parent of its target.
+pub fn logical_path (pwd: &str, dir: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    let start = if dir.starts_with('/') { "" } else { pwd };
+
+    for part in start.split('/').chain(dir.split('/')) {
+        match part {
+            "" | "." => {}
+            ".." => { parts.pop(); }
+            part => parts.push(part),
+        }
+    }
+
+    format!("/{}", parts.join("/"))
+}
+
+/// `~` and `~/dir` with $HOME in place of the `~`.
+pub fn expand_tilde (dir: &str) -> String {
+    let home = get_environment("HOME").unwrap_or("/");
+
+    match dir.strip_prefix('~') {
+        Some("") => home.to_string(),
+        Some(rest) if rest.starts_with('/') => format!("{}{rest}", home.trim_end_matches('/')),
+        _ => dir.to_string(),
+    }
+}
+
+/// `dir` with $HOME shown as `~`, the way `dirs` prints it.
+pub fn abbreviate (dir: &str) -> String {
+    let Some(home) = get_environment("HOME").map(|home| home.trim_end_matches('/')).filter(|home| !home.is_empty()) else {
+        return dir.to_string();
+    };
+
+    match dir.strip_prefix(home) {
+        Some("") => "~".to_string(),
+        Some(rest) if rest.starts_with('/') => format!("~{rest}"),
+        _ => dir.to_string(),
+    }
+}
+
+/// Looks for a relative `dir` in the directories of `cdpath`. Returns the path it was found at,
+/// unless that's just `dir` in the current directory.
+pub fn search_cdpath (dir: &str, cdpath: &str) -> Option<String> {
+    let first = dir.split('/').next().unwrap_or_default();
+    if dir.starts_with('/') || first == "." || first == ".." {
+        return None;
+    }
+
+    for entry in cdpath.split(':') {
+        if entry.is_empty() || entry == "." {
+            if is_directory(dir) { return None; }
+            continue;
+        }
+
+        let candidate = format!("{}/{dir}", entry.trim_end_matches('/'));
+        if is_directory(&candidate) {
+            return Some(candidate);
+        }
+    }
+
+    None
+}
+
+/// The directories a `cd` argument starting with `word` can be, each with a trailing `/`.
+/// Hidden ones only show up once the word asks for them with a `.`.
+pub fn complete_dir (word: &str) -> Vec<String> {
+    let (dir, prefix) = match word.rsplit_once('/') {
+        Some((dir, prefix)) => (format!("{dir}/"), prefix),
+        None => (String::new(), word),
+    };
+
+    let listed = match dir.as_str() {
+        "" => ".".to_string(),
+        dir => expand_tilde(dir),
+    };
+
+    let Ok(names) = utils::read_directory(&listed) else { return Vec::new() };
+
+    let mut dirs: Vec<String> = names
+        .filter(|name| name != "." && name != ".." && name.starts_with(prefix))
+        .filter(|name| prefix.starts_with('.') || !name.starts_with('.'))
+        .filter(|name| is_directory(&format!("{listed}/{name}")))
+        .map(|name| format!("{dir}{name}/"))
+        .collect();
+
+    dirs.sort();
+    dirs
+}
+
+/// Changes directory and keeps $PWD and $OLDPWD up to date, returns the new $PWD.
+/// With `physical` symlinks are resolved, otherwise `..` is taken lexically from $PWD.
+pub fn change_dir (dir: &str, physical: bool) -> Result<String, String> {
+    let chdir = |path: &str| {
+        let path = CString::new(path).map_err(|_| "invalid path".to_string())?;
+
+        match unsafe { libc::chdir(path.as_ptr()) } {
+            0 => Ok(()),
+            _ => Err(utils::strerror(utils::errno())),
+        }
+    };
+
+    let old = current_dir();
+
+    let new = if physical {
+        chdir(dir)?;
+        get_pwd()
+    } else {
+        let logical = logical_path(&old, dir);
+
+        // the lexical path can be gone where the real one isn't, like `..` from a removed directory
+        match chdir(&logical) {
+            Ok(()) => logical,
+            Err(error) => {
+                chdir(dir).map_err(|_| error)?;
+                get_pwd()
+            }
+        }
+    };
+
+    utils::set_environment("OLDPWD", &old);
+    utils::set_environment("PWD", &new);
+
+    Ok(new)
+}
+
+/// Where `+N` or `-N` points in a stack of `len` directories (the current one included),
+/// counting from the left or the right like `dirs -v` shows them.
+pub fn stack_index (spec: &str, len: usize) -> Option<usize> {
+    let (from_right, number) = match spec.split_at_checked(1)? {
+        ("+", number) => (false, number),
+        ("-", number) => (true, number),
+        _ => return None,
+    };
+
+    let number: usize = number.parse().ok()?;
+    if number >= len { return None; }
+
+    Some(if from_right { len - 1 - number } else { number })
+}
+
+#[cfg(test)]
+mod dirs_tests {
+    use super::*;
+    use crate::utils::TestDir;
+
+    #[test]
+    fn logical_paths () {
+        assert_eq!(logical_path("/tmp/link", ".."), "/tmp");
+        assert_eq!(logical_path("/tmp/link", "../x/./y/"), "/tmp/x/y");
+        assert_eq!(logical_path("/a/b", "/c/../d"), "/d");
+        assert_eq!(logical_path("/", "../.."), "/");
+    }
+
+    #[test]
+    fn stack_indexes () {
+        assert_eq!(stack_index("+0", 3), Some(0));
+        assert_eq!(stack_index("+2", 3), Some(2));
+        assert_eq!(stack_index("-0", 3), Some(2));
+        assert_eq!(stack_index("-2", 3), Some(0));
+        assert_eq!(stack_index("+3", 3), None);
+        assert_eq!(stack_index("2", 3), None);
+        assert_eq!(stack_index("+x", 3), None);
+    }
+
+    #[test]
+    fn cdpath () {
+        let root = TestDir::new("cdpath");
+        std::fs::create_dir_all(root.join("projects/app")).unwrap();
+        let projects = root.join("projects").to_string_lossy().to_string();
+
+        let cdpath = format!("/nonexistent::{projects}");
+        assert_eq!(search_cdpath("app", &cdpath), Some(format!("{projects}/app")));
+        assert_eq!(search_cdpath("./app", &cdpath), None);
+        assert_eq!(search_cdpath("missing", &cdpath), None);
+    }
+
+    #[test]
+    fn dir_completion () {
+        let dir = TestDir::new("complete");
+        for name in ["src", "scripts", ".git"] {
+            std::fs::create_dir_all(dir.join(name)).unwrap();
+        }
+        std::fs::write(dir.join("setup.sh"), "").unwrap();
+        let root = dir.to_string_lossy().to_string();
+
+        assert_eq!(complete_dir(&format!("{root}/s")), vec![format!("{root}/scripts/"), format!("{root}/src/")]);
+        assert_eq!(complete_dir(&format!("{root}/")).len(), 2);
+        assert_eq!(complete_dir(&format!("{root}/.")), vec![format!("{root}/.git/")]);
+        assert!(complete_dir(&format!("{root}/missing/")).is_empty());
+    }
+}
@@ -0,0 +1,714 @@
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+use crate::inflate;
+
+// how long the prompt waits for a fresh status before it shows the one it had
+const REFRESH_WAIT: Duration = Duration::from_millis(50);
+
+type Id = [u8; 20];
+
+/// What HEAD points at.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Head {
+    Branch(String),
+    /// a commit checked out directly, or a branch without commits yet (None)
+    Detached(Option<String>)
+}
+
+/// Where a repository keeps its things.
+pub struct Repo {
+    git_dir: String,
+    // where refs and objects are, not the same as `git_dir` in a linked worktree
+    common_dir: String,
+    work_tree: String
+}
+
+/// What the prompt shows about a repository.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Status {
+    pub head: Head,
+    pub ahead: usize,
+    pub behind: usize,
+    /// files changed in the work tree, not added yet
+    pub unstaged: bool,
+    /// the index differs from HEAD
+    pub staged: bool,
+    // what `staged` was worked out from, it stays valid until one of them changes
+    index_mtime: i64,
+    commit: Option<Id>
+}
+
+fn to_hex (id: &Id) -> String {
+    id.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex (hex: &str) -> Option<Id> {
+    let hex = hex.trim();
+    if hex.len() != 40 { return None; }
+
+    let mut id = [0u8; 20];
+    for (idx, byte) in id.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(2 * idx..2 * idx + 2)?, 16).ok()?;
+    }
+    Some(id)
+}
+
+fn read_u32 (data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+/// A file mapped in memory, pack files can be big and only a few objects of them are read.
+struct Mapped {
+    ptr: *mut libc::c_void,
+    len: usize
+}
+
+impl Mapped {
+    fn open (path: &str) -> Option<Self> {
+        let file = File::open(path).ok()?;
+        let len = file.metadata().ok()?.len() as usize;
+        if len == 0 { return None; }
+
+        let ptr = unsafe { libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_PRIVATE, file.as_raw_fd(), 0) };
+        (ptr != libc::MAP_FAILED).then_some(Self { ptr, len })
+    }
+
+    fn data (&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.cast(), self.len) }
+    }
+}
+
+impl Drop for Mapped {
+    fn drop (&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len); }
+    }
+}
+
+struct Pack {
+    index: Mapped,
+    pack: Mapped
+}
+
+impl Pack {
+    /// Where the object is in the pack, from the version 2 `.idx` next to it.
+    fn offset (&self, id: &Id) -> Option<usize> {
+        let index = self.index.data();
+        if index.get(..8)? != [0xFF, b't', b'O', b'c', 0, 0, 0, 2] { return None; }
+
+        let fanout = |byte: usize| read_u32(index, 8 + 4 * byte).map(|count| count as usize);
+        let total = fanout(255)?;
+        let (mut low, mut high) = (if id[0] == 0 { 0 } else { fanout(id[0] as usize - 1)? }, fanout(id[0] as usize)?);
+
+        let ids = 8 + 1024;
+        while low < high {
+            let mid = (low + high) / 2;
+
+            match index.get(ids + 20 * mid..ids + 20 * mid + 20)?.cmp(id) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => {
+                    let offsets = ids + 24 * total;
+                    let offset = read_u32(index, offsets + 4 * mid)?;
+                    if offset & 0x8000_0000 == 0 { return Some(offset as usize); }
+
+                    // packs over 2GB keep the rest in a table of 8 byte offsets
+                    let large = offsets + 4 * total + 8 * (offset & 0x7FFF_FFFF) as usize;
+                    return Some(u64::from_be_bytes(index.get(large..large + 8)?.try_into().ok()?) as usize);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// Rebuilds an object from its base and a delta against it.
+fn apply_delta (base: &[u8], delta: &[u8]) -> Option<Vec<u8>> {
+    let mut pos = 0;
+    let mut size = || {
+        let mut value = 0usize;
+        let mut shift = 0;
+        loop {
+            let byte = *delta.get(pos)?;
+            pos += 1;
+            value |= ((byte & 0x7F) as usize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 { return Some(value); }
+        }
+    };
+
+    let _base_size = size()?;
+    let result_size = size()?;
+    let mut out = Vec::with_capacity(result_size);
+
+    while let Some(&op) = delta.get(pos) {
+        pos += 1;
+
+        if op & 0x80 != 0 {
+            // copy from the base, the bits say which offset and size bytes follow
+            let (mut offset, mut len) = (0usize, 0usize);
+            for idx in 0..4 {
+                if op & (1 << idx) != 0 {
+                    offset |= (*delta.get(pos)? as usize) << (8 * idx);
+                    pos += 1;
+                }
+            }
+            for idx in 0..3 {
+                if op & (0x10 << idx) != 0 {
+                    len |= (*delta.get(pos)? as usize) << (8 * idx);
+                    pos += 1;
+                }
+            }
+            if len == 0 { len = 0x10000; }
+
+            out.extend_from_slice(base.get(offset..offset + len)?);
+        } else if op != 0 {
+            out.extend_from_slice(delta.get(pos..pos + op as usize)?);
+            pos += op as usize;
+        } else {
+            return None;
+        }
+    }
+
+    (out.len() == result_size).then_some(out)
+}
+
+/// Reads objects, loose or packed.
+struct Objects {
+    dir: String,
+    packs: Vec<Pack>
+}
+
+impl Objects {
+    fn open (dir: String) -> Self {
+        let packs = fs::read_dir(format!("{dir}/pack")).into_iter().flatten().flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let stem = name.strip_suffix(".idx")?;
+
+                Some(Pack {
+                    index: Mapped::open(&format!("{dir}/pack/{name}"))?,
+                    pack: Mapped::open(&format!("{dir}/pack/{stem}.pack"))?,
+                })
+            })
+            .collect();
+
+        Self { dir, packs }
+    }
+
+    /// The type (1 commit, 2 tree, 3 blob, 4 tag) and content of an object.
+    fn read (&self, id: &Id) -> Option<(u8, Vec<u8>)> {
+        let hex = to_hex(id);
+
+        if let Ok(data) = fs::read(format!("{}/{}/{}", self.dir, &hex[..2], &hex[2..])) {
+            let data = inflate::decompress(&data)?;
+            let header_end = data.iter().position(|&byte| byte == 0)?;
+            let kind = match data.get(..header_end)?.split(|&byte| byte == b' ').next()? {
+                b"commit" => 1,
+                b"tree" => 2,
+                b"blob" => 3,
+                b"tag" => 4,
+                _ => return None,
+            };
+            return Some((kind, data[header_end + 1..].to_vec()));
+        }
+
+        self.packs.iter().find_map(|pack| self.read_packed(pack, pack.offset(id)?))
+    }
+
+    fn read_packed (&self, pack: &Pack, offset: usize) -> Option<(u8, Vec<u8>)> {
+        let data = pack.pack.data().get(offset..)?;
+
+        // the type and size come first, the size isn't needed
+        let mut byte = *data.first()?;
+        let kind = (byte >> 4) & 7;
+        let mut pos = 1;
+        while byte & 0x80 != 0 {
+            byte = *data.get(pos)?;
+            pos += 1;
+        }
+
+        match kind {
+            1..=4 => Some((kind, inflate::decompress(data.get(pos..)?)?)),
+            // a delta against an object earlier in the pack
+            6 => {
+                let mut byte = *data.get(pos)?;
+                pos += 1;
+                let mut distance = (byte & 0x7F) as usize;
+                while byte & 0x80 != 0 {
+                    byte = *data.get(pos)?;
+                    pos += 1;
+                    distance = ((distance + 1) << 7) | (byte & 0x7F) as usize;
+                }
+
+                let (kind, base) = self.read_packed(pack, offset.checked_sub(distance)?)?;
+                Some((kind, apply_delta(&base, &inflate::decompress(data.get(pos..)?)?)?))
+            }
+            // a delta against an object named by its id
+            7 => {
+                let base_id: Id = data.get(pos..pos + 20)?.try_into().ok()?;
+                let (kind, base) = self.read(&base_id)?;
+                Some((kind, apply_delta(&base, &inflate::decompress(data.get(pos + 20..)?)?)?))
+            }
+            _ => None,
+        }
+    }
+
+    /// The parents of a commit and when it was committed.
+    fn commit (&self, id: &Id) -> Option<(Vec<Id>, i64)> {
+        let (1, content) = self.read(id)? else { return None };
+        let text = String::from_utf8_lossy(&content);
+
+        let mut parents = Vec::new();
+        let mut time = 0;
+
+        // the headers end at the first empty line
+        for line in text.lines().take_while(|line| !line.is_empty()) {
+            if let Some(parent) = line.strip_prefix("parent ") {
+                parents.push(from_hex(parent)?);
+            } else if let Some(committer) = line.strip_prefix("committer ") {
+                time = committer.rsplit(' ').nth(1).and_then(|time| time.parse().ok()).unwrap_or(0);
+            }
+        }
+
+        Some((parents, time))
+    }
+
+    fn tree_of (&self, commit: &Id) -> Option<Id> {
+        let (1, content) = self.read(commit)? else { return None };
+        from_hex(String::from_utf8_lossy(&content).lines().next()?.strip_prefix("tree ")?)
+    }
+
+    /// Every file under a tree, with the id of its content.
+    fn flatten_tree (&self, tree: &Id, prefix: &str, files: &mut HashMap<String, Id>) -> Option<()> {
+        let (2, content) = self.read(tree)? else { return None };
+        let mut rest = content.as_slice();
+
+        while !rest.is_empty() {
+            let space = rest.iter().position(|&byte| byte == b' ')?;
+            let nul = rest.iter().position(|&byte| byte == 0)?;
+            let mode = String::from_utf8_lossy(rest.get(..space)?).to_string();
+            let name = String::from_utf8_lossy(rest.get(space + 1..nul)?).to_string();
+            let id: Id = rest.get(nul + 1..nul + 21)?.try_into().ok()?;
+            rest = &rest[nul + 21..];
+
+            let path = format!("{prefix}{name}");
+            if mode == "40000" {
+                self.flatten_tree(&id, &format!("{path}/"), files)?;
+            } else {
+                files.insert(path, id);
+            }
+        }
+
+        Some(())
+    }
+
+    /// How many commits `left` has that `right` doesn't, and the other way round. Commits are
+    /// walked newest first, until all that's left to walk is history the two share.
+    fn ahead_behind (&self, left: &Id, right: &Id) -> Option<(usize, usize)> {
+        const LEFT: u8 = 1;
+        const RIGHT: u8 = 2;
+
+        let mut commits: HashMap<Id, (Vec<Id>, i64)> = HashMap::new();
+        let mut flags: HashMap<Id, u8> = HashMap::new();
+        let mut queue = BinaryHeap::new();
+        let mut walked = HashSet::new();
+
+        let time = |id: &Id, commits: &mut HashMap<Id, (Vec<Id>, i64)>| {
+            if !commits.contains_key(id) {
+                commits.insert(*id, self.commit(id)?);
+            }
+            Some(commits[id].1)
+        };
+
+        for (id, flag) in [(left, LEFT), (right, RIGHT)] {
+            *flags.entry(*id).or_default() |= flag;
+            queue.push((time(id, &mut commits)?, *id));
+        }
+
+        // a commit goes back in the queue whenever it gets a flag it didn't have, commit
+        // times can be wrong and a parent can be walked before all of its children
+        while queue.iter().any(|(_, id)| flags[id] != LEFT | RIGHT) {
+            let Some((_, id)) = queue.pop() else { break };
+            let flag = flags[&id];
+            walked.insert(id);
+
+            for parent in commits[&id].0.clone() {
+                let parent_flags = flags.entry(parent).or_default();
+                if *parent_flags | flag == *parent_flags { continue; }
+                *parent_flags |= flag;
+
+                // a parent missing from a shallow clone ends the walk there
+                if let Some(time) = time(&parent, &mut commits) {
+                    queue.push((time, parent));
+                }
+            }
+        }
+
+        // what's left only has shared history ahead, but it can still reach commits
+        // that were walked too early
+        while let Some((_, id)) = queue.pop() {
+            let flag = flags[&id];
+
+            for parent in commits[&id].0.clone() {
+                if !walked.contains(&parent) || flags[&parent] | flag == flags[&parent] { continue; }
+                *flags.entry(parent).or_default() |= flag;
+                queue.push((commits[&parent].1, parent));
+            }
+        }
+
+        let count = |flag| walked.iter().filter(|id| flags[*id] == flag).count();
+        Some((count(LEFT), count(RIGHT)))
+    }
+}
+
+/// An entry of the index: the path, the id of the staged content and what the file looked like then.
+struct IndexEntry {
+    path: String,
+    id: Id,
+    mtime: (i64, i64),
+    size: u32,
+    mode: u32,
+    stage: u16,
+    skip_worktree: bool
+}
+
+/// Reads the entries of a version 2 or 3 index, None for anything else.
+fn read_index (data: &[u8]) -> Option<Vec<IndexEntry>> {
+    if data.get(..4)? != b"DIRC" { return None; }
+    let version = read_u32(data, 4)?;
+    if !(2..=3).contains(&version) { return None; }
+
+    let count = read_u32(data, 8)? as usize;
+    // every entry takes at least 62 bytes, a bogus count mustn't reserve more than that
+    let mut entries = Vec::with_capacity(count.min(data.len() / 62));
+    let mut pos = 12;
+
+    for _ in 0..count {
+        let entry = data.get(pos..)?;
+        let flags = u16::from_be_bytes(entry.get(60..62)?.try_into().ok()?);
+        let extended = version >= 3 && flags & 0x4000 != 0;
+        let extra_flags = if extended { u16::from_be_bytes(entry.get(62..64)?.try_into().ok()?) } else { 0 };
+
+        let name_start = if extended { 64 } else { 62 };
+        let name_len = entry.get(name_start..)?.iter().position(|&byte| byte == 0)?;
+
+        entries.push(IndexEntry {
+            path: String::from_utf8_lossy(&entry[name_start..name_start + name_len]).to_string(),
+            id: entry.get(40..60)?.try_into().ok()?,
+            mtime: (read_u32(entry, 8)? as i64, read_u32(entry, 12)? as i64),
+            size: read_u32(entry, 36)?,
+            mode: read_u32(entry, 24)?,
+            stage: (flags >> 12) & 3,
+            skip_worktree: extra_flags & 0x4000 != 0,
+        });
+
+        // entries are padded with NULs to a multiple of 8 bytes
+        pos += (name_start + name_len + 8) & !7;
+    }
+
+    Some(entries)
+}
+
+impl Repo {
+    /// The repository `dir` is in, looking for `.git` in it and then in its parents.
+    pub fn discover (dir: &str) -> Option<Self> {
+        let mut dir = dir.trim_end_matches('/').to_string();
+
+        loop {
+            let dot_git = format!("{dir}/.git");
+
+            if let Ok(meta) = fs::metadata(&dot_git) {
+                let git_dir = if meta.is_dir() {
+                    dot_git
+                } else {
+                    // a `.git` file points to the real one, in worktrees and submodules
+                    let text = fs::read_to_string(&dot_git).ok()?;
+                    let target = text.trim().strip_prefix("gitdir: ")?;
+                    if target.starts_with('/') { target.to_string() } else { format!("{dir}/{target}") }
+                };
+
+                let common_dir = match fs::read_to_string(format!("{git_dir}/commondir")) {
+                    Ok(common) if common.trim().starts_with('/') => common.trim().to_string(),
+                    Ok(common) => format!("{git_dir}/{}", common.trim()),
+                    Err(_) => git_dir.clone(),
+                };
+
+                let work_tree = if dir.is_empty() { "/".to_string() } else { dir };
+                return Some(Self { git_dir, common_dir, work_tree });
+            }
+
+            let (parent, _) = dir.rsplit_once('/')?;
+            dir = parent.to_string();
+        }
+    }
+
+    /// The id a ref points at, following symbolic refs, from its own file or `packed-refs`.
+    fn resolve (&self, name: &str) -> Option<Id> {
+        let mut name = name.to_string();
+
+        // symbolic refs can point to each other, but not forever
+        for _ in 0..5 {
+            let dir = if name == "HEAD" { &self.git_dir } else { &self.common_dir };
+
+            match fs::read_to_string(format!("{dir}/{name}")) {
+                Ok(text) => match text.trim().strip_prefix("ref: ") {
+                    Some(target) => name = target.to_string(),
+                    None => return from_hex(&text),
+                },
+                Err(_) => {
+                    let packed = fs::read_to_string(format!("{}/packed-refs", self.common_dir)).ok()?;
+                    return packed.lines()
+                        .filter_map(|line| line.split_once(' '))
+                        .find(|(_, packed_name)| *packed_name == name)
+                        .and_then(|(id, _)| from_hex(id));
+                }
+            }
+        }
+
+        None
+    }
+
+    pub fn head (&self) -> Option<Head> {
+        let text = fs::read_to_string(format!("{}/HEAD", self.git_dir)).ok()?;
+
+        Some(match text.trim().strip_prefix("ref: ") {
+            Some(target) => match target.strip_prefix("refs/heads/") {
+                Some(branch) => Head::Branch(branch.to_string()),
+                None => Head::Detached(None),
+            },
+            None => Head::Detached(from_hex(&text).map(|id| to_hex(&id)[..7].to_string())),
+        })
+    }
+
+    /// The ref `branch` tracks, from its `remote` and `merge` in the config.
+    fn upstream (&self, branch: &str) -> Option<String> {
+        let config = fs::read_to_string(format!("{}/config", self.common_dir)).ok()?;
+        let section = format!("[branch \"{branch}\"]");
+
+        let (mut remote, mut merge) = (None, None);
+        let mut inside = false;
+
+        for line in config.lines().map(str::trim) {
+            if line.starts_with('[') {
+                inside = line == section;
+                continue;
+            }
+            if !inside { continue; }
+
+            let Some((key, value)) = line.split_once('=') else { continue };
+            match key.trim().to_lowercase().as_str() {
+                "remote" => remote = Some(value.trim().to_string()),
+                "merge" => merge = Some(value.trim().to_string()),
+                _ => {}
+            }
+        }
+
+        let merge = merge?;
+        match remote?.as_str() {
+            "." => Some(merge),
+            remote => Some(format!("refs/remotes/{remote}/{}", merge.strip_prefix("refs/heads/")?)),
+        }
+    }
+
+    /// Works the whole status out. `previous` is what it was last time in the same place,
+    /// whether anything is staged is only looked at again when HEAD or the index changed.
+    pub fn status (&self, previous: Option<&Status>) -> Option<Status> {
+        let head = self.head()?;
+        let commit = self.resolve("HEAD");
+        let objects = Objects::open(format!("{}/objects", self.common_dir));
+
+        let (ahead, behind) = match (&head, commit) {
+            (Head::Branch(branch), Some(commit)) => self.upstream(branch)
+                .and_then(|upstream| self.resolve(&upstream))
+                .and_then(|upstream| objects.ahead_behind(&commit, &upstream))
+                .unwrap_or((0, 0)),
+            _ => (0, 0),
+        };
+
+        let index_path = format!("{}/index", self.git_dir);
+        let index_mtime = fs::metadata(&index_path).map_or(0, |meta| meta.mtime() * 1_000_000_000 + meta.mtime_nsec());
+
+        // no index yet is an empty one, but one that can't be read (git is halfway through writing
+        // it) tells nothing about what's staged
+        let index = match fs::read(&index_path) {
+            Ok(data) => read_index(&data),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Some(Vec::new()),
+            Err(_) => None,
+        };
+        let Some(entries) = index else {
+            let staged = previous.is_some_and(|previous| previous.staged);
+            return Some(Status { head, ahead, behind, unstaged: false, staged, index_mtime: 0, commit });
+        };
+
+        // a file is changed when it doesn't look the way it did when it was added, like git
+        // without reading the content
+        let unstaged = entries.iter().any(|entry| {
+            if entry.stage != 0 { return true; }
+            if entry.skip_worktree || entry.mode == 0o160000 { return false; }
+
+            match fs::symlink_metadata(format!("{}/{}", self.work_tree, entry.path)) {
+                Ok(meta) => {
+                    let nanos_differ = entry.mtime.1 != 0 && meta.mtime_nsec() != entry.mtime.1;
+                    meta.mtime() != entry.mtime.0 || nanos_differ || meta.size() as u32 != entry.size
+                }
+                Err(_) => true,
+            }
+        });
+
+        let staged = match previous {
+            Some(previous) if previous.index_mtime == index_mtime && previous.commit == commit => previous.staged,
+            _ => {
+                let mut files = HashMap::new();
+                if let Some(tree) = commit.and_then(|commit| objects.tree_of(&commit)) {
+                    objects.flatten_tree(&tree, "", &mut files);
+                }
+
+                files.len() != entries.len() || entries.iter().any(|entry| files.get(&entry.path) != Some(&entry.id))
+            }
+        };
+
+        Some(Status { head, ahead, behind, unstaged, staged, index_mtime, commit })
+    }
+}
+
+/// The branch with `*` for unstaged and `+` for staged changes, then how far it is
+/// ahead (`↑`) and behind (`↓`) its upstream.
+pub fn format_status (status: &Status) -> String {
+    let mut out = match &status.head {
+        Head::Branch(branch) => branch.clone(),
+        Head::Detached(Some(commit)) => format!("({commit})"),
+        Head::Detached(None) => "(unknown)".to_string(),
+    };
+
+    if status.unstaged { out.push('*'); }
+    if status.staged { out.push('+'); }
+    if status.ahead > 0 { out.push_str(&format!(" ↑{}", status.ahead)); }
+    if status.behind > 0 { out.push_str(&format!(" ↓{}", status.behind)); }
+
+    out
+}
+
+/// The git part of the prompt. The branch is read right away, everything else comes
+/// from a thread and is cached per directory: a slow repository shows the last status
+/// it had rather than holding the prompt up, and has the new one by the next prompt.
+#[derive(Default)]
+pub struct GitPrompt {
+    cache: Arc<Mutex<HashMap<String, Status>>>,
+    refreshing: Arc<Mutex<HashSet<String>>>
+}
+
+impl GitPrompt {
+    pub fn segment (&self, dir: &str) -> String {
+        let Some(repo) = Repo::discover(dir) else { return String::new() };
+        let Some(head) = repo.head() else { return String::new() };
+
+        let previous = self.cache.lock().ok().and_then(|cache| cache.get(dir).cloned());
+        let fresh = self.refresh(dir, repo, previous.clone()).and_then(|done| done.recv_timeout(REFRESH_WAIT).ok());
+        let status = fresh.or(previous);
+
+        match status {
+            Some(status) if status.head == head => format_status(&status),
+            _ => format_status(&Status { head, ahead: 0, behind: 0, unstaged: false, staged: false, index_mtime: 0, commit: None }),
+        }
+    }
+
+    /// Works the status of `dir` out in a thread, unless one already is. The receiver
+    /// gets the new status once it's in the cache.
+    fn refresh (&self, dir: &str, repo: Repo, previous: Option<Status>) -> Option<mpsc::Receiver<Status>> {
+        if !self.refreshing.lock().ok()?.insert(dir.to_string()) { return None; }
+
+        let (sender, receiver) = mpsc::channel();
+        let (cache, refreshing, dir) = (self.cache.clone(), self.refreshing.clone(), dir.to_string());
+
+        std::thread::spawn(move || {
+            let status = repo.status(previous.as_ref());
+
+            if let Some(status) = &status {
+                if let Ok(mut cache) = cache.lock() { cache.insert(dir.clone(), status.clone()); }
+            }
+            // done before the prompt hears of it, so the next one can refresh again
+            if let Ok(mut refreshing) = refreshing.lock() { refreshing.remove(&dir); }
+
+            if let Some(status) = status { let _ = sender.send(status); }
+        });
+
+        Some(receiver)
+    }
+}
+
+#[cfg(test)]
+mod git_tests {
+    use super::*;
+    use crate::utils::TestDir;
+
+    #[test]
+    fn deltas () {
+        let base = b"the quick brown fox";
+        // 19 bytes in, 11 out: copy 4 bytes from 0, insert "red", copy 4 from 15
+        let delta = [19, 11, 0x90, 4, 3, b'r', b'e', b'd', 0x91, 15, 4];
+
+        assert_eq!(apply_delta(base, &delta).unwrap(), b"the red fox");
+        assert_eq!(apply_delta(base, &[19, 11, 0x91, 15, 40]), None);
+    }
+
+    #[test]
+    fn refs_and_head () {
+        let root = TestDir::new("git");
+        let git = root.join(".git");
+        fs::create_dir_all(git.join("refs/heads")).unwrap();
+        fs::create_dir_all(root.join("src/deep")).unwrap();
+
+        let id = "0123456789abcdef0123456789abcdef01234567";
+        fs::write(git.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        fs::write(git.join("packed-refs"), format!("# pack-refs with: peeled\n{id} refs/heads/main\n")).unwrap();
+        fs::write(git.join("config"), "[branch \"main\"]\n\tremote = origin\n\tmerge = refs/heads/main\n").unwrap();
+
+        let repo = Repo::discover(&root.join("src/deep").to_string_lossy()).unwrap();
+        assert_eq!(repo.work_tree, root.to_string_lossy());
+        assert_eq!(repo.head(), Some(Head::Branch("main".to_string())));
+        assert_eq!(repo.resolve("HEAD").map(|id| to_hex(&id)), Some(id.to_string()));
+        assert_eq!(repo.upstream("main"), Some("refs/remotes/origin/main".to_string()));
+
+        fs::write(git.join("HEAD"), format!("{id}\n")).unwrap();
+        assert_eq!(repo.head(), Some(Head::Detached(Some("0123456".to_string()))));
+    }
+
+    #[test]
+    fn unreadable_index () {
+        let root = TestDir::new("git-index");
+        let git = root.join(".git");
+        fs::create_dir_all(git.join("refs/heads")).unwrap();
+        fs::write(git.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+
+        let repo = Repo::discover(&root.to_string_lossy()).unwrap();
+        let status = repo.status(None).unwrap();
+        assert!(!status.staged && !status.unstaged);
+
+        // garbage in the index keeps what was known before
+        fs::write(git.join("index"), "not an index").unwrap();
+        assert!(!repo.status(None).unwrap().staged);
+        assert!(repo.status(Some(&Status { staged: true, ..status })).unwrap().staged);
+    }
+
+    #[test]
+    fn formatting () {
+        let status = Status {
+            head: Head::Branch("main".to_string()),
+            ahead: 2,
+            behind: 1,
+            unstaged: true,
+            staged: false,
+            index_mtime: 0,
+            commit: None,
+        };
+
+        assert_eq!(format_status(&status), "main* ↑2 ↓1");
+        assert_eq!(format_status(&Status { head: Head::Detached(Some("abc1234".to_string())), ahead: 0, behind: 0, staged: true, ..status }), "(abc1234)*+");
+    }
+}
@@ -0,0 +1,264 @@
+use crate::dirs;
+use crate::lexer::{self, Token};
+use crate::utils;
+
+/// What a character of the command line is part of, each kind is drawn in its own colour.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Plain,
+    /// A builtin, alias or binary, something that can run
+    Command,
+    /// A command that isn't any of those
+    Unknown,
+    String,
+    Variable,
+    Operator,
+    Redirection,
+    Comment,
+    /// An argument naming a file that exists
+    Path
+}
+
+// the names $NYASH_HIGHLIGHT knows the kinds by
+const NAMES: [(&str, Kind); 8] = [
+    ("command", Kind::Command),
+    ("unknown", Kind::Unknown),
+    ("string", Kind::String),
+    ("variable", Kind::Variable),
+    ("operator", Kind::Operator),
+    ("redirection", Kind::Redirection),
+    ("comment", Kind::Comment),
+    ("path", Kind::Path),
+];
+
+/// The SGR parameters each kind is drawn with, `32` for green, `1;4` for bold and underlined.
+#[derive(Clone)]
+pub struct Colours {
+    sgr: [String; 9]
+}
+
+impl Default for Colours {
+    fn default () -> Self {
+        Self { sgr: ["", "32", "31", "33", "36", "35", "34", "90", "4"].map(String::from) }
+    }
+}
+
+impl Colours {
+    /// Reads `kind=sgr` pairs separated by `:`, like `command=1;32:comment=2`. The kinds left out
+    /// keep their colour, and an empty one isn't coloured. None for `none`, nothing is coloured then.
+    pub fn parse (spec: &str) -> Option<Self> {
+        if spec == "none" { return None; }
+
+        let mut colours = Self::default();
+        for (name, sgr) in spec.split(':').filter_map(|pair| pair.split_once('=')) {
+            if let Some((_, kind)) = NAMES.iter().find(|(known, _)| *known == name) {
+                colours.sgr[*kind as usize] = sgr.to_string();
+            }
+        }
+
+        Some(colours)
+    }
+
+    /// The escape sequence drawing starts with for `kind`, empty if it's left alone.
+    pub fn start (&self, kind: Kind) -> String {
+        match self.sgr[kind as usize].as_str() {
+            "" => String::new(),
+            sgr => format!("\x1b[{sgr}m"),
+        }
+    }
+}
+
+/// Where the expansion starting with the `$` at `start` ends, just after the `$` if it isn't one.
+fn expansion_end (chars: &[char], start: usize) -> usize {
+    let next = start + 1;
+
+    match chars.get(next) {
+        Some('{') => chars[next..].iter().position(|&ch| ch == '}').map_or(chars.len(), |len| next + len + 1),
+        Some(ch) if "?$!#@*0123456789".contains(*ch) => next + 1,
+        Some(ch) if ch.is_ascii_alphabetic() || *ch == '_' => {
+            next + chars[next..].iter().take_while(|ch| ch.is_ascii_alphanumeric() || **ch == '_').count()
+        }
+        _ => next,
+    }
+}
+
+/// Colours the quoted parts and the expansions of a word, what's left stays as it is.
+fn paint_word (chars: &[char], kinds: &mut [Kind]) {
+    let mut double = false;
+    let mut idx = 0;
+
+    while idx < chars.len() {
+        match chars[idx] {
+            '\\' => {
+                let end = (idx + 2).min(chars.len());
+                if double { kinds[idx..end].fill(Kind::String); }
+                idx = end;
+                continue;
+            }
+            '\'' if !double => {
+                let end = chars[idx + 1..].iter().position(|&ch| ch == '\'').map_or(chars.len(), |len| idx + len + 2);
+                kinds[idx..end].fill(Kind::String);
+                idx = end;
+                continue;
+            }
+            '$' if expansion_end(chars, idx) > idx + 1 => {
+                let end = expansion_end(chars, idx);
+                kinds[idx..end].fill(Kind::Variable);
+                idx = end;
+                continue;
+            }
+            '"' => {
+                double = !double;
+                kinds[idx] = Kind::String;
+            }
+            _ if double => kinds[idx] = Kind::String,
+            _ => {}
+        }
+
+        idx += 1;
+    }
+}
+
+/// Whether `word` names a file that's there. Words with expansions are never checked.
+fn is_path (word: &str) -> bool {
+    !word.contains('$') && utils::stat(dirs::expand_tilde(&lexer::unquote(word))).is_some()
+}
+
+/// The length of the name in `NAME=value`, if `word` is an assignment.
+fn assignment (word: &str) -> Option<usize> {
+    let (name, _) = word.split_once('=')?;
+    let valid = name.starts_with(|ch: char| ch.is_ascii_alphabetic() || ch == '_')
+        && name.chars().all(|ch| ch.is_ascii_alphanumeric() || ch == '_');
+
+    valid.then_some(name.len())
+}
+
+/// The kind of each character of `line`. It's lexed after `before`, the lines of the same command
+/// that came first, so a quote they left open is still open. `is_command` tells whether a name runs.
+pub fn highlight (before: &str, line: &str, is_command: &dyn Fn(&str) -> bool) -> Vec<Kind> {
+    let input = if before.is_empty() { line.to_string() } else { format!("{before}\n{line}") };
+    let chars: Vec<char> = input.chars().collect();
+    let mut kinds = vec![Kind::Plain; chars.len()];
+    let lexed = lexer::lex(&input);
+
+    // whether the next word is a command, the file of a redirection, or inside `[[ ]]`
+    let mut command = true;
+    let mut target = false;
+    let mut conditional = false;
+
+    for (token, span) in lexed.tokens.iter().zip(lexed.spans) {
+        let text = &chars[span.clone()];
+
+        match token {
+            Token::Op(op) if op.contains(['<', '>']) => {
+                kinds[span].fill(Kind::Redirection);
+                target = true;
+            }
+            Token::Op(op) => {
+                kinds[span].fill(Kind::Operator);
+                // what follows a subshell is its redirections
+                command = *op != ")";
+            }
+            Token::IoNumber(_) => kinds[span].fill(Kind::Redirection),
+            Token::HereDocBody(_) => kinds[span].fill(Kind::String),
+            Token::Word(word) if conditional && word == "]]" => {
+                kinds[span].fill(Kind::Command);
+                conditional = false;
+            }
+            Token::Word(word) if command && !target => match word.as_str() {
+                "[[" => {
+                    kinds[span].fill(Kind::Command);
+                    command = false;
+                    conditional = true;
+                }
+                "{" | "!" => kinds[span].fill(Kind::Command),
+                "}" => {
+                    kinds[span].fill(Kind::Command);
+                    command = false;
+                }
+                word => if let Some(len) = assignment(word) {
+                    kinds[span.start..span.start + len].fill(Kind::Variable);
+                    paint_word(&text[len + 1..], &mut kinds[span.start + len + 1..span.end]);
+                } else if word.contains('$') {
+                    paint_word(text, &mut kinds[span]);
+                    command = false;
+                } else {
+                    let name = lexer::unquote(word);
+                    let runs = is_command(&name) || (name.contains('/') && utils::is_executable(dirs::expand_tilde(&name)));
+
+                    kinds[span].fill(if runs { Kind::Command } else { Kind::Unknown });
+                    command = false;
+                },
+            },
+            Token::Word(word) => {
+                if is_path(word) {
+                    kinds[span].fill(Kind::Path);
+                } else {
+                    paint_word(text, &mut kinds[span]);
+                }
+                target = false;
+            }
+        }
+    }
+
+    for comment in lexed.comments {
+        kinds[comment].fill(Kind::Comment);
+    }
+
+    kinds.split_off(chars.len() - line.chars().count())
+}
+
+#[cfg(test)]
+mod highlight_tests {
+    use super::*;
+
+    // one letter a character: c command, u unknown, s string, v variable, o operator, r redirection,
+    // # comment, p path and `.` plain, blanks stay blank
+    fn kinds (before: &str, line: &str) -> String {
+        highlight(before, line, &|name| name == "echo" || name == "ls")
+            .into_iter()
+            .zip(line.chars())
+            .map(|(kind, ch)| match kind {
+                Kind::Plain if ch == ' ' => ' ',
+                Kind::Plain => '.',
+                Kind::Command => 'c',
+                Kind::Unknown => 'u',
+                Kind::String => 's',
+                Kind::Variable => 'v',
+                Kind::Operator => 'o',
+                Kind::Redirection => 'r',
+                Kind::Comment => '#',
+                Kind::Path => 'p',
+            })
+            .collect()
+    }
+
+    #[test]
+    fn commands () {
+        assert_eq!(kinds("", "echo hi | nope && X=1 ls"), "cccc .. o uuuu oo v.. cc");
+        assert_eq!(kinds("", "(ls) >/ 2>&1 # ls"), "occo rp rrr. ####");
+        assert_eq!(kinds("", "[[ $a == b ]]; {"), "cc vv .. . cco c");
+    }
+
+    #[test]
+    fn words () {
+        assert_eq!(kinds("", r#"echo 'a $b' "$c d\"" ${e}f$?"#), "cccc ssssss svvsssss vvvv.vv");
+        assert_eq!(kinds("", "echo x=$y $ 2"), "cccc ..vv . .");
+    }
+
+    #[test]
+    fn continued_lines () {
+        assert_eq!(kinds("echo 'a", "b' c"), "ss .");
+        assert_eq!(kinds("echo \\", "ls"), "..");
+        assert_eq!(kinds("cat <<E", "text"), "ssss");
+    }
+
+    #[test]
+    fn colours () {
+        let colours = Colours::parse("command=1;32:path=:bogus=1").unwrap();
+        assert_eq!(colours.start(Kind::Command), "\x1b[1;32m");
+        assert_eq!(colours.start(Kind::Path), "");
+        assert_eq!(colours.start(Kind::Unknown), "\x1b[31m");
+        assert!(Colours::parse("none").is_none());
+    }
+}
@@ -0,0 +1,211 @@
+// a small DEFLATE decoder (RFC 1951) for the zlib streams git keeps its objects in
+
+// base lengths and extra bits of the length symbols 257..285
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+// and of the distance symbols
+const DIST_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// the order code lengths come in, in a dynamic block
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buf: u32,
+    count: u32
+}
+
+impl Bits<'_> {
+    /// The next `n` bits (at most 16), least significant first.
+    fn bits (&mut self, n: u32) -> Option<u32> {
+        while self.count < n {
+            let byte = *self.data.get(self.pos)?;
+            self.pos += 1;
+            self.buf |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+
+        let value = self.buf & ((1 << n) - 1);
+        self.buf >>= n;
+        self.count -= n;
+        Some(value)
+    }
+}
+
+/// A canonical Huffman code: how many codes there are of each length, and the symbols
+/// in code order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>
+}
+
+impl Huffman {
+    fn new (lengths: &[u8]) -> Self {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut offsets = [0usize; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len] as usize;
+        }
+
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize]] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+
+        Self { counts, symbols }
+    }
+
+    /// Reads a code bit by bit, the codes of each length follow the ones of the length before.
+    fn decode (&self, bits: &mut Bits) -> Option<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+
+        for len in 1..16 {
+            code |= bits.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+
+            if code - count < first {
+                return self.symbols.get((index + code - first) as usize).copied();
+            }
+
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+
+        None
+    }
+}
+
+fn fixed_codes () -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_codes (bits: &mut Bits) -> Option<(Huffman, Huffman)> {
+    let nlen = bits.bits(5)? as usize + 257;
+    let ndist = bits.bits(5)? as usize + 1;
+    let ncode = bits.bits(4)? as usize + 4;
+
+    let mut code_lengths = [0u8; 19];
+    for &idx in &CODE_LENGTH_ORDER[..ncode] {
+        code_lengths[idx] = bits.bits(3)? as u8;
+    }
+    let code = Huffman::new(&code_lengths);
+
+    let mut lengths = Vec::with_capacity(nlen + ndist);
+    while lengths.len() < nlen + ndist {
+        let (len, repeat) = match code.decode(bits)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => (*lengths.last()?, 3 + bits.bits(2)?),
+            17 => (0, 3 + bits.bits(3)?),
+            _ => (0, 11 + bits.bits(7)?),
+        };
+        lengths.resize(lengths.len() + repeat as usize, len);
+    }
+
+    if lengths.len() != nlen + ndist { return None; }
+    Some((Huffman::new(&lengths[..nlen]), Huffman::new(&lengths[nlen..])))
+}
+
+fn inflate_codes (bits: &mut Bits, out: &mut Vec<u8>, lengths: &Huffman, distances: &Huffman) -> Option<()> {
+    loop {
+        match lengths.decode(bits)? {
+            symbol @ 0..=255 => out.push(symbol as u8),
+            256 => return Some(()),
+            symbol => {
+                let symbol = symbol as usize - 257;
+                let len = *LENGTH_BASE.get(symbol)? as usize + bits.bits(*LENGTH_EXTRA.get(symbol)? as u32)? as usize;
+
+                let symbol = distances.decode(bits)? as usize;
+                let dist = *DIST_BASE.get(symbol)? as usize + bits.bits(*DIST_EXTRA.get(symbol)? as u32)? as usize;
+
+                // the copy can overlap what it's producing
+                let start = out.len().checked_sub(dist)?;
+                for idx in start..start + len {
+                    out.push(out[idx]);
+                }
+            }
+        }
+    }
+}
+
+/// Decompresses a zlib stream, None if it's corrupt. What follows the stream is ignored,
+/// and so is the checksum.
+pub fn decompress (data: &[u8]) -> Option<Vec<u8>> {
+    let (header, data) = data.split_at_checked(2)?;
+    if header[0] & 0x0F != 8 || header[1] & 0x20 != 0 { return None; }
+
+    let mut bits = Bits { data, pos: 0, buf: 0, count: 0 };
+    let mut out = Vec::new();
+
+    loop {
+        let last = bits.bits(1)? == 1;
+
+        match bits.bits(2)? {
+            0 => {
+                // stored blocks start on a byte
+                bits.buf = 0;
+                bits.count = 0;
+
+                let header = data.get(bits.pos..bits.pos + 4)?;
+                let len = u16::from_le_bytes([header[0], header[1]]) as usize;
+                bits.pos += 4;
+
+                out.extend_from_slice(data.get(bits.pos..bits.pos + len)?);
+                bits.pos += len;
+            }
+            1 => {
+                let (lengths, distances) = fixed_codes();
+                inflate_codes(&mut bits, &mut out, &lengths, &distances)?;
+            }
+            2 => {
+                let (lengths, distances) = dynamic_codes(&mut bits)?;
+                inflate_codes(&mut bits, &mut out, &lengths, &distances)?;
+            }
+            _ => return None,
+        }
+
+        if last { return Some(out); }
+    }
+}
+
+#[cfg(test)]
+mod inflate_tests {
+    use super::*;
+
+    #[test]
+    fn blocks () {
+        let stored = [120, 1, 1, 23, 0, 232, 255, 104, 101, 108, 108, 111, 32, 104, 101, 108, 108, 111, 32, 104, 101, 108, 108, 111, 32, 110, 121, 97, 115, 104, 104, 87, 8, 192];
+        let fixed = [120, 218, 203, 72, 205, 201, 201, 87, 200, 64, 34, 243, 42, 19, 139, 51, 0, 104, 87, 8, 192];
+        let dynamic = [
+            120, 218, 205, 203, 193, 13, 0, 32, 8, 4, 193, 86, 174, 185, 83, 248, 64, 34, 136, 150, 175, 118, 225, 115, 147,
+            89, 55, 34, 151, 35, 101, 144, 104, 62, 7, 154, 22, 17, 186, 17, 44, 26, 168, 93, 18, 166, 15, 222, 244, 255,
+            134, 3, 136, 55, 71, 69,
+        ];
+
+        assert_eq!(decompress(&stored).unwrap(), b"hello hello hello nyash");
+        assert_eq!(decompress(&fixed).unwrap(), b"hello hello hello nyash");
+        assert_eq!(decompress(&dynamic).unwrap(), b"one two three four five six seven eight nine ten ".repeat(4));
+    }
+
+    #[test]
+    fn corrupt () {
+        assert_eq!(decompress(&[120, 218, 203, 72]), None);
+        assert_eq!(decompress(&[0, 0, 0]), None);
+        assert_eq!(decompress(&[120, 1, 7]), None);
+    }
+}
@@ -1,296 +1,2019 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::process;
 use std::ffi::CString;
-use std::io;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::FromRawFd;
 
 use anyhow::{Error, Result};
 
-use libc::{ O_RDONLY, O_WRONLY, O_CREAT, O_TRUNC, O_APPEND };
+use crate::args_parser::{expand_here_doc, expand_pattern, expand_regex, parse_args, substitute_processes};
+use crate::conditional::{self, Operand};
+use crate::dirs;
+use crate::git::GitPrompt;
+use crate::jump;
+use crate::prompt::{self, Expansion, PromptInfo};
+use crate::jobs::{self, JobState, JobTable};
+use crate::printf;
+use crate::read;
+use crate::parser::{self, AndOr, Command, Connector, List, Pipeline, SimpleCommand};
+use crate::redirect::{self, Redirect, RedirectOp, SavedFds};
+use crate::signals::{self, Trap};
+use crate::utils::{self, PipeLine, get_environment, get_pwd};
 
-use crate::utils::{self, PipeLine, get_environment, get_executable, get_pwd, redirect_io};
+#[derive(Debug, Default)]
+pub struct HashedCommand {
+    pub path: String,
+    pub hits: u32
+}
+
+#[derive(Default)]
+pub struct Interpreter {
+    // builtins run inside the shell unless they're part of a pipeline or a background job
+    builtins: HashMap<&'static str, fn(&[&str], &mut Interpreter)>,
+    // remembered command locations, thrown away whenever PATH changes
+    hash: HashMap<String, HashedCommand>,
+    hashed_path: String,
+    pub history: i32,
+    pub status: i32,
+    pub jobs: JobTable,
+    last_background: Option<i32>,
+    pub interactive: bool,
+    traps: HashMap<Trap, String>,
+    // set while a trap runs, so ERR and DEBUG don't fire from inside their own handlers
+    in_trap: bool,
+    // the `set -o` options that are on
+    pub options: HashSet<&'static str>,
+    // our ends of the `<(cmd)` and `>(cmd)` pipes, open until the command using them started
+    substitution_fds: Vec<i32>,
+    // process substitutions that haven't been collected yet
+    substitution_pids: Vec<i32>,
+    pub aliases: HashMap<String, String>,
+    // variables that aren't exported, exported ones live in the environment
+    vars: HashMap<String, String>,
+    // indexed arrays, like BASH_REMATCH
+    arrays: HashMap<String, Vec<String>>,
+    // $1, $2...
+    positional: Vec<String>,
+    // $? from before the builtin that's running, `exit` and `return` default to it
+    last_status: i32,
+    // `pushd` directories, the current one isn't in there
+    dir_stack: Vec<String>,
+    // how many `source`s deep we are, `return` only works inside one
+    source_depth: usize,
+    // set by `return`, stops everything up to the `source` it returns from
+    returning: bool,
+    // the `\g` of the prompt, cached per directory
+    git: GitPrompt
+}
+
+// a pipeline stage once its words and redirections got expanded
+enum Stage<'a> {
+    Simple(SimpleCommand),
+    // a subshell or a group, which only differ when they run on their own
+    List(&'a List, Vec<Redirect>),
+    // operands are expanded while the expression is evaluated
+    Conditional(&'a [String], Vec<Redirect>)
+}
+
+/// Options `set -o` knows about, with their single letter flag if they have one.
+const SHELL_OPTIONS: &[(&str, Option<char>)] = &[
+    ("emacs", None),
+    ("noclobber", Some('C')),
+    ("vi", None),
+    ("xtrace", Some('x')),
+];
+
+/// Writes what a builtin printed right away, so a failed write (`echo x > /dev/full`) is reported
+/// by the builtin itself. Returns the builtin's status.
+fn write_output (name: &str, output: &[u8]) -> i32 {
+    let mut stdout = io::stdout().lock();
+    write_status(name, stdout.write_all(output).and_then(|_| stdout.flush()))
+}
+
+/// The status of a builtin whose output went out with `written`, reporting it if that failed.
+fn write_status (name: &str, written: io::Result<()>) -> i32 {
+    match written {
+        Ok(()) => 0,
+        Err(error) => {
+            eprintln!("nyash: {name}: write error: {}", utils::strerror(error.raw_os_error().unwrap_or(libc::EIO)));
+            1
+        }
+    }
+}
+
+/// The status of `test`, `[` or `[[`: 0 when true, 1 when false and 2 for a bad expression.
+fn test_status (name: &str, result: Result<bool, String>) -> i32 {
+    match result {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(error) => {
+            eprintln!("nyash: {name}: {error}");
+            2
+        }
+    }
+}
+
+impl Interpreter {
+    pub fn new(history: &str) -> Self {
+        let mut inter = Interpreter { history: utils::open_file(history), ..Interpreter::default() };
+        inter.options.insert("emacs");
+
+        inter.builtins.insert("exit", |argv: &[&str], inter| {
+            let status = argv.first().map(|code| code.parse::<i32>().unwrap_or(2)).unwrap_or(inter.last_status);
+            inter.exit(status);
+        });
+
+        inter.builtins.insert("return", |argv, inter| {
+            if inter.source_depth == 0 {
+                eprintln!("return: can only `return' from a function or sourced script");
+                inter.status = 1;
+                return;
+            }
+
+            inter.status = match argv.first().map(|code| code.parse::<i32>()) {
+                Some(Ok(code)) => code & 0xff,
+                Some(Err(_)) => {
+                    eprintln!("return: {}: numeric argument required", argv[0]);
+                    2
+                }
+                None => inter.last_status,
+            };
+            inter.returning = true;
+        });
+
+        let source: fn(&[&str], &mut Interpreter) = |argv, inter| {
+            let Some(name) = argv.first() else {
+                eprintln!("source: filename argument required");
+                inter.status = 2;
+                return;
+            };
+
+            let path = inter.find_source(name);
+            inter.source_file(&path, &argv[1..]);
+        };
+        inter.builtins.insert("source", source);
+        inter.builtins.insert(".", source);
+
+        inter.builtins.insert("eval", |argv, inter| {
+            inter.execute(&argv.join(" "));
+        });
+
+        inter.builtins.insert("export", |argv, inter| {
+            let argv: Vec<&str> = argv.iter().copied().filter(|&arg| arg != "-p").collect();
+
+            if argv.is_empty() {
+                let mut vars: Vec<(String, String)> = std::env::vars().collect();
+                vars.sort();
+
+                let mut out = String::new();
+                for (name, value) in vars {
+                    let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('$', "\\$").replace('`', "\\`");
+                    let _ = writeln!(out, "declare -x {name}=\"{value}\"");
+                }
+                inter.print("export", &out);
+                return;
+            }
+
+            for arg in argv {
+                let (name, value) = match arg.split_once('=') {
+                    Some((name, value)) => (name, Some(value.to_string())),
+                    None => (arg, None),
+                };
+
+                if !parser::is_name(name) {
+                    eprintln!("export: `{arg}': not a valid identifier");
+                    inter.status = 1;
+                    continue;
+                }
+
+                if let Some(value) = value.or_else(|| inter.vars.get(name).cloned()) {
+                    inter.vars.remove(name);
+                    utils::set_environment(name, &value);
+                }
+            }
+        });
+        inter.builtins.insert("test", |argv, inter| inter.status = test_status("test", conditional::test(argv)));
+        inter.builtins.insert("[", |argv, inter| {
+            let Some((&"]", args)) = argv.split_last() else {
+                eprintln!("nyash: [: missing `]'");
+                inter.status = 2;
+                return;
+            };
+
+            inter.status = test_status("[", conditional::test(args));
+        });
+
+        inter.builtins.insert("echo", |argv: &[&str], inter| {
+            let mut newline = true;
+            let mut escapes = false;
+
+            // options only count as long as they're made of n, e and E
+            let options = argv.iter().take_while(|arg| {
+                arg.len() > 1 && arg.starts_with('-') && arg[1..].chars().all(|ch| "neE".contains(ch))
+            }).count();
+
+            for option in &argv[..options] {
+                for ch in option[1..].chars() {
+                    match ch {
+                        'n' => newline = false,
+                        'e' => escapes = true,
+                        _ => escapes = false,
+                    }
+                }
+            }
+
+            let text = argv[options..].join(" ");
+            let mut output = if escapes {
+                let (output, go_on) = printf::unescape(&text);
+                newline &= go_on;
+                output
+            } else {
+                text.into_bytes()
+            };
+
+            if newline { output.push(b'\n'); }
+            inter.status = write_output("echo", &output);
+        });
+
+        inter.builtins.insert("read", |argv, inter| {
+            let args = match read::parse_options(argv) {
+                Ok(args) => args,
+                Err(error) => {
+                    eprintln!("nyash: read: {error}");
+                    eprintln!("read: usage: read [-rs] [-a array] [-d delim] [-n nchars] [-p prompt] [-t timeout] [-u fd] [name ...]");
+                    inter.status = 2;
+                    return;
+                }
+            };
+
+            if let Some(name) = args.names.iter().chain(&args.array).find(|name| !parser::is_name(name)) {
+                eprintln!("nyash: read: `{name}': not a valid identifier");
+                inter.status = 1;
+                return;
+            }
+
+            let fd = args.options.fd;
+
+            // `-t 0` only checks whether there's something to read
+            if args.options.timeout.is_some_and(|timeout| timeout.is_zero()) {
+                inter.status = if read::input_ready(fd, 0) { 0 } else { 1 };
+                return;
+            }
+
+            if let Some(prompt) = args.prompt.filter(|_| unsafe { libc::isatty(fd) } == 1) {
+                eprint!("{prompt}");
+            }
+
+            let input = read::read_input(&args.options);
+
+            inter.status = match input.ending {
+                read::Ending::Delimiter | read::Ending::Count => 0,
+                read::Ending::Eof => 1,
+                read::Ending::Timeout => 128 + libc::SIGALRM,
+                read::Ending::Interrupt => {
+                    inter.status = 128 + libc::SIGINT;
+                    return;
+                }
+            };
+
+            let ifs = inter.lookup_var("IFS").unwrap_or_else(|| " \t\n".to_string());
+
+            if let Some(array) = args.array {
+                inter.vars.remove(array);
+                inter.arrays.insert(array.to_string(), read::split_fields(&input.text, &ifs, 0));
+            } else if args.names.is_empty() {
+                // REPLY gets the line as it is
+                let text: Vec<u8> = input.text.iter().map(|(byte, _)| *byte).collect();
+                inter.set_var("REPLY", &String::from_utf8_lossy(&text));
+            } else {
+                let mut fields = read::split_fields(&input.text, &ifs, args.names.len()).into_iter();
+
+                for name in args.names {
+                    inter.set_var(name, &fields.next().unwrap_or_default());
+                }
+            }
+        });
+
+        inter.builtins.insert("printf", |argv, inter| {
+            let mut argv = argv;
+            let mut variable = None;
+
+            if let Some(("-v", name)) = argv.first().zip(argv.get(1)).map(|(option, name)| (*option, *name)) {
+                if !parser::is_name(name) {
+                    eprintln!("nyash: printf: `{name}': not a valid identifier");
+                    inter.status = 2;
+                    return;
+                }
+                variable = Some(name);
+                argv = &argv[2..];
+            }
+
+            if argv.first() == Some(&"--") { argv = &argv[1..]; }
+
+            let Some(format) = argv.first() else {
+                eprintln!("nyash: printf: usage: printf [-v var] format [arguments]");
+                inter.status = 2;
+                return;
+            };
+
+            // straight to stdout, a huge width is never held in memory
+            let (formatted, written) = match variable {
+                Some(name) => {
+                    let mut output = Vec::new();
+                    let formatted = printf::printf(format, &argv[1..], &mut output);
+                    inter.set_var(name, &String::from_utf8_lossy(&output));
+                    (formatted, Ok(()))
+                }
+                None => {
+                    let mut stdout = io::stdout().lock();
+                    let mut formatted = printf::printf(format, &argv[1..], &mut stdout);
+                    let written = match formatted.write_error.take() {
+                        Some(error) => Err(error),
+                        None => stdout.flush(),
+                    };
+                    (formatted, written)
+                }
+            };
+
+            for error in &formatted.errors {
+                eprintln!("nyash: printf: {error}");
+            }
+
+            inter.status = write_status("printf", written);
+
+            if !formatted.errors.is_empty() { inter.status = 1; }
+        });
+        inter.builtins.insert("type", |argv: &[&str], interpreter| {
+            if let Some(cmd) = argv.first() {
+                let description = if let Some(value) = interpreter.aliases.get(*cmd) {
+                    format!("{cmd} is aliased to `{value}'")
+                }
+                else if interpreter.get_builtins().contains(&cmd) {
+                    format!("{cmd} is a shell builtin")
+                }
+                else if cmd.contains('/') && utils::is_executable(cmd) {
+                    format!("{cmd} is {cmd}")
+                }
+                else if let Some(hashed) = interpreter.hash.get(*cmd) {
+                    format!("{cmd} is hashed ({})", hashed.path)
+                }
+                else if let Some(path) = interpreter.search_path(cmd) {
+                    format!("{cmd} is {path}")
+                }
+                else {
+                    format!("{cmd}: not found")
+                };
+                interpreter.print("type", &format!("{description}\n"));
+            }
+        });
+
+        inter.builtins.insert("alias", |argv, inter| {
+            let quote = |name: &str, value: &str| format!("alias {name}='{}'\n", value.replace('\'', "'\\''"));
+            let argv: Vec<&str> = argv.iter().copied().filter(|&arg| arg != "-p").collect();
+
+            if argv.is_empty() {
+                let mut aliases: Vec<_> = inter.aliases.iter().collect();
+                aliases.sort();
+
+                let out: String = aliases.into_iter().map(|(name, value)| quote(name, value)).collect();
+                inter.print("alias", &out);
+                return;
+            }
+
+            for arg in argv {
+                match arg.split_once('=') {
+                    Some((name, _)) if name.is_empty() || name.contains(['/', '$', '`', '\'', '"', '\\']) => {
+                        eprintln!("alias: `{name}': invalid alias name");
+                        inter.status = 1;
+                    }
+                    Some((name, value)) => { inter.aliases.insert(name.to_string(), value.to_string()); }
+                    None => match inter.aliases.get(arg) {
+                        Some(value) => {
+                            let line = quote(arg, value);
+                            inter.print("alias", &line);
+                        }
+                        None => {
+                            eprintln!("alias: {arg}: not found");
+                            inter.status = 1;
+                        }
+                    }
+                }
+            }
+        });
+
+        inter.builtins.insert("unalias", |argv, inter| {
+            if argv.first() == Some(&"-a") {
+                inter.aliases.clear();
+                return;
+            }
+
+            if argv.is_empty() {
+                eprintln!("unalias: usage: unalias [-a] name [name ...]");
+                inter.status = 2;
+                return;
+            }
+
+            for name in argv {
+                if inter.aliases.remove(*name).is_none() {
+                    eprintln!("unalias: {name}: not found");
+                    inter.status = 1;
+                }
+            }
+        });
+
+        inter.builtins.insert("pwd", |argv, inter| {
+            let pwd = if argv.last() == Some(&"-P") { get_pwd() } else { dirs::current_dir() };
+            if pwd.is_empty() {
+                inter.print("pwd", "Error excuting getcwd\n");
+            } else {
+                inter.print("pwd", &format!("{pwd}\n"));
+            }
+        });
+
+        inter.builtins.insert("history", |argv, inter| {
+            let entries = inter.read_history();
+
+            // `history N` only shows the last N
+            let skip = match argv.first().map(|arg| arg.parse::<usize>()) {
+                Some(Ok(count)) => entries.len().saturating_sub(count),
+                Some(Err(_)) => {
+                    eprintln!("history: {}: numeric argument required", argv[0]);
+                    inter.status = 2;
+                    return;
+                }
+                None => 0,
+            };
+
+            let mut out = String::new();
+            for (idx, entry) in entries.iter().enumerate().skip(skip) {
+                let _ = writeln!(out, "{:>5}  {entry}", idx + 1);
+            }
+            inter.print("history", &out);
+        });
+
+        inter.builtins.insert("hash", |argv, inter| {
+            inter.invalidate_hash();
+
+            let mut names = Vec::new();
+            let mut out = String::new();
+            let mut iter = argv.iter();
+
+            while let Some(&arg) = iter.next() {
+                match arg {
+                    "-r" => inter.hash.clear(),
+                    "-p" => {
+                        let (Some(path), Some(name)) = (iter.next(), iter.next()) else {
+                            eprintln!("nyash: hash: -p: usage: hash -p pathname name");
+                            inter.status = 1;
+                            return;
+                        };
+                        inter.hash.insert(name.to_string(), HashedCommand { path: path.to_string(), hits: 0 });
+                    }
+                    "-d" => {
+                        for name in iter.by_ref() {
+                            if inter.hash.remove(*name).is_none() {
+                                eprintln!("nyash: hash: {name}: not found");
+                                inter.status = 1;
+                            }
+                        }
+                    }
+                    "-t" => {
+                        let rest: Vec<&&str> = iter.by_ref().collect();
+                        for name in &rest {
+                            let Some(path) = inter.hash.get(**name).map(|hashed| &hashed.path) else {
+                                eprintln!("nyash: hash: {name}: not found");
+                                inter.status = 1;
+                                continue;
+                            };
+
+                            if rest.len() > 1 { let _ = writeln!(out, "{name}\t{path}"); }
+                            else { let _ = writeln!(out, "{path}"); }
+                        }
+                    }
+                    "-l" => {
+                        for (name, hashed) in &inter.hash {
+                            let _ = writeln!(out, "builtin hash -p {} {name}", hashed.path);
+                        }
+                    }
+                    name => names.push(name),
+                }
+            }
+
+            if argv.is_empty() {
+                if inter.hash.is_empty() {
+                    inter.print("hash", "hash: hash table empty\n");
+                    return;
+                }
+
+                out.push_str("hits\tcommand\n");
+                for hashed in inter.hash.values() {
+                    let _ = writeln!(out, "{:4}\t{}", hashed.hits, hashed.path);
+                }
+            }
+            inter.print("hash", &out);
+
+            for name in names {
+                if inter.get_builtins().contains(&&name) { continue; }
+
+                match inter.search_path(name) {
+                    Some(path) => { inter.hash.insert(name.to_string(), HashedCommand { path, hits: 0 }); }
+                    None => {
+                        eprintln!("nyash: hash: {name}: not found");
+                        inter.status = 1;
+                    }
+                }
+            }
+        });
+
+        inter.builtins.insert("set", |argv, inter| {
+            let mut iter = argv.iter();
+
+            while let Some(arg) = iter.next() {
+                let (enable, flags) = match (arg.strip_prefix('-'), arg.strip_prefix('+')) {
+                    (Some(flags), _) => (true, flags),
+                    (_, Some(flags)) => (false, flags),
+                    _ => {
+                        eprintln!("nyash: set: {arg}: invalid option");
+                        inter.status = 2;
+                        return;
+                    }
+                };
+
+                if flags == "o" {
+                    let Some(name) = iter.next() else {
+                        // `set -o` lists the options, `set +o` prints them as commands
+                        let mut out = String::new();
+                        for (name, _) in SHELL_OPTIONS {
+                            let on = inter.options.contains(name);
+                            if enable {
+                                let _ = writeln!(out, "{name:<15}\t{}", if on { "on" } else { "off" });
+                            } else {
+                                let _ = writeln!(out, "set {}o {name}", if on { '-' } else { '+' });
+                            }
+                        }
+                        inter.print("set", &out);
+                        return;
+                    };
+
+                    match SHELL_OPTIONS.iter().find(|(option, _)| option == name) {
+                        Some((option, _)) => inter.set_option(option, enable),
+                        None => {
+                            eprintln!("nyash: set: {name}: invalid option name");
+                            inter.status = 2;
+                        }
+                    }
+                    continue;
+                }
+
+                for flag in flags.chars() {
+                    match SHELL_OPTIONS.iter().find(|(_, letter)| *letter == Some(flag)) {
+                        Some((option, _)) => inter.set_option(option, enable),
+                        None => {
+                            eprintln!("nyash: set: -{flag}: invalid option");
+                            inter.status = 2;
+                        }
+                    }
+                }
+            }
+        });
+
+        inter.builtins.insert("trap", |argv, inter| {
+            let mut argv = argv;
+
+            match argv.first() {
+                Some(&"-l") => {
+                    let mut out = String::new();
+                    for (idx, (name, sig)) in signals::SIGNALS.iter().enumerate() {
+                        let _ = write!(out, "{:2}) {:<12}", sig, format!("SIG{name}"));
+                        if idx % 5 == 4 { out.push('\n'); }
+                    }
+                    out.push('\n');
+                    inter.print("trap", &out);
+                    return;
+                }
+                Some(&"--") => argv = &argv[1..],
+                _ => {}
+            }
+
+            // `trap` and `trap -p [SIG...]` print the traps back as commands
+            let print_only = argv.first() == Some(&"-p");
+            if print_only { argv = &argv[1..]; }
+
+            if argv.is_empty() || print_only {
+                let wanted: Vec<Trap> = argv.iter().filter_map(|arg| Trap::parse(arg)).collect();
+                let mut traps: Vec<(&Trap, &String)> = inter.traps.iter()
+                    .filter(|(trap, _)| wanted.is_empty() || wanted.contains(trap))
+                    .collect();
+                traps.sort_by_key(|(trap, _)| trap.name());
+
+                let mut out = String::new();
+                for (trap, action) in traps {
+                    let _ = writeln!(out, "trap -- '{}' {}", action.replace('\'', "'\\''"), trap.name());
+                }
+                inter.print("trap", &out);
+                return;
+            }
+
+            // a lone signal resets it, just like `trap - SIG`
+            let (action, specs) = if Trap::parse(argv[0]).is_some() && argv.len() == 1 {
+                ("-", argv)
+            } else {
+                (argv[0], &argv[1..])
+            };
+
+            for spec in specs {
+                let Some(trap) = Trap::parse(spec) else {
+                    eprintln!("nyash: trap: {spec}: invalid signal specification");
+                    inter.status = 1;
+                    continue;
+                };
+
+                inter.set_trap(trap, action);
+            }
+        });
+
+        inter.builtins.insert("jobs", |argv, inter| {
+            let long = argv.contains(&"-l");
+            let pids_only = argv.contains(&"-p");
+            let specs: Vec<&&str> = argv.iter().filter(|arg| !arg.starts_with('-')).collect();
+
+            inter.jobs.update();
+
+            let mut ids = Vec::new();
+            for spec in specs {
+                match inter.jobs.find(spec) {
+                    Ok(id) => ids.push(id),
+                    Err(error) => {
+                        eprintln!("nyash: jobs: {error}");
+                        inter.status = 1;
+                    }
+                }
+            }
+
+            if ids.is_empty() && inter.status == 0 {
+                ids = inter.jobs.iter().map(|job| job.id).collect();
+            }
+
+            let mut out = String::new();
+            for id in ids {
+                let Some(job) = inter.jobs.get(id) else { continue };
+
+                if pids_only {
+                    let _ = writeln!(out, "{}", job.leader());
+                } else {
+                    let _ = writeln!(out, "{}", job.describe(inter.jobs.marker(id), long));
+                }
+            }
+            inter.print("jobs", &out);
+
+            // the ones we just showed as done don't need another notification
+            inter.jobs.take_finished();
+        });
+
+        inter.builtins.insert("fg", |argv, inter| {
+            let id = match inter.jobs.find(argv.first().unwrap_or(&"%%")) {
+                Ok(id) => id,
+                Err(error) => {
+                    eprintln!("nyash: fg: {error}");
+                    inter.status = 1;
+                    return;
+                }
+            };
+
+            if let Some(command) = inter.jobs.get(id).map(|job| format!("{}\n", job.command)) {
+                inter.print("fg", &command);
+            }
+
+            inter.wait_foreground(id, true);
+        });
+
+        inter.builtins.insert("bg", |argv, inter| {
+            let id = match inter.jobs.find(argv.first().unwrap_or(&"%%")) {
+                Ok(id) => id,
+                Err(error) => {
+                    eprintln!("nyash: bg: {error}");
+                    inter.status = 1;
+                    return;
+                }
+            };
+
+            match inter.jobs.get(id).map(|job| job.state()) {
+                Some(JobState::Stopped) => {
+                    inter.jobs.background(id);
+
+                    if let Some(job) = inter.jobs.get(id) {
+                        let line = format!("[{id}]{} {} &\n", inter.jobs.marker(id), job.command);
+                        inter.last_background = job.processes.last().map(|p| p.pid);
+                        inter.print("bg", &line);
+                    }
+                }
+                _ => {
+                    eprintln!("nyash: bg: job {id} already in background");
+                    inter.status = 1;
+                }
+            }
+        });
+
+        inter.builtins.insert("wait", |argv, inter| {
+            if argv.is_empty() {
+                let ids: Vec<usize> = inter.jobs.iter().map(|job| job.id).collect();
+                for id in ids {
+                    inter.jobs.wait(id);
+                    inter.jobs.remove(id);
+                }
+                inter.status = 0;
+                return;
+            }
+
+            for arg in argv {
+                if arg.starts_with('%') {
+                    match inter.jobs.find(arg) {
+                        Ok(id) => {
+                            inter.jobs.wait(id);
+                            inter.status = inter.jobs.remove(id).map(|job| job.status()).unwrap_or(0);
+                        }
+                        Err(error) => {
+                            eprintln!("nyash: wait: {error}");
+                            inter.status = 127;
+                        }
+                    }
+                    continue;
+                }
+
+                let Ok(pid) = arg.parse::<i32>() else {
+                    eprintln!("nyash: wait: `{arg}': not a pid or valid job spec");
+                    inter.status = 2;
+                    continue;
+                };
+
+                match inter.jobs.wait_pid(pid) {
+                    Some(status) => {
+                        inter.status = utils::exit_status(status);
+
+                        if let Ok(id) = inter.jobs.find(arg) {
+                            if inter.jobs.get(id).is_some_and(|job| job.state() == JobState::Done) {
+                                inter.jobs.remove(id);
+                            }
+                        }
+                    }
+                    None => {
+                        eprintln!("nyash: wait: pid {pid} is not a child of this shell");
+                        inter.status = 127;
+                    }
+                }
+            }
+        });
+
+        inter.builtins.insert("cd", |argv, inter| {
+            let mut argv = argv;
+            let mut physical = false;
+
+            while let Some(option) = argv.first().filter(|arg| arg.len() > 1 && arg.starts_with('-')) {
+                argv = &argv[1..];
+                if *option == "--" { break; }
+
+                // the last of -L and -P wins
+                for flag in option[1..].chars() {
+                    match flag {
+                        'L' => physical = false,
+                        'P' => physical = true,
+                        flag => {
+                            eprintln!("nyash: cd: -{flag}: invalid option");
+                            eprintln!("cd: usage: cd [-L|-P] [dir]");
+                            inter.status = 2;
+                            return;
+                        }
+                    }
+                }
+            }
+
+            if argv.len() > 1 {
+                eprintln!("nyash: cd: too many arguments");
+                inter.status = 1;
+                return;
+            }
+
+            // `cd -` and a directory found through CDPATH print where they went
+            let (dir, print) = match argv.first() {
+                None => match get_environment("HOME") {
+                    Some(home) => (home.to_string(), false),
+                    None => {
+                        eprintln!("nyash: cd: HOME not set");
+                        inter.status = 1;
+                        return;
+                    }
+                },
+                Some(&"-") => match get_environment("OLDPWD") {
+                    Some(old) => (old.to_string(), true),
+                    None => {
+                        eprintln!("nyash: cd: OLDPWD not set");
+                        inter.status = 1;
+                        return;
+                    }
+                },
+                Some(dir) => {
+                    let dir = dirs::expand_tilde(dir);
+                    let cdpath = inter.lookup_var("CDPATH").unwrap_or_default();
+
+                    match dirs::search_cdpath(&dir, &cdpath) {
+                        Some(found) => (found, true),
+                        None => (dir, false),
+                    }
+                }
+            };
+
+            match dirs::change_dir(&dir, physical) {
+                Ok(pwd) => {
+                    if print { inter.print("cd", &format!("{pwd}\n")); }
+                    inter.record_visit(&pwd);
+                }
+                Err(error) => {
+                    eprintln!("nyash: cd: {dir}: {error}");
+                    inter.status = 1;
+                }
+            }
+        });
+
+        inter.builtins.insert("pushd", |argv, inter| {
+            let no_cd = argv.first() == Some(&"-n");
+            let argv = if no_cd { &argv[1..] } else { argv };
+            let current = dirs::current_dir();
+
+            match argv.first() {
+                // swap the two top directories
+                None => {
+                    let Some(top) = inter.dir_stack.first().cloned() else {
+                        eprintln!("nyash: pushd: no other directory");
+                        inter.status = 1;
+                        return;
+                    };
+
+                    if !no_cd && !inter.enter_dir("pushd", &top) { return; }
+                    inter.dir_stack[0] = current;
+                }
+                Some(spec) if spec.starts_with(['+', '-']) && spec.len() > 1 => {
+                    let mut all = vec![current];
+                    all.extend(inter.dir_stack.iter().cloned());
+
+                    let Some(index) = dirs::stack_index(spec, all.len()) else {
+                        eprintln!("nyash: pushd: {spec}: directory stack index out of range");
+                        inter.status = 1;
+                        return;
+                    };
+
+                    all.rotate_left(index);
+                    if !no_cd && !inter.enter_dir("pushd", &all[0]) { return; }
+                    inter.dir_stack = all.split_off(1);
+                }
+                Some(dir) => {
+                    let dir = dirs::expand_tilde(dir);
+
+                    if no_cd {
+                        inter.dir_stack.insert(0, dirs::logical_path(&current, &dir));
+                    } else {
+                        if !inter.enter_dir("pushd", &dir) { return; }
+                        inter.dir_stack.insert(0, current);
+                    }
+                }
+            }
+
+            inter.print_dirs("pushd", false, false, false);
+        });
+
+        inter.builtins.insert("popd", |argv, inter| {
+            let no_cd = argv.first() == Some(&"-n");
+            let argv = if no_cd { &argv[1..] } else { argv };
+
+            if inter.dir_stack.is_empty() {
+                eprintln!("nyash: popd: directory stack empty");
+                inter.status = 1;
+                return;
+            }
+
+            let index = match argv.first() {
+                None => 0,
+                Some(spec) => match dirs::stack_index(spec, inter.dir_stack.len() + 1) {
+                    Some(index) => index,
+                    None => {
+                        eprintln!("nyash: popd: {spec}: directory stack index out of range");
+                        inter.status = 1;
+                        return;
+                    }
+                },
+            };
+
+            // the current directory goes by changing to the next one
+            if index == 0 {
+                if !no_cd && !inter.enter_dir("popd", &inter.dir_stack[0].clone()) { return; }
+                inter.dir_stack.remove(0);
+            } else {
+                inter.dir_stack.remove(index - 1);
+            }
+
+            inter.print_dirs("popd", false, false, false);
+        });
+
+        inter.builtins.insert("j", |argv, inter| {
+            let list = argv.first() == Some(&"-l");
+            let terms = if list { &argv[1..] } else { argv };
+
+            let Some(file) = jump::database_path() else {
+                eprintln!("nyash: j: HOME not set");
+                inter.status = 1;
+                return;
+            };
+
+            // directories that are gone since they were visited don't count
+            let mut db = jump::Database::load(&file);
+            db.entries.retain(|entry| utils::stat(&entry.path).is_some());
+            let matches = db.matches(terms, jump::now());
+
+            // the best one is listed last, right above the prompt
+            if list || terms.is_empty() {
+                let mut out = String::new();
+                for (score, path) in matches.iter().rev() {
+                    let _ = writeln!(out, "{score:<10.1} {path}");
+                }
+                inter.print("j", &out);
+                return;
+            }
+
+            match matches.first() {
+                Some((_, best)) => { inter.enter_dir("j", best); }
+                None => {
+                    eprintln!("nyash: j: {}: no match", terms.join(" "));
+                    inter.status = 1;
+                }
+            }
+        });
+
+        inter.builtins.insert("dirs", |argv, inter| {
+            let (mut long, mut lines, mut numbered) = (false, false, false);
+            let mut entry = None;
+
+            for arg in argv {
+                match *arg {
+                    "-c" => inter.dir_stack.clear(),
+                    "-l" => long = true,
+                    "-p" => lines = true,
+                    "-v" => numbered = true,
+                    spec => match dirs::stack_index(spec, inter.dir_stack.len() + 1) {
+                        Some(index) => entry = Some(index),
+                        None => {
+                            eprintln!("nyash: dirs: {spec}: directory stack index out of range");
+                            inter.status = 1;
+                            return;
+                        }
+                    },
+                }
+            }
+
+            if argv.contains(&"-c") { return; }
+
+            match entry {
+                Some(index) => {
+                    let dir = if index == 0 { dirs::current_dir() } else { inter.dir_stack[index - 1].clone() };
+                    let line = format!("{}\n", inter.show_dir(&dir, long));
+                    inter.print("dirs", &line);
+                }
+                None => inter.print_dirs("dirs", long, lines, numbered),
+            }
+        });
+
+        inter
+    }
+
+    pub fn get_builtins (&self) -> Vec<&&str> {
+        self.builtins.keys().collect()
+    }
+
+    fn search_path (&self, cmd: &str) -> Option<String> {
+        utils::get_executable(cmd)
+    }
+
+    /// Drops every remembered location if PATH changed since the table was filled.
+    fn invalidate_hash (&mut self) {
+        let path = get_environment("PATH").unwrap_or("");
+
+        if self.hashed_path != path {
+            self.hash.clear();
+            self.hashed_path = path.to_string();
+        }
+    }
+
+    /// Resolves `cmd` through the hash table, searching PATH and remembering the result on a miss.
+    pub fn lookup_command (&mut self, cmd: &str) -> Option<String> {
+        self.invalidate_hash();
+
+        if let Some(hashed) = self.hash.get_mut(cmd) {
+            // the binary might have been removed since we found it
+            if utils::is_executable(&hashed.path) {
+                hashed.hits += 1;
+                return Some(hashed.path.clone());
+            }
+
+            self.hash.remove(cmd);
+        }
+
+        let path = self.search_path(cmd)?;
+        self.hash.insert(cmd.to_string(), HashedCommand { path: path.clone(), hits: 1 });
+
+        Some(path)
+    }
+
+    /// Commands containing a slash skip the PATH search and run as they are.
+    /// Reports why the file can't be executed and returns the status to fail with.
+    fn check_path_command (cmd: &str) -> Result<(), i32> {
+        let Some(st) = utils::stat(cmd) else {
+            eprintln!("nyash: {cmd}: No such file or directory");
+            return Err(127);
+        };
+
+        if st.st_mode & libc::S_IFMT == libc::S_IFDIR {
+            eprintln!("nyash: {cmd}: Is a directory");
+            return Err(126);
+        }
+
+        if !utils::is_executable(cmd) {
+            eprintln!("nyash: {cmd}: Permission denied");
+            return Err(126);
+        }
+
+        Ok(())
+    }
+
+    /// Expands the words and redirection targets of a command right before it runs.
+    fn expand_command (&mut self, command: &SimpleCommand) -> Result<SimpleCommand, String> {
+        // process substitutions start first, their commands expand on their own
+        let raw_words = command.words.iter()
+            .map(|word| substitute_processes(word, &mut |command, input| self.spawn_substitution(command, input)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let redirects = self.expand_redirects(&command.redirects)?;
+
+        let vars = |name: &str| self.lookup_var(name);
+        let words = raw_words.iter().flat_map(|word| parse_args(word, &vars)).collect();
+
+        // the value is expanded but never split
+        let assignments = command.assignments.iter().map(|assignment| {
+            let (name, value) = assignment.split_once('=').unwrap_or((assignment, ""));
+            format!("{name}={}", parse_args(value, &vars).join(" "))
+        }).collect();
+
+        Ok(SimpleCommand { assignments, words, redirects })
+    }
+
+    fn expand_redirects (&mut self, redirects: &[Redirect]) -> Result<Vec<Redirect>, String> {
+        let mut raw_redirects = Vec::new();
+        for redirect in redirects {
+            let target = match redirect.op {
+                RedirectOp::HereDoc | RedirectOp::HereDocStrip => redirect.target.clone(),
+                _ => substitute_processes(&redirect.target, &mut |command, input| self.spawn_substitution(command, input))?,
+            };
+            raw_redirects.push(Redirect { target, ..redirect.clone() });
+        }
+
+        let vars = |name: &str| self.lookup_var(name);
+        let mut redirects = Vec::new();
+
+        for redirect in &raw_redirects {
+            match redirect.op {
+                RedirectOp::HereDoc | RedirectOp::HereDocStrip => {
+                    // quoting any part of the delimiter keeps the body literal
+                    let literal = redirect.target.contains(['\'', '"', '\\']);
+                    let body = redirect.body.as_deref().unwrap_or_default();
+                    let body = if literal { body.to_string() } else { expand_here_doc(body, &vars) };
+
+                    redirects.push(Redirect { body: Some(body), ..redirect.clone() });
+                    continue;
+                }
+                // the word isn't split
+                RedirectOp::HereString => {
+                    let target = parse_args(&redirect.target, &vars).join(" ");
+                    redirects.push(Redirect { target, ..redirect.clone() });
+                    continue;
+                }
+                _ => {}
+            }
+
+            let mut target = parse_args(&redirect.target, &vars);
+            if target.len() != 1 {
+                return Err(format!("{}: ambiguous redirect", redirect.target));
+            }
+
+            redirects.push(Redirect { target: target.remove(0), ..redirect.clone() });
+        }
+
+        Ok(redirects)
+    }
+
+    /// Finds what to run for `cmd`: a builtin's name or an executable's path.
+    /// Reports why it can't be run otherwise and returns the status to fail with.
+    fn resolve_command (&mut self, cmd: &str) -> Result<String, i32> {
+        if self.builtins.contains_key(cmd) {
+            return Ok(cmd.to_string());
+        }
+
+        if cmd.contains('/') {
+            return Self::check_path_command(cmd).map(|_| cmd.to_string());
+        }
+
+        self.lookup_command(cmd).ok_or_else(|| {
+            eprintln!("{cmd}: command not found");
+            127
+        })
+    }
+
+    /// Runs a builtin in the shell process itself. Its redirections are applied around it
+    /// and undone afterwards, so it can still change the shell's state.
+    fn run_builtin (&mut self, executor: fn(&[&str], &mut Interpreter), command: &SimpleCommand) {
+        let saved = match SavedFds::apply(&command.redirects, self.options.contains("noclobber")) {
+            Ok(saved) => saved,
+            Err(error) => {
+                eprintln!("nyash: {error}");
+                self.status = 1;
+                return;
+            }
+        };
+
+        // `VAR=value builtin` only sets VAR while the builtin runs
+        let previous: Vec<(&str, Option<String>)> = command.assignments.iter().map(|assignment| {
+            let (name, value) = assignment.split_once('=').unwrap_or((assignment, ""));
+            let old = get_environment(name).map(String::from);
+            utils::set_environment(name, value);
+            (name, old)
+        }).collect();
+
+        let argv: Vec<&str> = command.words[1..].iter().map(|s| s.as_str()).collect();
+        self.last_status = self.status;
+        self.status = 0;
+
+        executor(&argv, self);
+
+        for (name, old) in previous.into_iter().rev() {
+            match old {
+                Some(value) => utils::set_environment(name, &value),
+                None => utils::unset_environment(name),
+            }
+        }
+
+        drop(saved);
+    }
+
+    /// Runs a `{ list; }` in the shell itself, with its redirections around it.
+    fn run_group (&mut self, list: &List, redirects: &[Redirect]) -> bool {
+        let saved = match SavedFds::apply(redirects, self.options.contains("noclobber")) {
+            Ok(saved) => saved,
+            Err(error) => {
+                eprintln!("nyash: {error}");
+                self.status = 1;
+                return true;
+            }
+        };
+
+        let found = self.run_list(list);
+        drop(saved);
+        found
+    }
+
+    /// Evaluates a `[[ ]]` in the shell, so an `=~` can set BASH_REMATCH.
+    fn run_conditional (&mut self, words: &[String], redirects: &[Redirect]) {
+        let saved = match SavedFds::apply(redirects, self.options.contains("noclobber")) {
+            Ok(saved) => saved,
+            Err(error) => {
+                eprintln!("nyash: {error}");
+                self.status = 1;
+                return;
+            }
+        };
+
+        let vars = |name: &str| self.lookup_var(name);
+        let result = conditional::conditional(words, &mut |word, operand| match operand {
+            // nothing is split or globbed in there
+            Operand::Word => parse_args(word, &vars).join(" "),
+            Operand::Pattern => expand_pattern(word, &vars),
+            Operand::Regex => expand_regex(word, &vars),
+        });
+
+        let result = result.map(|(result, rematch)| {
+            if let Some(groups) = rematch {
+                self.arrays.insert("BASH_REMATCH".to_string(), groups);
+            }
+            result
+        });
+
+        self.status = test_status("[[", result);
+        drop(saved);
+    }
+
+    /// Runs a pipeline, returns false if one of its commands couldn't be found.
+    pub fn run_pipeline (&mut self, pipeline: &Pipeline, background: bool) -> bool {
+        let mut stages = Vec::new();
+
+        for command in &pipeline.commands {
+            let stage = match command {
+                Command::Simple(command) => self.expand_command(command).map(Stage::Simple),
+                Command::Subshell(list, redirects) | Command::Group(list, redirects) => {
+                    self.expand_redirects(redirects).map(|redirects| Stage::List(list, redirects))
+                }
+                Command::Conditional(words, redirects) => {
+                    self.expand_redirects(redirects).map(|redirects| Stage::Conditional(words, redirects))
+                }
+            };
+
+            match stage {
+                Ok(stage) => {
+                    if let Stage::Simple(command) = &stage {
+                        if self.options.contains("xtrace") { self.trace(command); }
+                    }
+                    stages.push(stage);
+                }
+                Err(error) => {
+                    eprintln!("nyash: {error}");
+                    self.status = 1;
+                    return true;
+                }
+            }
+        }
+
+        // builtins and groups on their own run in the shell, everything else gets forked
+        if stages.len() == 1 && !background {
+            match (&stages[0], &pipeline.commands[0]) {
+                (Stage::Simple(command), _) => {
+                    if let Some(&executor) = command.words.first().and_then(|cmd| self.builtins.get(cmd.as_str())) {
+                        self.run_builtin(executor, command);
+                        return true;
+                    }
+
+                    // assignments without a command stay in the shell
+                    if command.words.is_empty() {
+                        for assignment in &command.assignments {
+                            let (name, value) = assignment.split_once('=').unwrap_or((assignment, ""));
+                            self.set_var(name, value);
+                        }
+
+                        self.status = 0;
+                        if command.redirects.is_empty() { return true; }
+                    }
+                }
+                (Stage::List(list, redirects), Command::Group(..)) => return self.run_group(list, redirects),
+                (Stage::Conditional(words, redirects), _) => {
+                    self.run_conditional(words, redirects);
+                    return true;
+                }
+                _ => {}
+            }
+        }
+
+        let mut all_fds = Vec::new();
+        let mut pids = Vec::new();
+        let mut found = true;
+        let num_pipes = stages.len() - 1;
+        
+        for _ in 0..num_pipes {
+            let mut fds = [0; 2];
+            unsafe { libc::pipe(fds.as_mut_ptr()); }
+            all_fds.push(fds);
+        }
+
+        for ( idx, stage ) in stages.iter().enumerate() {
+            let mut pipeline = Vec::new();
+
+            if idx > 0 {
+                pipeline.push(PipeLine {
+                    fd_t: libc::STDIN_FILENO,
+                    fds: all_fds[idx - 1],
+                });
+            }
+
+            if idx < all_fds.len() {
+                pipeline.push(PipeLine {
+                    fd_t: libc::STDOUT_FILENO,
+                    fds: all_fds[idx],
+                });
+            }
+
+            let pgid = pids.first().copied().unwrap_or(0);
+
+            let command = match stage {
+                Stage::Simple(command) => command,
+                Stage::List(list, redirects) => {
+                    match self.fork_list(list, redirects, Some((pipeline, &all_fds)), pgid, !background) {
+                        Err(error) => eprintln!("nyash: {error}"),
+                        Ok(pid) => { pids.push(pid); }
+                    }
+                    continue;
+                }
+                Stage::Conditional(words, redirects) => {
+                    let list = List { items: vec![AndOr {
+                        first: Pipeline { commands: vec![Command::Conditional(words.to_vec(), Vec::new())] },
+                        rest: Vec::new(),
+                        background: false,
+                    }] };
+
+                    match self.fork_list(&list, redirects, Some((pipeline, &all_fds)), pgid, !background) {
+                        Err(error) => eprintln!("nyash: {error}"),
+                        Ok(pid) => { pids.push(pid); }
+                    }
+                    continue;
+                }
+            };
+
+            // a command made of redirections only still opens (and creates) its files
+            let path = match command.words.first() {
+                Some(cmd) => match self.resolve_command(cmd) {
+                    Ok(path) => path,
+                    Err(status) => {
+                        found &= status != 127;
+                        self.status = status;
+                        continue;
+                    }
+                },
+                None => String::new(),
+            };
+
+            match self.exec_command(&path, command, Some((pipeline, &all_fds)), pgid, !background) {
+                Err(error) => eprintln!("error running {path}: {error}"),
+                Ok(pid) => { pids.push(pid); }
+            };
+        }
+
+        for [ read, write ] in &all_fds {
+            unsafe {
+                libc::close(*read);
+                libc::close(*write);
+            }
+        }
+
+        self.finish_pipeline(pids, background, &pipeline.to_string());
+
+        found
+    }
+
+    /// Runs pipelines joined by `&&` and `||`. In the background the whole list becomes one job.
+    fn run_and_or (&mut self, and_or: &AndOr) -> bool {
+        if and_or.background && !and_or.rest.is_empty() {
+            let list = List { items: vec![AndOr { background: false, ..and_or.clone() }] };
+
+            match self.fork_list(&list, &[], None, 0, false) {
+                Ok(pid) => self.finish_pipeline(vec![pid], true, &and_or.to_string()),
+                Err(error) => eprintln!("nyash: {error}"),
+            }
+            return true;
+        }
 
-#[derive(Debug, Default)]
-pub struct Interpreter {
-    builtins: HashMap<&'static str, fn(&[&str], &Interpreter)>,
-    shell_commands: HashMap<&'static str, fn(&[&str], &Interpreter)>,
-    pub history: i32
-}
+        let mut found = self.run_pipeline(&and_or.first, and_or.background);
 
-impl Interpreter {
-    pub fn new(history: &str) -> Self {
-        let mut inter = Interpreter { history: utils::open_file(history), ..Interpreter::default() };
+        for (connector, pipeline) in &and_or.rest {
+            if self.returning { break; }
 
-        inter.shell_commands.insert("exit", |argv: &[&str], _| process::exit(argv.first().unwrap_or(&"0").parse::<i32>().unwrap_or(0)));
-        inter.builtins.insert("echo", |argv: &[&str], _| println!("{}", argv.join(" ")));
-        inter.builtins.insert("type", |argv: &[&str], interpreter: &Interpreter| {
-            if let Some(cmd) = argv.first() {
-                if interpreter.get_builtins().contains(&cmd) {
-                    println!("{cmd} is a shell builtin")
-                }
-                else if let Some(path) = get_executable(cmd) {
-                    println!("{cmd} is {path}");
-                }
-                else {
-                    println!("{cmd}: not found")
-                };
+            let run = match connector {
+                Connector::And => self.status == 0,
+                Connector::Or => self.status != 0,
+            };
+
+            if run {
+                found &= self.run_pipeline(pipeline, false);
             }
-        });
+        }
 
-        inter.builtins.insert("pwd", |_, _| {
-            let pwd = get_pwd();
-            if pwd.is_empty() {
-                println!("Error excuting getcwd");
-            } else {
-                println!("{pwd}");
+        found
+    }
+
+    /// Runs every command of `list`, firing the DEBUG and ERR traps around them.
+    /// Returns false if a command wasn't found.
+    pub fn run_list (&mut self, list: &List) -> bool {
+        let mut found = true;
+
+        for and_or in &list.items {
+            if self.returning { break; }
+
+            self.run_trap(Trap::Debug);
+
+            found &= self.run_and_or(and_or);
+            self.finish_substitutions();
+
+            if self.status != 0 {
+                self.run_trap(Trap::Err);
             }
-        });
 
-        inter.builtins.insert("history", |_, inter| {
+            self.run_pending_traps();
+        }
 
-        });
+        found
+    }
 
-        inter.shell_commands.insert("cd", |argv, _| {
-            let mut dir = argv.first().unwrap_or(&"~").to_string();
+    /// Registers the pipeline as a job, then waits for it unless it was started with `&`.
+    fn finish_pipeline (&mut self, pids: Vec<i32>, background: bool, text: &str) {
+        let Some(&last) = pids.last() else { return };
+        let id = self.jobs.add(pids, text.to_string());
 
-            if dir == "~" {
-                dir = get_environment("HOME").unwrap_or("/root").to_string();
-            } else if !dir.starts_with("/") {
-                let parts: Vec<&str> = dir.split("/").collect();
-                let pwd = get_pwd();
-                let mut res: Vec<&str> = pwd.split("/").filter(|e| !e.is_empty()).collect();
+        if background {
+            self.last_background = Some(last);
+            self.status = 0;
 
-                for part in parts {
-                    if part == "." { continue; }
-                    if part == ".." {
-                        res.pop();
-                        continue;
-                    }
+            eprintln!("[{id}] {last}");
+            return;
+        }
 
-                    res.push(part);
+        self.wait_foreground(id, false);
+    }
+
+    /// Gives the terminal to a job until it's done, a stopped job stays in the table for `fg` and `bg`.
+    fn wait_foreground (&mut self, id: usize, cont: bool) {
+        let state = self.jobs.foreground(id, cont);
+
+        match state {
+            JobState::Stopped => {
+                self.status = 128 + libc::SIGTSTP;
+
+                if let Some(job) = self.jobs.get(id) {
+                    eprintln!("\n{}", job.describe(self.jobs.marker(id), false));
+                }
+            }
+            _ => {
+                if let Some(job) = self.jobs.remove(id) {
+                    self.status = job.status();
                 }
 
-                dir = res.join("/");
-                dir = format!("/{dir}");
+                // the ^C the terminal echoed leaves the cursor mid-line
+                if self.interactive && self.status == 128 + libc::SIGINT {
+                    let _ = writeln!(io::stdout());
+                }
             }
+        }
+    }
 
-            let Ok(path) = CString::new(dir.as_str()) else {
-                println!("Failed to create path from {}", dir);
-                return;
-            };
+    /// Reports the background jobs that finished since the last prompt.
+    pub fn notify_jobs (&mut self) {
+        self.jobs.update();
+        self.substitution_pids.retain(|&pid| !jobs::reap(pid));
 
-            unsafe {
-                if libc::chdir(path.as_ptr()) != 0 {
-                    println!("cd: {dir}: No such file or directory");
+        for job in self.jobs.take_finished() {
+            eprintln!("{}", job.describe(' ', false));
+        }
+    }
+
+    /// Values for `$name` expansions.
+    pub fn lookup_var (&self, name: &str) -> Option<String> {
+        match name {
+            "!" => self.last_background.map(|pid| pid.to_string()),
+            "?" => Some(self.status.to_string()),
+            "$" => Some(std::process::id().to_string()),
+            "0" => Some("nyash".to_string()),
+            "#" => Some(self.positional.len().to_string()),
+            "@" | "*" => Some(self.positional.join(" ")),
+            name if name.chars().all(|ch| ch.is_ascii_digit()) => {
+                // `${00}` is `$0` all the same
+                name.parse::<usize>().ok().and_then(|idx| match idx.checked_sub(1) {
+                    Some(idx) => self.positional.get(idx).cloned(),
+                    None => self.lookup_var("0"),
+                })
+            }
+            // `${#name}` is the length of the value, `${#name[@]}` the number of elements
+            name if name.starts_with('#') => match name[1..].strip_suffix("[@]").or_else(|| name[1..].strip_suffix("[*]")) {
+                Some(array) => Some(self.arrays.get(array).map_or(0, Vec::len).to_string()),
+                None => Some(self.lookup_var(&name[1..]).unwrap_or_default().chars().count().to_string()),
+            },
+            name if name.ends_with(']') => {
+                let (array, index) = name[..name.len() - 1].split_once('[')?;
+                let elements = self.arrays.get(array)?;
+
+                match index {
+                    "@" | "*" => Some(elements.join(" ")),
+                    index => elements.get(index.parse::<usize>().ok()?).cloned(),
                 }
             }
-        });
+            name => self.vars.get(name).cloned()
+                .or_else(|| get_environment(name).map(String::from))
+                .or_else(|| self.arrays.get(name).and_then(|elements| elements.first().cloned())),
+        }
+    }
 
-        inter
+    /// Assigns a variable, it stays exported if it already was.
+    pub fn set_var (&mut self, name: &str, value: &str) {
+        if get_environment(name).is_some() {
+            utils::set_environment(name, value);
+        } else {
+            self.vars.insert(name.to_string(), value.to_string());
+        }
     }
 
-    pub fn get_builtins (&self) -> Vec<&&str> {
-        let mut vec: Vec<&&str> = self.builtins.keys().collect();
+    /// Counts a visit to `dir` for `j`. Only directories the user went to at the prompt count,
+    /// not the ones scripts go through.
+    fn record_visit (&self, dir: &str) {
+        if !self.interactive || get_environment("HOME") == Some(dir) { return; }
+        let Some(file) = jump::database_path() else { return };
+
+        let mut db = jump::Database::load(&file);
+        db.visit(dir, jump::now());
 
-        for key in self.shell_commands.keys() {
-            vec.push(key);
+        if let Err(error) = db.save(&file) {
+            eprintln!("nyash: {file}: {error}");
+        }
+    }
+
+    /// Changes directory for `pushd`, `popd` and `j`, reporting why it couldn't.
+    fn enter_dir (&mut self, name: &str, dir: &str) -> bool {
+        match dirs::change_dir(dir, false) {
+            Ok(pwd) => {
+                self.record_visit(&pwd);
+                true
+            }
+            Err(error) => {
+                eprintln!("nyash: {name}: {dir}: {error}");
+                self.status = 1;
+                false
+            }
         }
-        
-        vec
     }
 
-    pub fn interpret_command (&self, cmd: &str, argv: &Vec<&str>) -> bool {
-        let commands = argv.split(|argv| argv == &"|").collect::<Vec<&[&str]>>();
+    fn show_dir (&self, dir: &str, long: bool) -> String {
+        if long { dir.to_string() } else { dirs::abbreviate(dir) }
+    }
 
-        if commands.len() == 1 {
-            if let Some(executor) = self.shell_commands.get(cmd) {
-                executor(&argv[1..], self);
-                return true;
+    /// Writes out a builtin's output, the builtin fails if that doesn't work.
+    fn print (&mut self, name: &str, output: &str) {
+        if write_output(name, output.as_bytes()) != 0 {
+            self.status = 1;
+        }
+    }
+
+    /// The directory stack as `dirs` shows it, the current directory first.
+    fn print_dirs (&mut self, name: &str, long: bool, lines: bool, numbered: bool) {
+        let current = dirs::current_dir();
+        let all: Vec<String> = std::iter::once(&current).chain(&self.dir_stack)
+            .map(|dir| self.show_dir(dir, long))
+            .collect();
+
+        let mut out = String::new();
+        if numbered {
+            for (idx, dir) in all.iter().enumerate() {
+                let _ = writeln!(out, "{idx:2}  {dir}");
             }
+        } else if lines {
+            for dir in all {
+                let _ = writeln!(out, "{dir}");
+            }
+        } else {
+            let _ = writeln!(out, "{}", all.join(" "));
+        }
+        self.print(name, &out);
+    }
 
-            if let Some(path) = get_executable(cmd) {
-                match self.exec_command(&path, argv, None) {
-                    Err(error) => eprintln!("error running {cmd}: {error}"),
-                    Ok(pid) => { Self::wait_process(pid); }
-                };
-                return true;
+    /// Where `source name` reads from: a name without a slash is looked for in PATH first.
+    fn find_source (&self, name: &str) -> String {
+        if !name.contains('/') {
+            let path = get_environment("PATH").unwrap_or("");
+
+            for dir in std::env::split_paths(path) {
+                let candidate = dir.join(name);
+                if utils::stat(&candidate).is_some_and(|st| st.st_mode & libc::S_IFMT == libc::S_IFREG) {
+                    return candidate.to_string_lossy().to_string();
+                }
             }
+        }
 
-            if self.builtins.contains_key(cmd) {
-                match self.exec_command(cmd, argv, None) {
-                    Err(error) => eprintln!("error running {cmd}: {error}"),
-                    Ok(pid) => { Self::wait_process(pid); }
-                };
-                return true;
+        name.to_string()
+    }
+
+    /// Runs the file at `path` in the current shell, with `args` as its positional parameters.
+    fn source_file (&mut self, path: &str, args: &[&str]) {
+        let script = match std::fs::read(path) {
+            Ok(script) => String::from_utf8_lossy(&script).to_string(),
+            Err(error) => {
+                let message = error.raw_os_error().map(utils::strerror).unwrap_or(error.to_string());
+                eprintln!("nyash: {path}: {message}");
+                self.status = 1;
+                return;
             }
+        };
+
+        let saved = (!args.is_empty())
+            .then(|| std::mem::replace(&mut self.positional, args.iter().map(|arg| arg.to_string()).collect()));
+
+        self.source_depth += 1;
+        self.run_source(&script);
+        self.source_depth -= 1;
 
+        // `return` only leaves this file
+        self.returning = false;
+        self.run_trap(Trap::Return);
 
-            return false;
+        if let Some(saved) = saved {
+            self.positional = saved;
         }
+    }
 
+    /// Runs every line of `path` in this interpreter, this is what we fall back to
+    /// when the kernel doesn't know how to execute a file.
+    pub fn run_script (&mut self, path: &str) -> i32 {
+        let script = match std::fs::read_to_string(path) {
+            Ok(script) => script,
+            Err(error) => {
+                eprintln!("nyash: {path}: {error}");
+                return 126;
+            }
+        };
 
-        let mut all_fds = Vec::new();
-        let mut pids = Vec::new();
-        let num_pipes = commands.len() - 1;
-        
-        for _ in 0..num_pipes {
-            let mut fds = [0; 2];
-            unsafe { libc::pipe(fds.as_mut_ptr()); }
-            all_fds.push(fds);
+        self.run_source(&script);
+        self.status
+    }
+
+    /// Executes shell code command by command in the current interpreter.
+    pub fn run_source (&mut self, source: &str) {
+        let mut lines = source.lines().map(String::from);
+
+        while let Some(mut command) = lines.next() {
+            parser::complete_input(&mut command, &mut |_| lines.next());
+            self.execute(&command);
+
+            if self.returning { break; }
         }
+    }
 
-        for ( idx, argv ) in commands.iter().enumerate() {
-            if argv.is_empty() { return false; };
-            let cmd = argv.first().unwrap();
+    /// Appends a command line to the history file. Lines of a multi-line command end
+    /// with a `\` there so it's read back as one entry, backslashes already at the end
+    /// of a line are doubled.
+    pub fn add_history (&self, entry: &str) {
+        let lines: Vec<String> = entry.split('\n').map(|line| {
+            let text = line.trim_end_matches('\\');
+            format!("{text}{}", "\\".repeat(2 * (line.len() - text.len())))
+        }).collect();
 
-            let mut pipeline = Vec::new();
+        let record = format!("{}\n", lines.join("\\\n"));
+        let buf = record.as_bytes();
 
-            if idx > 0 {
-                pipeline.push(PipeLine {
-                    fd_t: libc::STDIN_FILENO,
-                    fds: all_fds[idx - 1],
-                });
+        let written = unsafe { libc::write(self.history, buf.as_ptr().cast(), buf.len()) };
+        if written == -1 {
+            eprintln!("Failed to write: {}", io::Error::last_os_error());
+        }
+    }
+
+    /// Every entry of the history file, oldest first.
+    pub fn read_history (&self) -> Vec<String> {
+        let mut contents = Vec::new();
+        let mut buf = [0u8; 4096];
+
+        loop {
+            let n = unsafe { libc::pread(self.history, buf.as_mut_ptr().cast(), buf.len(), contents.len() as libc::off_t) };
+            if n <= 0 { break; }
+            contents.extend_from_slice(&buf[..n as usize]);
+        }
+
+        let mut entries: Vec<String> = Vec::new();
+        let mut continued = false;
+
+        for line in String::from_utf8_lossy(&contents).lines() {
+            // an odd number of trailing backslashes means the entry goes on
+            let text = line.trim_end_matches('\\');
+            let trailing = line.len() - text.len();
+            let line = format!("{text}{}", "\\".repeat(trailing / 2));
+
+            match entries.last_mut() {
+                Some(entry) if continued => {
+                    entry.push('\n');
+                    entry.push_str(&line);
+                }
+                _ => entries.push(line),
             }
 
-            if idx < all_fds.len() {
-                pipeline.push(PipeLine {
-                    fd_t: libc::STDOUT_FILENO,
-                    fds: all_fds[idx],
-                });
+            continued = trailing % 2 == 1;
+        }
+
+        entries
+    }
+
+    /// Parses and runs a command line. Returns false if it had a syntax error
+    /// or a command wasn't found.
+    pub fn execute (&mut self, line: &str) -> bool {
+        match parser::parse(line, &self.aliases) {
+            Ok(list) => self.run_list(&list),
+            Err(error) => {
+                eprintln!("nyash: {error}");
+                self.status = 2;
+                false
             }
+        }
+    }
 
-            if let Some(path) = get_executable(cmd) {
-                match self.exec_command(&path, argv, Some((pipeline, &all_fds))) {
-                    Err(error) => eprintln!("error running {cmd}: {error}"),
-                    Ok(pid) => { pids.push(pid); }
-                };
+    fn set_option (&mut self, option: &'static str, enable: bool) {
+        if enable {
+            // the line editor has one set of keys at a time
+            match option {
+                "vi" => { self.options.remove("emacs"); }
+                "emacs" => { self.options.remove("vi"); }
+                _ => {}
+            }
+            self.options.insert(option);
+        } else {
+            self.options.remove(option);
+        }
+    }
 
-                continue;
+    fn set_trap (&mut self, trap: Trap, action: &str) {
+        match action {
+            "-" => {
+                self.traps.remove(&trap);
+                if let Trap::Signal(sig) = trap { signals::restore(sig, self.interactive); }
+            }
+            "" => {
+                self.traps.insert(trap, String::new());
+                if let Trap::Signal(sig) = trap { signals::ignore(sig); }
             }
+            action => {
+                self.traps.insert(trap, action.to_string());
+                if let Trap::Signal(sig) = trap { signals::catch(sig); }
+            }
+        }
+    }
 
-            if self.builtins.contains_key(cmd) {
-                match self.exec_command(cmd, argv, Some((pipeline, &all_fds))) {
-                    Err(error) => eprintln!("error running {cmd}: {error}"),
-                    Ok(pid) => { pids.push(pid); }
-                };
+    /// Runs the command attached to `trap`, leaving `$?` as it was.
+    pub fn run_trap (&mut self, trap: Trap) {
+        if self.in_trap { return; }
+
+        let Some(action) = self.traps.get(&trap).filter(|action| !action.is_empty()).cloned() else { return };
+        let status = self.status;
+
+        self.in_trap = true;
+        self.run_source(&action);
+        self.in_trap = false;
+
+        self.status = status;
+    }
+
+    /// Runs the traps of the signals that arrived since we last looked.
+    pub fn run_pending_traps (&mut self) {
+        for sig in signals::take_pending() {
+            self.run_trap(Trap::Signal(sig));
+        }
+    }
+
+    /// Leaves the shell, running the EXIT trap first.
+    pub fn exit (&mut self, status: i32) -> ! {
+        self.run_trap(Trap::Exit);
+        process::exit(status);
+    }
+
+    /// What a forked child does first: it's no longer an interactive shell and doesn't keep the traps.
+    fn reset_for_child (&mut self) {
+        self.jobs.disable_job_control();
 
-                continue;
+        // traps aren't inherited, only the signals explicitly ignored stay that way
+        self.traps.retain(|_, action| action.is_empty());
+        let ignored: Vec<i32> = self.traps.keys()
+            .filter_map(|trap| if let Trap::Signal(sig) = trap { Some(*sig) } else { None })
+            .collect();
+        signals::reset_for_child(&ignored);
+        self.interactive = false;
+    }
+
+    /// Starts `command` for a `<(command)` (`input`) or `>(command)` substitution, connected to a pipe
+    /// whose other end stays open in the shell. Returns the `/dev/fd` path of that end.
+    fn spawn_substitution (&mut self, command: &str, input: bool) -> Result<String, String> {
+        let mut fds = [0; 2];
+
+        unsafe {
+            if libc::pipe(fds.as_mut_ptr()) != 0 {
+                return Err(format!("cannot make pipe for process substitution: {}", utils::strerror(utils::errno())));
             }
 
-            // this is not likely to happen during tests
-            for [ read, write ] in &all_fds {
-                unsafe {
-                    libc::close(*read);
-                    libc::close(*write);
+            // `<(cmd)` reads what cmd writes
+            let (ours, theirs, target) = if input { (fds[0], fds[1], 1) } else { (fds[1], fds[0], 0) };
+
+            match libc::fork() {
+                -1 => {
+                    libc::close(ours);
+                    libc::close(theirs);
+                    Err(format!("cannot fork: {}", utils::strerror(utils::errno())))
                 }
-            }
+                0 => {
+                    self.reset_for_child();
+
+                    libc::dup2(theirs, target);
+                    libc::close(theirs);
+                    libc::close(ours);
+                    for &fd in &self.substitution_fds {
+                        libc::close(fd);
+                    }
+                    self.substitution_fds.clear();
+
+                    self.execute(command);
+                    let _ = io::stdout().flush();
+                    process::exit(self.status);
+                }
+                pid => {
+                    libc::close(theirs);
+
+                    // out of the way of the fds redirections use, but still inherited by the command
+                    let fd = libc::fcntl(ours, libc::F_DUPFD, 10);
+                    libc::close(ours);
 
-            return false;
+                    self.substitution_fds.push(fd);
+                    self.substitution_pids.push(pid);
+                    Ok(format!("/dev/fd/{fd}"))
+                }
+            }
         }
+    }
 
-        for [ read, write ] in &all_fds {
-            unsafe {
-                libc::close(*read);
-                libc::close(*write);
+    /// Runs `command` in a child for a `$(command)` in a prompt, and returns what it wrote
+    /// without the trailing newlines.
+    fn capture_output (&mut self, command: &str) -> String {
+        let mut fds = [0; 2];
+
+        unsafe {
+            if libc::pipe(fds.as_mut_ptr()) != 0 {
+                eprintln!("nyash: cannot make pipe for command substitution: {}", utils::strerror(utils::errno()));
+                return String::new();
+            }
+
+            match libc::fork() {
+                -1 => {
+                    libc::close(fds[0]);
+                    libc::close(fds[1]);
+                    eprintln!("nyash: cannot fork: {}", utils::strerror(utils::errno()));
+                    String::new()
+                }
+                0 => {
+                    self.reset_for_child();
+                    self.options.remove("xtrace");
+
+                    libc::dup2(fds[1], 1);
+                    libc::close(fds[1]);
+                    libc::close(fds[0]);
+
+                    self.execute(command);
+                    let _ = io::stdout().flush();
+                    process::exit(self.status);
+                }
+                pid => {
+                    libc::close(fds[1]);
+
+                    let mut output = Vec::new();
+                    let _ = File::from_raw_fd(fds[0]).read_to_end(&mut output);
+                    jobs::wait_child(pid);
+
+                    String::from_utf8_lossy(&output).trim_end_matches('\n').to_string()
+                }
             }
         }
+    }
+
+    /// The prompt variable `name` (PS1, PS2 or PS4) expanded, `default` if it isn't set.
+    /// The escapes say where the shell is at, `$(command)` in it leaves `$?` alone.
+    pub fn prompt (&mut self, name: &str, default: &str) -> String {
+        let text = self.lookup_var(name).unwrap_or_else(|| default.to_string());
+
+        let pwd = dirs::current_dir();
+        let info = PromptInfo {
+            user: utils::get_user(),
+            host: utils::get_hostname(),
+            git: if text.contains("\\g") { self.git.segment(&pwd) } else { String::new() },
+            pwd,
+            jobs: self.jobs.iter().count(),
+            // reading the whole history file for it is only worth it if it's asked for
+            history: if text.contains("\\!") { self.read_history().len() + 1 } else { 0 },
+            root: unsafe { libc::geteuid() } == 0,
+            time: unsafe { libc::time(std::ptr::null_mut()) },
+        };
+
+        let status = self.status;
+        let prompt = prompt::expand(&text, &info, &mut |expansion| match expansion {
+            Expansion::Variable(name) => self.lookup_var(name).unwrap_or_default(),
+            Expansion::Command(command) => self.capture_output(command),
+        });
+        self.status = status;
+
+        prompt
+    }
+
+    /// Runs $PROMPT_COMMAND before the prompt is shown, `$?` stays the one of the last command.
+    pub fn run_prompt_command (&mut self) {
+        let Some(command) = self.lookup_var("PROMPT_COMMAND").filter(|command| !command.is_empty()) else { return };
+        let status = self.status;
+
+        self.run_source(&command);
+        self.status = status;
+    }
+
+    /// `set -x`: shows a command as it's about to run, after $PS4.
+    fn trace (&mut self, command: &SimpleCommand) {
+        let quote = |word: &String| {
+            if !word.is_empty() && word.chars().all(|ch| ch.is_ascii_alphanumeric() || "-_./=:,+@%^".contains(ch)) {
+                word.clone()
+            } else {
+                format!("'{}'", word.replace('\'', "'\\''"))
+            }
+        };
+
+        let assignments = command.assignments.iter().map(|assignment| match assignment.split_once('=') {
+            Some((name, value)) => format!("{name}={}", quote(&value.to_string())),
+            None => quote(assignment),
+        });
+        let words: Vec<String> = assignments.chain(command.words.iter().map(quote)).collect();
+        let ps4 = prompt::printable(&self.prompt("PS4", "+ "));
 
-        for pid in pids {
-            Self::wait_process(pid);
+        eprintln!("{ps4}{}", words.join(" "));
+    }
+
+    /// Closes the shell's ends of the process substitutions once their command started,
+    /// and collects the ones that are done.
+    fn finish_substitutions (&mut self) {
+        for fd in self.substitution_fds.drain(..) {
+            unsafe { libc::close(fd); }
         }
 
-        true
+        self.substitution_pids.retain(|&pid| !jobs::reap(pid));
     }
 
-    pub fn wait_process (pid: i32) -> i32 {
-        let mut status = 0;
+    /// Sets up a freshly forked pipeline stage: its process group, the pipes and its redirections.
+    fn setup_stage (&mut self, pipe: Option<(Vec<utils::PipeLine>, &Vec<[i32; 2]>)>, pgid: i32, foreground: bool, redirects: &[Redirect]) {
+        self.jobs.setup_child(pgid, foreground);
+        self.reset_for_child();
+
         unsafe {
-            libc::waitpid(pid, &mut status, 0);
-        };
-        status
+            if let Some((pipes, all_fds)) = pipe {
+                for pipe in pipes {
+                    match pipe.fd_t {
+                        libc::STDOUT_FILENO => { 
+                            libc::dup2(pipe.fds[1], pipe.fd_t); 
+                            libc::close(pipe.fds[0]);
+                        },
+                        libc::STDIN_FILENO => {
+                            libc::dup2(pipe.fds[0], pipe.fd_t); 
+                            libc::close(pipe.fds[1]);
+                        },
+                        _ => {}
+                    }
+                }
+
+                for [ r, w ] in all_fds {
+                    libc::close(*w);
+                    libc::close(*r);
+                }
+            }
+        }
+
+        let noclobber = self.options.contains("noclobber");
+        for redirect in redirects {
+            if let Err(error) = redirect::apply(redirect, noclobber) {
+                eprintln!("nyash: {error}");
+                process::exit(1);
+            }
+        }
+    }
+
+    /// Forks a copy of the shell to run `list`: a `( )` subshell, a group inside a pipeline
+    /// or an and-or list in the background. Variables, cwd and traps changed there stay there.
+    fn fork_list (&mut self, list: &List, redirects: &[Redirect], pipe: Option<(Vec<utils::PipeLine>, &Vec<[i32; 2]>)>, pgid: i32, foreground: bool) -> Result<i32, String> {
+        match unsafe { libc::fork() } {
+            0 => {
+                self.setup_stage(pipe, pgid, foreground, redirects);
+
+                self.run_list(list);
+                let _ = io::stdout().flush();
+                process::exit(self.status);
+            }
+            pid if pid > 0 => {
+                self.jobs.setup_parent(pid, pgid);
+                Ok(pid)
+            }
+            _ => Err(format!("fork: {}", utils::strerror(utils::errno()))),
+        }
     }
 
-    pub fn exec_command (&self, cmd: &str, argv: &[&str], pipe: Option<(Vec<utils::PipeLine>, &Vec<[i32; 2]>)>) -> Result<i32> {
+    /// Forks and runs `command`, `path` being what `resolve_command` found for it.
+    /// The child joins the process group `pgid` (0 to lead a new one).
+    pub fn exec_command (&mut self, path: &str, command: &SimpleCommand, pipe: Option<(Vec<utils::PipeLine>, &Vec<[i32; 2]>)>, pgid: i32, foreground: bool) -> Result<i32> {
         unsafe {
             let pid = libc::fork();
 
             match pid {
                 0 => {
                     // this is a child process!
-                    
-                    if let Some((pipes, all_fds)) = pipe {
-                        for pipe in pipes {
-                            match pipe.fd_t {
-                                libc::STDOUT_FILENO => { 
-                                    libc::dup2(pipe.fds[1], pipe.fd_t); 
-                                    libc::close(pipe.fds[0]);
-                                },
-                                libc::STDIN_FILENO => {
-                                    libc::dup2(pipe.fds[0], pipe.fd_t); 
-                                    libc::close(pipe.fds[1]);
-                                },
-                                _ => {}
-                            }
-                        }
-
-                        for [ r, w ] in all_fds {
-                            libc::close(*w);
-                            libc::close(*r);
-                        }
-                    }
-                    
-                    let r_argv = argv;
-                    let mut argv = Vec::new();
-                    let mut redirections = Vec::<(&str, &str)>::new();
+                    self.setup_stage(pipe, pgid, foreground, &command.redirects);
 
-                    let mut iter = r_argv.iter();
+                    let argv: Vec<&str> = command.words.iter().map(|s| s.as_str()).collect();
+                    if argv.is_empty() { process::exit(0); }
 
-                    while let Some(&arg) = iter.next() {
-                        match arg {
-                            ">" | "<" | ">>" | "1>" | "2>" | "&>" | "1>>" | "2>>" => {
-                                let Some(file) = iter.next() else { 
-                                    return Err(io::Error::new(io::ErrorKind::Other, "Syntax error").into()); 
-                                };
-
-                                redirections.push(( arg, file ));
-                            }
-                            _ => {
-                                argv.push(arg);
-                            }
-                        }
-                    };
-
-                    for (re, file) in redirections {
-                        match re {
-                            ">" | "1>" => {
-                                let _ = redirect_io(file, O_WRONLY | O_TRUNC | O_CREAT, 1);
-                            }
-                            "<" => {
-                                let _ = redirect_io(file, O_RDONLY, 0);
-                            }
-                            ">>" | "1>>" => {
-                                let _ = redirect_io(file, O_WRONLY | O_CREAT | O_APPEND, 1);
-                            }
-                            "2>" => {
-                                let _ = redirect_io(file, O_WRONLY | O_TRUNC | O_CREAT, 2);
-                            }
-                            "2>>" => {
-                                let _ = redirect_io(file, O_WRONLY | O_CREAT | O_APPEND, 2);
-                            }
-                            "&>" => {
-                                redirect_io(file, O_WRONLY | O_TRUNC | O_CREAT, 1).unwrap();
-                                libc::dup2(1, 2);                                
-                            }
-                            _ => {}
-                        }
+                    for assignment in &command.assignments {
+                        let (name, value) = assignment.split_once('=').unwrap_or((assignment, ""));
+                        utils::set_environment(name, value);
                     }
 
-                    if let Some(executor) = self.builtins.get(cmd) {
+                    if let Some(&executor) = self.builtins.get(path) {
+                        self.last_status = self.status;
+                        self.status = 0;
                         executor(&argv[1..], self);
-                        process::exit(0);
+                        let _ = io::stdout().flush();
+                        process::exit(self.status);
                     }
 
                     // child process
-                    let exec_path = CString::new(cmd)?;
+                    let exec_path = CString::new(path)?;
                     let c_args: Vec<CString> = argv.iter()
                         .map(|&s| CString::new(s).unwrap())
                         .collect();
@@ -303,17 +2026,107 @@ impl Interpreter {
 
                     libc::execv(exec_path.as_ptr(), argv.as_ptr());
 
-                    // we shouldn't normally reach here.
-                    panic!("We're cooked man!");
+                    // we only get here if exec failed
+                    let error = io::Error::last_os_error();
+                    match error.raw_os_error() {
+                        Some(libc::ENOEXEC) => {
+                            // the script gets the rest of the command line as $1, $2...
+                            self.positional = command.words[1..].to_vec();
+                            process::exit(self.run_script(path));
+                        }
+                        Some(libc::ENOENT) => {
+                            eprintln!("nyash: {path}: No such file or directory");
+                            process::exit(127);
+                        }
+                        _ => {
+                            eprintln!("nyash: {path}: {error}");
+                            process::exit(126);
+                        }
+                    }
                 },
                 pid if pid > 0 => { 
+                    self.jobs.setup_parent(pid, pgid);
                     Ok(pid)
                 },
                 _ => {
-                    let error = io::Error::new(io::ErrorKind::Other, format!("failed to spawn process {}", cmd));
+                    let error = io::Error::other(format!("failed to spawn process {}", path));
                     Err(Error::from(error))
                 }
             }
         }
     } 
 }
+
+#[cfg(test)]
+mod interpreter_tests {
+    use std::fs;
+
+    use super::*;
+    use crate::utils::TestDir;
+
+    #[test]
+    fn positional_parameters () {
+        let inter = Interpreter { positional: vec!["a".to_string(), "b".to_string()], ..Default::default() };
+
+        assert_eq!(inter.lookup_var("0").as_deref(), Some("nyash"));
+        assert_eq!(inter.lookup_var("00").as_deref(), Some("nyash"));
+        assert_eq!(inter.lookup_var("2").as_deref(), Some("b"));
+        assert_eq!(inter.lookup_var("02").as_deref(), Some("b"));
+        assert_eq!(inter.lookup_var("3"), None);
+        assert_eq!(inter.lookup_var("#").as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn script_without_shebang () {
+        let dir = TestDir::new("script");
+        let script = dir.join("script");
+        let out = dir.join("out");
+
+        fs::write(&script, format!("echo n=$# 1=$1 2=$2 > {}\n", out.display())).unwrap();
+        fs::set_permissions(&script, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+
+        let mut inter = Interpreter::new("/dev/null");
+        inter.execute(&format!("{} a 'b c'", script.display()));
+        assert_eq!(fs::read_to_string(&out).unwrap(), "n=2 1=a 2=b c\n");
+    }
+
+    #[test]
+    fn return_trap () {
+        let dir = TestDir::new("return");
+        let file = dir.join("file");
+        fs::write(&file, "X=1; return 3; X=2\n").unwrap();
+
+        let mut inter = Interpreter::new("/dev/null");
+        inter.execute("trap 'RAN=$X:$1' RETURN");
+        inter.execute(&format!(". {} arg", file.display()));
+
+        assert_eq!(inter.lookup_var("RAN").as_deref(), Some("1:arg"));
+        assert_eq!(inter.status, 3);
+    }
+
+    #[test]
+    fn background_jobs () {
+        let mut inter = Interpreter::new("/dev/null");
+
+        inter.execute("sleep 0.1 &");
+        inter.execute("bg");
+        assert_eq!(inter.status, 1);
+
+        inter.execute("false & false; wait");
+        assert_eq!(inter.status, 0);
+        assert!(inter.jobs.iter().next().is_none());
+    }
+
+    #[test]
+    fn hash_errors () {
+        let mut inter = Interpreter::new("/dev/null");
+
+        for line in ["hash -p /bin/ls", "hash -d nyash-none", "hash -t nyash-none", "hash nyash-none"] {
+            inter.execute(line);
+            assert_eq!(inter.status, 1, "{line}");
+        }
+
+        inter.execute("hash -p /bin/ls ls");
+        assert_eq!(inter.status, 0);
+    }
+}
@@ -0,0 +1,428 @@
+use std::ffi::CStr;
+
+use crate::signals::{self, ChildSignalsBlocked};
+use crate::utils;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JobState {
+    Running,
+    Stopped,
+    Done
+}
+
+#[derive(Debug)]
+pub struct Process {
+    pub pid: i32,
+    // raw waitpid status, None while the process is still running
+    pub status: Option<i32>,
+    pub stopped: bool
+}
+
+/// A pipeline tracked as a single unit, all of its processes share the `pgid` process group.
+pub struct Job {
+    pub id: usize,
+    pub pgid: i32,
+    pub processes: Vec<Process>,
+    pub command: String,
+    // terminal modes the job had when it got stopped, given back to it on `fg`
+    tmodes: Option<libc::termios>
+}
+
+impl Job {
+    pub fn state (&self) -> JobState {
+        if self.processes.iter().all(|p| p.status.is_some()) {
+            JobState::Done
+        } else if self.processes.iter().all(|p| p.status.is_some() || p.stopped) {
+            JobState::Stopped
+        } else {
+            JobState::Running
+        }
+    }
+
+    fn record (&mut self, pid: i32, status: i32) {
+        let Some(process) = self.processes.iter_mut().find(|p| p.pid == pid) else { return };
+
+        if libc::WIFSTOPPED(status) {
+            process.stopped = true;
+        } else if libc::WIFCONTINUED(status) {
+            process.stopped = false;
+        } else {
+            process.status = Some(status);
+        }
+    }
+
+    /// The job's exit status is the one of its last process, like a pipeline's.
+    pub fn status (&self) -> i32 {
+        self.processes.last()
+            .and_then(|p| p.status)
+            .map(utils::exit_status)
+            .unwrap_or(0)
+    }
+
+    fn resume (&mut self) {
+        for process in &mut self.processes {
+            process.stopped = false;
+        }
+
+        unsafe {
+            if self.pgid > 0 {
+                libc::kill(-self.pgid, libc::SIGCONT);
+            } else {
+                for process in self.processes.iter().filter(|p| p.status.is_none()) {
+                    libc::kill(process.pid, libc::SIGCONT);
+                }
+            }
+        }
+    }
+
+    pub fn leader (&self) -> i32 {
+        self.processes.first().map(|p| p.pid).unwrap_or(0)
+    }
+
+    fn state_name (&self) -> String {
+        match self.state() {
+            JobState::Running => "Running".to_string(),
+            JobState::Stopped => "Stopped".to_string(),
+            JobState::Done => {
+                let status = self.processes.last().and_then(|p| p.status).unwrap_or(0);
+
+                if libc::WIFSIGNALED(status) {
+                    unsafe {
+                        let desc = libc::strsignal(libc::WTERMSIG(status));
+                        if desc.is_null() { "Killed".to_string() }
+                        else { CStr::from_ptr(desc).to_string_lossy().to_string() }
+                    }
+                } else if self.status() != 0 {
+                    format!("Exit {}", self.status())
+                } else {
+                    "Done".to_string()
+                }
+            }
+        }
+    }
+
+    /// Formats the job the way `jobs` lists it, `marker` is `+` for the current job and `-` for the previous one.
+    pub fn describe (&self, marker: char, long: bool) -> String {
+        let suffix = if self.state() == JobState::Running { " &" } else { "" };
+
+        if long {
+            let mut lines = Vec::new();
+
+            for (idx, process) in self.processes.iter().enumerate() {
+                if idx == 0 {
+                    lines.push(format!("[{}]{marker} {:>5} {:<24}{}{suffix}", self.id, process.pid, self.state_name(), self.command));
+                } else {
+                    lines.push(format!("     {:>5}", process.pid));
+                }
+            }
+
+            lines.join("\n")
+        } else {
+            format!("[{}]{marker}  {:<24}{}{suffix}", self.id, self.state_name(), self.command)
+        }
+    }
+}
+
+/// What the shell needs to hand the terminal to its jobs and take it back.
+struct Terminal {
+    fd: i32,
+    shell_pgid: i32,
+    tmodes: libc::termios
+}
+
+#[derive(Default)]
+pub struct JobTable {
+    jobs: Vec<Job>,
+    // only set when the shell runs interactively on a terminal
+    terminal: Option<Terminal>
+}
+
+impl JobTable {
+    /// Puts the shell in its own process group in control of `fd`, jobs get their own groups from now on.
+    pub fn enable_job_control (&mut self, fd: i32) {
+        unsafe {
+            if libc::isatty(fd) == 0 { return; }
+
+            // wait until we're in the foreground before touching the terminal
+            loop {
+                let pgrp = libc::getpgrp();
+                if libc::tcgetpgrp(fd) == pgrp { break; }
+                libc::kill(-pgrp, libc::SIGTTIN);
+            }
+
+            // the shell itself must never be stopped or interrupted by the terminal
+            signals::ignore_interactive();
+
+            let shell_pgid = libc::getpid();
+            if libc::getpgrp() != shell_pgid && libc::setpgid(shell_pgid, shell_pgid) < 0 {
+                eprintln!("nyash: couldn't put the shell in its own process group");
+                return;
+            }
+            libc::tcsetpgrp(fd, shell_pgid);
+
+            let mut tmodes = std::mem::zeroed();
+            libc::tcgetattr(fd, &mut tmodes);
+
+            self.terminal = Some(Terminal { fd, shell_pgid, tmodes });
+        }
+    }
+
+    /// Forked children keep a copy of the table but must not play with the terminal.
+    pub fn disable_job_control (&mut self) {
+        self.terminal = None;
+    }
+
+    /// Called in a freshly forked child: joins the job's process group (`pgid` 0 starts a new one)
+    /// and takes the terminal if it's a foreground job.
+    pub fn setup_child (&self, pgid: i32, foreground: bool) {
+        let Some(terminal) = &self.terminal else { return };
+
+        unsafe {
+            let pid = libc::getpid();
+            let pgid = if pgid == 0 { pid } else { pgid };
+
+            libc::setpgid(pid, pgid);
+            if foreground {
+                libc::tcsetpgrp(terminal.fd, pgid);
+            }
+        }
+    }
+
+    /// The parent's half of `setup_child`, both sides do it so neither has to wait for the other.
+    pub fn setup_parent (&self, pid: i32, pgid: i32) {
+        if self.terminal.is_none() { return; }
+
+        unsafe {
+            libc::setpgid(pid, if pgid == 0 { pid } else { pgid });
+        }
+    }
+
+    pub fn add (&mut self, pids: Vec<i32>, command: String) -> usize {
+        let id = self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+        let pgid = if self.terminal.is_some() { pids.first().copied().unwrap_or(0) } else { 0 };
+        let processes = pids.into_iter().map(|pid| Process { pid, status: None, stopped: false }).collect();
+
+        self.jobs.push(Job { id, pgid, processes, command, tmodes: None });
+        id
+    }
+
+    pub fn iter (&self) -> impl Iterator<Item = &Job> {
+        self.jobs.iter()
+    }
+
+    /// `+` for the most recent job, `-` for the one before it.
+    pub fn marker (&self, id: usize) -> char {
+        let len = self.jobs.len();
+
+        if len > 0 && self.jobs[len - 1].id == id { '+' }
+        else if len > 1 && self.jobs[len - 2].id == id { '-' }
+        else { ' ' }
+    }
+
+    /// Resolves a job spec (`%1`, `%%`, `%+`, `%-`, `%name`, `%?text`) to a job id.
+    pub fn find (&self, spec: &str) -> Result<usize, String> {
+        let not_found = || Err(format!("{spec}: no such job"));
+
+        let Some(spec_body) = spec.strip_prefix('%') else {
+            return match spec.parse::<i32>() {
+                Ok(pid) => match self.jobs.iter().find(|job| job.processes.iter().any(|p| p.pid == pid)) {
+                    Some(job) => Ok(job.id),
+                    None => not_found(),
+                },
+                Err(_) => not_found(),
+            };
+        };
+
+        let found = match spec_body {
+            "" | "%" | "+" => self.jobs.last(),
+            "-" => self.jobs.iter().rev().nth(1),
+            body if body.chars().all(|c| c.is_ascii_digit()) => {
+                let id = body.parse::<usize>().unwrap_or(0);
+                self.jobs.iter().find(|job| job.id == id)
+            }
+            body => match body.strip_prefix('?') {
+                Some(text) => self.jobs.iter().rev().find(|job| job.command.contains(text)),
+                None => self.jobs.iter().rev().find(|job| job.command.starts_with(body)),
+            }
+        };
+
+        match found {
+            Some(job) => Ok(job.id),
+            None if spec_body.is_empty() || spec_body == "%" || spec_body == "+" => Err("current: no such job".to_string()),
+            None => not_found(),
+        }
+    }
+
+    pub fn get (&self, id: usize) -> Option<&Job> {
+        self.jobs.iter().find(|job| job.id == id)
+    }
+
+    pub fn remove (&mut self, id: usize) -> Option<Job> {
+        let idx = self.jobs.iter().position(|job| job.id == id)?;
+        Some(self.jobs.remove(idx))
+    }
+
+    /// Collects the status of every process that finished, stopped or continued without blocking.
+    pub fn update (&mut self) {
+        for job in &mut self.jobs {
+            let pids: Vec<i32> = job.processes.iter().filter(|p| p.status.is_none()).map(|p| p.pid).collect();
+
+            for pid in pids {
+                while let Some(status) = wait_pid(pid, libc::WNOHANG | libc::WUNTRACED | libc::WCONTINUED) {
+                    job.record(pid, status);
+                    if job.processes.iter().any(|p| p.pid == pid && p.status.is_some()) { break; }
+                }
+            }
+        }
+    }
+
+    /// Blocks until every process of the job finished or stopped.
+    pub fn wait (&mut self, id: usize) {
+        let Some(job) = self.jobs.iter_mut().find(|job| job.id == id) else { return };
+
+        let pids: Vec<i32> = job.processes.iter()
+            .filter(|p| p.status.is_none() && !p.stopped)
+            .map(|p| p.pid)
+            .collect();
+
+        for pid in pids {
+            if let Some(status) = wait_pid(pid, libc::WUNTRACED) {
+                job.record(pid, status);
+            }
+        }
+    }
+
+    /// Blocks until `pid` finished or stopped, returning its raw status if it belongs to one of our jobs.
+    pub fn wait_pid (&mut self, pid: i32) -> Option<i32> {
+        let job = self.jobs.iter_mut().find(|job| job.processes.iter().any(|p| p.pid == pid))?;
+        let process = job.processes.iter().find(|p| p.pid == pid)?;
+
+        if let Some(status) = process.status { return Some(status); }
+
+        let status = wait_pid(pid, libc::WUNTRACED)?;
+        job.record(pid, status);
+
+        Some(status)
+    }
+
+    /// Runs the job in the foreground until it finishes or stops, resuming it first if `cont` is set.
+    /// Returns the job's state once we got the terminal back.
+    pub fn foreground (&mut self, id: usize, cont: bool) -> JobState {
+        let Some(job) = self.jobs.iter_mut().find(|job| job.id == id) else { return JobState::Done };
+
+        if let Some(terminal) = &self.terminal {
+            unsafe {
+                libc::tcsetpgrp(terminal.fd, job.pgid);
+
+                if let Some(tmodes) = job.tmodes.take() {
+                    libc::tcsetattr(terminal.fd, libc::TCSADRAIN, &tmodes);
+                }
+            }
+        }
+
+        if cont { job.resume(); }
+
+        self.wait(id);
+
+        let job = self.jobs.iter_mut().find(|job| job.id == id).unwrap();
+        let state = job.state();
+
+        if let Some(terminal) = &self.terminal {
+            unsafe {
+                libc::tcsetpgrp(terminal.fd, terminal.shell_pgid);
+
+                if state == JobState::Stopped {
+                    let mut tmodes = std::mem::zeroed();
+                    libc::tcgetattr(terminal.fd, &mut tmodes);
+                    job.tmodes = Some(tmodes);
+                }
+
+                libc::tcsetattr(terminal.fd, libc::TCSADRAIN, &terminal.tmodes);
+            }
+        }
+
+        state
+    }
+
+    /// Continues a stopped job in the background.
+    pub fn background (&mut self, id: usize) {
+        if let Some(job) = self.jobs.iter_mut().find(|job| job.id == id) {
+            job.resume();
+        }
+    }
+
+    /// Removes and returns the jobs that finished since the last time we asked.
+    pub fn take_finished (&mut self) -> Vec<Job> {
+        let (done, running) = std::mem::take(&mut self.jobs)
+            .into_iter()
+            .partition(|job| job.state() == JobState::Done);
+
+        self.jobs = running;
+        done
+    }
+}
+
+/// Collects a child that isn't part of any job, like a process substitution. True once it exited.
+pub fn reap (pid: i32) -> bool {
+    wait_pid(pid, libc::WNOHANG).is_some()
+}
+
+/// Waits for a child that isn't part of any job to exit, returning its raw status.
+pub fn wait_child (pid: i32) -> Option<i32> {
+    wait_pid(pid, 0)
+}
+
+/// waitpid that also looks at what the SIGCHLD handler already reaped.
+/// Returns None if nothing changed (with WNOHANG).
+fn wait_pid (pid: i32, flags: i32) -> Option<i32> {
+    // while blocking, the handler must not get to the status before we do
+    let _blocked = (flags & libc::WNOHANG == 0).then(ChildSignalsBlocked::new);
+
+    if let Some(status) = signals::take_reaped(pid) {
+        return Some(status);
+    }
+
+    loop {
+        let mut status = 0;
+        let res = unsafe { libc::waitpid(pid, &mut status, flags) };
+
+        if res == pid { return Some(status); }
+        if res == 0 { return None; }
+
+        match std::io::Error::last_os_error().raw_os_error() {
+            Some(libc::EINTR) => continue,
+            // reaped by the handler in the meantime, or lost entirely
+            _ => return Some(signals::take_reaped(pid).unwrap_or(0)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod job_tests {
+    use super::*;
+
+    #[test]
+    fn job_specs () {
+        let mut table = JobTable::default();
+
+        table.add(vec![100, 101], "sleep 10 | cat".to_string());
+        table.add(vec![200], "vim notes".to_string());
+        table.add(vec![300], "make -j8".to_string());
+
+        assert_eq!(table.find("%1"), Ok(1));
+        assert_eq!(table.find("%%"), Ok(3));
+        assert_eq!(table.find("%+"), Ok(3));
+        assert_eq!(table.find("%-"), Ok(2));
+        assert_eq!(table.find("%vim"), Ok(2));
+        assert_eq!(table.find("%?cat"), Ok(1));
+        assert_eq!(table.find("101"), Ok(1));
+        assert!(table.find("%7").is_err());
+
+        assert_eq!(table.marker(3), '+');
+        assert_eq!(table.marker(2), '-');
+        assert_eq!(table.marker(1), ' ');
+
+        table.remove(3);
+        assert_eq!(table.add(vec![400], "top".to_string()), 3);
+    }
+}
@@ -0,0 +1,183 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::utils::get_environment;
+
+// once the ranks add up to more than this, they all decay and the ones that get too small go
+const MAX_TOTAL_RANK: f64 = 9000.0;
+
+/// A directory `cd` went to, how often and when it was last.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub path: String,
+    pub rank: f64,
+    pub time: i64
+}
+
+/// The directories `j` jumps to, kept in a file under the config directory.
+#[derive(Debug, Default)]
+pub struct Database {
+    pub entries: Vec<Entry>
+}
+
+/// `$XDG_CONFIG_HOME/nyash/dirs`, or `~/.config/nyash/dirs` without it.
+pub fn database_path () -> Option<String> {
+    let config = match get_environment("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => dir.to_string(),
+        None => format!("{}/.config", get_environment("HOME")?),
+    };
+
+    Some(format!("{config}/nyash/dirs"))
+}
+
+pub fn now () -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs() as i64)
+}
+
+/// The rank weighted by how recent the last visit was.
+fn frecency (entry: &Entry, now: i64) -> f64 {
+    let age = now - entry.time;
+
+    let weight = match age {
+        age if age < 3600 => 4.0,
+        age if age < 86400 => 2.0,
+        age if age < 604800 => 0.5,
+        _ => 0.25,
+    };
+
+    entry.rank * weight
+}
+
+/// Whether `terms` all appear in `path`, one after the other.
+fn matches_terms (path: &str, terms: &[String]) -> bool {
+    let mut rest = path;
+
+    for term in terms {
+        let Some(pos) = rest.find(term.as_str()) else { return false };
+        rest = &rest[pos + term.len()..];
+    }
+
+    true
+}
+
+impl Database {
+    /// Reads the database, empty if there's none yet. Each line is `rank<TAB>time<TAB>path`.
+    pub fn load (file: &str) -> Self {
+        let text = std::fs::read_to_string(file).unwrap_or_default();
+
+        let entries = text.lines().filter_map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            let rank = fields.next()?.parse().ok()?;
+            let time = fields.next()?.parse().ok()?;
+            let path = fields.next()?.to_string();
+
+            Some(Entry { path, rank, time })
+        }).collect();
+
+        Self { entries }
+    }
+
+    /// Writes the database through a temporary file, so two shells never leave half of one behind.
+    pub fn save (&self, file: &str) -> std::io::Result<()> {
+        if let Some((dir, _)) = file.rsplit_once('/') {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let text: String = self.entries.iter()
+            .map(|entry| format!("{}\t{}\t{}\n", entry.rank, entry.time, entry.path))
+            .collect();
+
+        let temporary = format!("{file}.{}", std::process::id());
+        std::fs::write(&temporary, text)?;
+        std::fs::rename(&temporary, file)
+    }
+
+    /// Counts a visit to `dir`.
+    pub fn visit (&mut self, dir: &str, now: i64) {
+        match self.entries.iter_mut().find(|entry| entry.path == dir) {
+            Some(entry) => {
+                entry.rank += 1.0;
+                entry.time = now;
+            }
+            None => self.entries.push(Entry { path: dir.to_string(), rank: 1.0, time: now }),
+        }
+
+        if self.entries.iter().map(|entry| entry.rank).sum::<f64>() > MAX_TOTAL_RANK {
+            for entry in &mut self.entries {
+                entry.rank *= 0.99;
+            }
+            self.entries.retain(|entry| entry.rank >= 1.0);
+        }
+    }
+
+    /// The entries whose path contains `terms` in order, best first, with their score.
+    /// Case only matters if something matches with it.
+    pub fn matches (&self, terms: &[&str], now: i64) -> Vec<(f64, &str)> {
+        let exact: Vec<String> = terms.iter().map(|term| term.to_string()).collect();
+        let lower: Vec<String> = terms.iter().map(|term| term.to_lowercase()).collect();
+
+        let mut matches: Vec<(f64, &str)> = self.entries.iter()
+            .filter(|entry| matches_terms(&entry.path, &exact))
+            .map(|entry| (frecency(entry, now), entry.path.as_str()))
+            .collect();
+
+        if matches.is_empty() {
+            matches = self.entries.iter()
+                .filter(|entry| matches_terms(&entry.path.to_lowercase(), &lower))
+                .map(|entry| (frecency(entry, now), entry.path.as_str()))
+                .collect();
+        }
+
+        matches.sort_by(|a, b| b.0.total_cmp(&a.0));
+        matches
+    }
+}
+
+#[cfg(test)]
+mod jump_tests {
+    use super::*;
+    use crate::utils::TestDir;
+
+    #[test]
+    fn ranking () {
+        let mut db = Database::default();
+        let now = 1_000_000;
+
+        db.visit("/src/app/frontend", now - 200_000);
+        db.visit("/src/app/frontend", now - 200_000);
+        db.visit("/src/app/backend", now - 10);
+        db.visit("/src/lib/Front", now - 10);
+
+        let best = |terms: &[&str]| db.matches(terms, now).first().map(|(_, path)| path.to_string());
+
+        // two old visits lose against a recent one
+        assert_eq!(best(&["app"]), Some("/src/app/backend".to_string()));
+        assert_eq!(best(&["front"]), Some("/src/app/frontend".to_string()));
+        assert_eq!(best(&["lib", "fro"]), Some("/src/lib/Front".to_string()));
+        assert_eq!(best(&["end", "app"]), None);
+        assert_eq!(db.matches(&[], now).len(), 3);
+    }
+
+    #[test]
+    fn aging () {
+        let mut db = Database::default();
+        db.entries.push(Entry { path: "/busy".to_string(), rank: MAX_TOTAL_RANK, time: 0 });
+        db.entries.push(Entry { path: "/rare".to_string(), rank: 1.0, time: 0 });
+
+        db.visit("/busy", 1);
+
+        assert_eq!(db.entries.len(), 1);
+        assert!(db.entries[0].rank < MAX_TOTAL_RANK);
+    }
+
+    #[test]
+    fn save_and_load () {
+        let dir = TestDir::new("jump");
+        let file = dir.join("nyash/dirs").to_string_lossy().to_string();
+
+        let mut db = Database::default();
+        db.visit("/with\ttab and space", 42);
+        db.save(&file).unwrap();
+
+        assert_eq!(Database::load(&file).entries, db.entries);
+    }
+}
@@ -0,0 +1,474 @@
+use std::ops::Range;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    /// A word exactly as typed, quotes and escapes are only removed once it gets expanded.
+    Word(String),
+    /// The digits right in front of a redirection operator, like the `2` in `2>&1`.
+    IoNumber(i32),
+    Op(&'static str),
+    /// The lines of a here-document, right after its delimiter word.
+    HereDocBody(String)
+}
+
+// longest first, so `>>` wins over `>`
+const OPERATORS: &[&str] = &[
+    "&>>", "<<<", "<<-", "&&", "||", "&>", ">>", ">|", ">&", "<>", "<&", "<<",
+    "|", "&", ";", "(", ")", ">", "<",
+];
+
+#[derive(PartialEq)]
+enum State {
+    Normal,
+    InQuotes,
+    InDoubleQuotes
+}
+
+// a `<<` waiting for the newline its body starts after
+struct PendingHereDoc {
+    // where its delimiter word is in the tokens
+    index: usize,
+    strip_tabs: bool
+}
+
+/// The delimiter as it's matched against the body's lines: without quotes.
+pub fn unquote (word: &str) -> String {
+    let mut result = String::new();
+    let mut chars = word.chars();
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => result.extend(chars.next()),
+            '\'' | '"' => {}
+            ch => result.push(ch),
+        }
+    }
+
+    result
+}
+
+/// Reads the here-document body starting at `idx`, up to the line holding only `delimiter`.
+/// Returns the body, where the input continues and whether the delimiter was found.
+fn read_here_doc (chars: &[char], mut idx: usize, delimiter: &str, strip_tabs: bool) -> (String, usize, bool) {
+    let mut body = String::new();
+
+    while idx < chars.len() {
+        let end = chars[idx..].iter().position(|&c| c == '\n').map_or(chars.len(), |pos| idx + pos);
+        let mut line: String = chars[idx..end].iter().collect();
+        idx = end + 1;
+
+        if strip_tabs {
+            line = line.trim_start_matches('\t').to_string();
+        }
+
+        if line == delimiter {
+            return (body, idx, true);
+        }
+
+        body.push_str(&line);
+        body.push('\n');
+    }
+
+    (body, chars.len(), false)
+}
+
+/// Finds the `)` closing the `(` at `open`, skipping nested parentheses and anything quoted.
+pub fn closing_paren (chars: &[char], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut idx = open;
+
+    while idx < chars.len() {
+        match chars[idx] {
+            '\\' => idx += 1,
+            '\'' => idx += chars[idx + 1..].iter().position(|&c| c == '\'')? + 1,
+            '"' => {
+                idx += 1;
+                while idx < chars.len() && chars[idx] != '"' {
+                    if chars[idx] == '\\' { idx += 1; }
+                    idx += 1;
+                }
+            }
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 { return Some(idx); }
+            }
+            _ => {}
+        }
+
+        idx += 1;
+    }
+
+    None
+}
+
+/// Finds where the regex starting at `start` ends: at a blank or a `)` outside any parentheses
+/// or quotes. None if a quote isn't closed.
+fn regex_end (chars: &[char], start: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut idx = start;
+
+    while idx < chars.len() {
+        match chars[idx] {
+            '\\' => idx += 1,
+            '\'' => idx += chars[idx + 1..].iter().position(|&c| c == '\'')? + 1,
+            '"' => {
+                idx += 1;
+                while chars.get(idx) != Some(&'"') {
+                    if chars.get(idx)? == &'\\' { idx += 1; }
+                    idx += 1;
+                }
+            }
+            '(' => depth += 1,
+            ')' if depth == 0 => break,
+            ')' => depth -= 1,
+            ' ' | '\t' | '\n' if depth == 0 => break,
+            _ => {}
+        }
+
+        idx += 1;
+    }
+
+    Some(idx.min(chars.len()))
+}
+
+/// Whether the next word is the regex of an `=~` inside `[[ ]]`.
+fn regex_follows (tokens: &[Token]) -> bool {
+    if tokens.last() != Some(&Token::Word("=~".to_string())) { return false; }
+
+    tokens.iter().rev()
+        .find_map(|token| match token {
+            Token::Word(word) if word == "[[" => Some(true),
+            Token::Word(word) if word == "]]" => Some(false),
+            _ => None,
+        })
+        .unwrap_or(false)
+}
+
+pub struct Lexed {
+    pub tokens: Vec<Token>,
+    /// Where each token is in the input, in characters
+    pub spans: Vec<Range<usize>>,
+    /// Where the comments are, the tokens leave them out
+    pub comments: Vec<Range<usize>>,
+    /// The delimiter of the first here-document whose body isn't complete yet
+    pub unterminated_here_doc: Option<String>,
+    /// The input stopped inside quotes, after a `\` or before a here-document ended
+    pub incomplete: bool
+}
+
+/// The delimiter of the first here-document whose body isn't complete yet, if any.
+/// While there is one, more lines are needed before the command can run.
+pub fn unterminated_here_doc (input: &str) -> Option<String> {
+    lex(input).unterminated_here_doc
+}
+
+/// Splits a command line into words and operators. Words keep their quoting so
+/// the expansion step still knows what was quoted.
+pub fn lex (input: &str) -> Lexed {
+    let mut tokens = Vec::new();
+    let mut spans = Vec::new();
+    let mut comments = Vec::new();
+    let mut word = String::new();
+    // where the word being read started
+    let mut start = 0;
+    // a quoted word is never an io number, and "" is still a word
+    let mut quoted = false;
+    let mut state = State::Normal;
+    let mut here_docs: Vec<PendingHereDoc> = Vec::new();
+    let mut unterminated = None;
+    let mut incomplete = false;
+
+    let chars: Vec<char> = input.chars().collect();
+    let mut idx = 0;
+
+    let flush = |tokens: &mut Vec<Token>, spans: &mut Vec<Range<usize>>, word: &mut String, quoted: &mut bool, span: Range<usize>| {
+        if !word.is_empty() || *quoted {
+            tokens.push(Token::Word(std::mem::take(word)));
+            spans.push(span);
+        }
+        *quoted = false;
+    };
+
+    while idx < chars.len() {
+        let ch = chars[idx];
+
+        match state {
+            State::InQuotes => {
+                word.push(ch);
+                if ch == '\'' { state = State::Normal; }
+            }
+            State::InDoubleQuotes => {
+                word.push(ch);
+
+                if ch == '\\' && chars.get(idx + 1) == Some(&'\n') {
+                    // a line continuation disappears, even in double quotes
+                    word.pop();
+                    idx += 1;
+                } else if ch == '\\' && idx + 1 < chars.len() {
+                    idx += 1;
+                    word.push(chars[idx]);
+                } else if ch == '\\' {
+                    incomplete = true;
+                } else if ch == '"' {
+                    state = State::Normal;
+                }
+            }
+            State::Normal => {
+                if word.is_empty() && !quoted { start = idx; }
+
+                // `|` and parentheses belong to the regex after `=~`, it's a single word
+                if word.is_empty() && !quoted && !matches!(ch, ' ' | '\t' | '\n') && regex_follows(&tokens) {
+                    if let Some(end) = regex_end(&chars, idx).filter(|&end| end > idx) {
+                        word.extend(&chars[idx..end]);
+                        idx = end;
+                        flush(&mut tokens, &mut spans, &mut word, &mut quoted, start..idx);
+                        continue;
+                    }
+                }
+
+                match ch {
+                    '\'' => {
+                        state = State::InQuotes;
+                        quoted = true;
+                        word.push(ch);
+                    }
+                    '"' => {
+                        state = State::InDoubleQuotes;
+                        quoted = true;
+                        word.push(ch);
+                    }
+                    '\\' if chars.get(idx + 1) == Some(&'\n') => idx += 1,
+                    '\\' => {
+                        quoted = true;
+                        word.push(ch);
+
+                        if idx + 1 < chars.len() {
+                            idx += 1;
+                            word.push(chars[idx]);
+                        } else {
+                            incomplete = true;
+                        }
+                    }
+                    ' ' | '\t' => flush(&mut tokens, &mut spans, &mut word, &mut quoted, start..idx),
+                    '\n' => {
+                        flush(&mut tokens, &mut spans, &mut word, &mut quoted, start..idx);
+                        tokens.push(Token::Op("\n"));
+                        spans.push(idx..idx + 1);
+                        idx += 1;
+
+                        // the bodies of this line's here-documents come right after it, in order
+                        for (inserted, here_doc) in here_docs.drain(..).enumerate() {
+                            let index = here_doc.index + inserted;
+                            let Some(Token::Word(delimiter)) = tokens.get(index) else { continue };
+                            let delimiter = unquote(delimiter);
+
+                            let (body, next, found) = read_here_doc(&chars, idx, &delimiter, here_doc.strip_tabs);
+                            if !found && unterminated.is_none() {
+                                unterminated = Some(delimiter);
+                            }
+
+                            tokens.insert(index + 1, Token::HereDocBody(body));
+                            spans.insert(index + 1, idx..next.min(chars.len()));
+                            idx = next;
+                        }
+                        continue;
+                    }
+                    // `<(cmd)` and `>(cmd)` are part of the word, the command is run when it's expanded
+                    '<' | '>' if chars.get(idx + 1) == Some(&'(') => {
+                        let end = closing_paren(&chars, idx + 1).unwrap_or_else(|| {
+                            incomplete = true;
+                            chars.len() - 1
+                        });
+                        word.extend(&chars[idx..=end]);
+                        idx = end;
+                    }
+                    '#' if word.is_empty() && !quoted => {
+                        // a comment runs to the end of the line, not of the input
+                        let from = idx;
+                        while idx + 1 < chars.len() && chars[idx + 1] != '\n' { idx += 1; }
+                        comments.push(from..idx + 1);
+                    }
+                    _ => {
+                        let rest: String = chars[idx..].iter().take(3).collect();
+
+                        let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) else {
+                            word.push(ch);
+                            idx += 1;
+                            continue;
+                        };
+
+                        let io_number = (op.starts_with('<') || op.starts_with('>'))
+                            && !quoted
+                            && !word.is_empty()
+                            && word.chars().all(|c| c.is_ascii_digit());
+
+                        match word.parse::<i32>() {
+                            Ok(fd) if io_number => {
+                                tokens.push(Token::IoNumber(fd));
+                                spans.push(start..idx);
+                                word.clear();
+                            }
+                            _ => flush(&mut tokens, &mut spans, &mut word, &mut quoted, start..idx),
+                        }
+
+                        tokens.push(Token::Op(op));
+                        spans.push(idx..idx + op.len());
+                        idx += op.len();
+
+                        if *op == "<<" || *op == "<<-" {
+                            here_docs.push(PendingHereDoc { index: tokens.len(), strip_tabs: *op == "<<-" });
+                        }
+                        continue;
+                    }
+                }
+            }
+        }
+
+        idx += 1;
+    }
+
+    flush(&mut tokens, &mut spans, &mut word, &mut quoted, start..idx);
+
+    // the input ended before the line the bodies would start after
+    for (inserted, here_doc) in here_docs.into_iter().enumerate() {
+        let index = here_doc.index + inserted;
+        let Some(Token::Word(delimiter)) = tokens.get(index) else { continue };
+
+        unterminated.get_or_insert_with(|| unquote(delimiter));
+        tokens.insert(index + 1, Token::HereDocBody(String::new()));
+        spans.insert(index + 1, chars.len()..chars.len());
+    }
+
+    Lexed {
+        tokens,
+        spans,
+        comments,
+        incomplete: incomplete || unterminated.is_some() || state != State::Normal,
+        unterminated_here_doc: unterminated,
+    }
+}
+
+#[cfg(test)]
+mod lexer_tests {
+    use super::*;
+    use super::Token::*;
+
+    fn tokenize (input: &str) -> Vec<Token> {
+        lex(input).tokens
+    }
+
+    fn word (w: &str) -> Token {
+        Word(w.to_string())
+    }
+
+    #[test]
+    fn words_keep_their_quotes () {
+        assert_eq!(
+            tokenize(r#"echo 'a b' "c \" d" e\ f"#),
+            vec![word("echo"), word("'a b'"), word(r#""c \" d""#), word(r"e\ f")]
+        );
+    }
+
+    #[test]
+    fn operators_split_words () {
+        assert_eq!(
+            tokenize("ls>out|wc -l&"),
+            vec![word("ls"), Op(">"), word("out"), Op("|"), word("wc"), word("-l"), Op("&")]
+        );
+        assert_eq!(tokenize("echo '|' \">\""), vec![word("echo"), word("'|'"), word("\">\"")]);
+        assert_eq!(
+            tokenize("(a&&b)||c;d\ne"),
+            vec![Op("("), word("a"), Op("&&"), word("b"), Op(")"), Op("||"), word("c"), Op(";"), word("d"), Op("\n"), word("e")]
+        );
+        // the longest operator is taken first, what's left starts the next one
+        assert_eq!(
+            tokenize("a|||b&&&c"),
+            vec![word("a"), Op("||"), Op("|"), word("b"), Op("&&"), Op("&"), word("c")]
+        );
+    }
+
+    #[test]
+    fn io_numbers () {
+        assert_eq!(
+            tokenize("cmd 2>&1 3<>file 10>&- x2>y \"2\">z"),
+            vec![
+                word("cmd"),
+                IoNumber(2), Op(">&"), word("1"),
+                IoNumber(3), Op("<>"), word("file"),
+                IoNumber(10), Op(">&"), word("-"),
+                word("x2"), Op(">"), word("y"),
+                word("\"2\""), Op(">"), word("z"),
+            ]
+        );
+    }
+
+    #[test]
+    fn combined_and_clobber_operators () {
+        assert_eq!(
+            tokenize("make &>> log >| out"),
+            vec![word("make"), Op("&>>"), word("log"), Op(">|"), word("out")]
+        );
+    }
+
+    #[test]
+    fn here_documents () {
+        assert_eq!(
+            tokenize("cat <<EOF <<-'END' | wc\nhello $x\nEOF\n\tindented\n\tEND\n"),
+            vec![
+                word("cat"),
+                Op("<<"), word("EOF"), HereDocBody("hello $x\n".to_string()),
+                Op("<<-"), word("'END'"), HereDocBody("indented\n".to_string()),
+                Op("|"), word("wc"), Op("\n"),
+            ]
+        );
+        assert_eq!(tokenize("cat <<< 'a b'"), vec![word("cat"), Op("<<<"), word("'a b'")]);
+
+        assert_eq!(unterminated_here_doc("cat <<EOF"), Some("EOF".to_string()));
+        assert_eq!(unterminated_here_doc("cat <<\"E\"F\nline"), Some("EF".to_string()));
+        assert_eq!(unterminated_here_doc("cat <<EOF\nline\nEOF"), None);
+    }
+
+    #[test]
+    fn process_substitutions () {
+        assert_eq!(
+            tokenize(r#"diff <(sort a | uniq) >(tee ")" (x))>out"#),
+            vec![word("diff"), word("<(sort a | uniq)"), word(r#">(tee ")" (x))"#), Op(">"), word("out")]
+        );
+    }
+
+    #[test]
+    fn incomplete_input () {
+        assert!(lex("echo 'abc").incomplete);
+        assert!(lex("echo \"abc\ndef").incomplete);
+        assert!(lex("echo abc\\").incomplete);
+        assert!(lex("diff <(sort").incomplete);
+        assert!(lex("cat <<EOF\nbody").incomplete);
+        assert!(!lex("echo 'a\nb'").incomplete);
+
+        assert_eq!(tokenize("echo ab\\\ncd \"e\\\nf\""), vec![word("echo"), word("abcd"), word("\"ef\"")]);
+    }
+
+    #[test]
+    fn conditional_regexes () {
+        assert_eq!(
+            tokenize("[[ $x =~ ^(a|b c)+\\ $ && x =~ y ]] | z"),
+            vec![word("[["), word("$x"), word("=~"), word("^(a|b c)+\\ $"), Op("&&"), word("x"), word("=~"), word("y"), word("]]"), Op("|"), word("z")]
+        );
+        assert_eq!(tokenize("echo =~ a|b"), vec![word("echo"), word("=~"), word("a"), Op("|"), word("b")]);
+    }
+
+    #[test]
+    fn comments () {
+        assert_eq!(tokenize("echo hi # there"), vec![word("echo"), word("hi")]);
+        assert_eq!(tokenize("echo a#b"), vec![word("echo"), word("a#b")]);
+    }
+
+    #[test]
+    fn spans () {
+        // a here-document's body comes right after its delimiter, ahead of the newline
+        let lexed = lex("cat 'a b'  2>>log <<E # the end\nbody\nE\n");
+        assert_eq!(lexed.spans, vec![0..3, 4..9, 11..12, 12..14, 14..17, 18..20, 20..21, 32..39, 31..32]);
+        assert_eq!(lexed.comments, vec![22..31]);
+    }
+}
@@ -4,44 +4,81 @@ mod error;
 mod args_parser;
 mod readline;
 mod trie;
-
-use std::ffi::CString;
+mod jobs;
+mod signals;
+mod lexer;
+mod parser;
+mod redirect;
+mod conditional;
+mod printf;
+mod read;
+mod dirs;
+mod jump;
+mod prompt;
+mod inflate;
+mod git;
+mod highlight;
+mod vi;
 
 use interpreter::Interpreter;
-use args_parser::parse_args;
-use readline::Reader;
+use readline::{Prompts, Reader};
 
 fn main() {
     let mut reader = Reader::new(); 
-    let interpreter = Interpreter::new("./history");
+    let mut interpreter = Interpreter::new("./history");
+
+    signals::install_child_handler();
+
+    // `cd` works from $PWD, it has to name the directory we start in
+    utils::set_environment("PWD", &dirs::current_dir());
+
+    if unsafe { libc::isatty(libc::STDIN_FILENO) } == 1 {
+        interpreter.interactive = true;
+        interpreter.jobs.enable_job_control(libc::STDIN_FILENO);
+        signals::catch_resize();
+    }
 
     reader.update_trie(&interpreter.get_builtins());
-    reader.update_trie(&utils::get_system_binaries());
+
+    // aliases and the like that should be there in every session
+    if interpreter.interactive {
+        if let Some(home) = utils::get_environment("HOME") {
+            let rc = format!("{home}/.nyashrc");
+            if utils::stat(&rc).is_some() {
+                interpreter.run_script(&rc);
+            }
+        }
+    }
     
     loop {
-        // Wait for user input
-        let mut input = reader.read_line("$ ");
-
-        let command = parse_args(&input);
-
-        if let Some(cmd) = command.first() {
-            let found = interpreter.interpret_command(cmd, &command.iter().map(|e| e.as_str()).collect());
-            
-            if !found { println!("{}: command not found", cmd); }
-            else {
-                unsafe {
-                    let history = interpreter.history;
-                        input.push('\n');
-                    let cmd = CString::new(input).unwrap();
-                    let buf = cmd.as_bytes();
-
-                    let written = libc::write(history, buf.as_ptr().cast(), buf.len());
-                    if written == -1 {
-                        let err = *libc::__errno_location();
-                        eprintln!("Failed to write: {}", std::io::Error::from_raw_os_error(err));
-                    }
-                }
-            }
+        // pick up PATH changes and newly installed binaries
+        reader.refresh_binaries();
+        reader.update_aliases(interpreter.aliases.keys());
+        reader.set_colours(interpreter.lookup_var("NYASH_HIGHLIGHT").as_deref());
+
+        // the history is only gone through with the vi keys
+        let vi = interpreter.options.contains("vi");
+        reader.set_vi_mode(vi);
+        if vi { reader.set_history(interpreter.read_history()); }
+        interpreter.notify_jobs();
+        interpreter.run_pending_traps();
+
+        interpreter.run_prompt_command();
+        let prompts = Prompts {
+            primary: interpreter.prompt("PS1", "$ "),
+            continuation: interpreter.prompt("PS2", "> "),
+            right: interpreter.prompt("RPROMPT", ""),
+            transient: interpreter.lookup_var("TRANSIENT_PROMPT").is_some().then(|| interpreter.prompt("TRANSIENT_PROMPT", "")),
+        };
+
+        // Wait for user input, a command can go on over several lines
+        let Some(input) = reader.read_command(&prompts) else {
+            if interpreter.interactive { println!("exit"); }
+            interpreter.exit(interpreter.status);
+        };
+
+        if interpreter.execute(&input) && !input.trim().is_empty() {
+            interpreter.add_history(&input);
         }
     }
 }
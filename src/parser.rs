@@ -0,0 +1,718 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::lexer::{self, Token};
+use crate::redirect::{Redirect, RedirectOp};
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SimpleCommand {
+    /// `NAME=value` words in front of the command
+    pub assignments: Vec<String>,
+    pub words: Vec<String>,
+    pub redirects: Vec<Redirect>
+}
+
+/// Whether `word` looks like `NAME=value`, with an unquoted valid name.
+pub fn is_assignment (word: &str) -> bool {
+    let Some((name, _)) = word.split_once('=') else { return false };
+    is_name(name)
+}
+
+/// A valid variable (or function) name: letters, digits and `_`, not starting with a digit.
+pub fn is_name (name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|ch| ch.is_ascii_alphabetic() || ch == '_')
+        && chars.all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Simple(SimpleCommand),
+    /// `( list )`, runs in a forked copy of the shell
+    Subshell(List, Vec<Redirect>),
+    /// `{ list; }`, runs in the shell itself
+    Group(List, Vec<Redirect>),
+    /// `[[ expression ]]`, its words unexpanded and with the operators in between
+    Conditional(Vec<String>, Vec<Redirect>)
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Pipeline {
+    pub commands: Vec<Command>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Connector {
+    /// `&&`
+    And,
+    /// `||`
+    Or
+}
+
+/// Pipelines joined by `&&` and `||`, the unit `&` puts in the background.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AndOr {
+    pub first: Pipeline,
+    pub rest: Vec<(Connector, Pipeline)>,
+    pub background: bool
+}
+
+/// Commands separated by `;`, `&` or newlines.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct List {
+    pub items: Vec<AndOr>
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    /// The input stops in the middle of a command, it goes on on the next line
+    Incomplete,
+    Syntax(String)
+}
+
+impl fmt::Display for ParseError {
+    fn fmt (&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Incomplete => write!(f, "syntax error: unexpected end of file"),
+            ParseError::Syntax(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+fn unexpected (token: Option<&Token>) -> ParseError {
+    let token = match token {
+        Some(Token::Word(word)) => word.clone(),
+        Some(Token::IoNumber(fd)) => fd.to_string(),
+        Some(Token::Op("\n")) => "newline".to_string(),
+        Some(Token::Op(op)) => op.to_string(),
+        Some(Token::HereDocBody(_)) | None => "newline".to_string(),
+    };
+
+    ParseError::Syntax(format!("syntax error near unexpected token `{token}'"))
+}
+
+/// Parses a command line, an empty list if there's nothing to run.
+/// The first word of each simple command is looked up in `aliases`.
+pub fn parse (input: &str, aliases: &HashMap<String, String>) -> Result<List, ParseError> {
+    let lexed = lexer::lex(input);
+    if lexed.incomplete {
+        return Err(ParseError::Incomplete);
+    }
+
+    let mut parser = Parser { tokens: lexed.tokens, pos: 0, aliases, expanding: Vec::new(), expand_next: None };
+    let list = parser.list(None)?;
+
+    match parser.peek() {
+        None => Ok(list),
+        token => Err(unexpected(token)),
+    }
+}
+
+/// Appends lines from `next_line` to `input` until it's a whole command: quotes closed,
+/// nothing left after a `|` or `&&`, every group and here-document ended. `next_line` is
+/// given what there is of the command so far. An `if`, loop or `case` is read up to its
+/// `fi`, `done` or `esac` too.
+/// Returns false if the lines ran out first.
+/// The keyword that would close the last `if`, `while`, `until`, `for` or `case` left open in
+/// `input`, None when they're all closed. Keywords only count where a command can start.
+fn unclosed_keyword (input: &str) -> Option<&'static str> {
+    let mut open: Vec<&'static str> = Vec::new();
+    // whether a command can start at the next word, and whether it's a redirection's file instead
+    let mut command = true;
+    let mut target = false;
+
+    for token in lexer::lex(input).tokens {
+        match token {
+            Token::Op(op) if op.contains(['<', '>']) => target = true,
+            // a `)` ends a subshell, or a pattern in a `case` and then a command follows
+            Token::Op(")") => command = open.last() == Some(&"esac"),
+            Token::Op(_) => command = true,
+            Token::Word(_) if target => target = false,
+            Token::Word(word) if command => match word.as_str() {
+                "if" => open.push("fi"),
+                "while" | "until" => open.push("done"),
+                "for" | "case" => {
+                    open.push(if word == "for" { "done" } else { "esac" });
+                    command = false;
+                }
+                "then" | "do" | "else" | "elif" | "{" | "!" => {}
+                closing if open.last() == Some(&closing) => {
+                    open.pop();
+                    command = false;
+                }
+                word => command = is_assignment(word),
+            },
+            _ => {}
+        }
+    }
+
+    open.pop()
+}
+
+pub fn complete_input (input: &mut String, next_line: &mut dyn FnMut(&str) -> Option<String>) -> bool {
+    loop {
+        // a here-document only ends on its delimiter, no need to parse again before that
+        if let Some(delimiter) = lexer::unterminated_here_doc(input) {
+            loop {
+                let Some(line) = next_line(input) else {
+                    eprintln!("nyash: warning: here-document delimited by end-of-file (wanted `{delimiter}')");
+                    input.push('\n');
+                    input.push_str(&delimiter);
+                    break;
+                };
+
+                input.push('\n');
+                input.push_str(&line);
+
+                if line.trim_start_matches('\t') == delimiter { break; }
+            }
+            continue;
+        }
+
+        if parse(input, &HashMap::new()) != Err(ParseError::Incomplete) && unclosed_keyword(input).is_none() {
+            return true;
+        }
+
+        let Some(line) = next_line(input) else { return false };
+
+        // `\` at the very end joins the lines, that's up to the lexer
+        input.push('\n');
+        input.push_str(&line);
+    }
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    aliases: &'a HashMap<String, String>,
+    // aliases being expanded, with where their tokens end: an alias isn't expanded inside itself
+    expanding: Vec<(String, usize)>,
+    // after an alias ending with a blank, the word that follows is checked too
+    expand_next: Option<usize>
+}
+
+impl Parser<'_> {
+    fn peek (&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next (&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn skip_newlines (&mut self) {
+        while self.peek() == Some(&Token::Op("\n")) {
+            self.pos += 1;
+        }
+    }
+
+    fn at_closing (&self, closing: Option<&str>) -> bool {
+        match (self.peek(), closing) {
+            (Some(Token::Op(")")), Some(")")) => true,
+            (Some(Token::Word(word)), Some("}")) => word == "}",
+            _ => false,
+        }
+    }
+
+    /// Reads and-or lists up to `closing` (`)` or `}`), or to the end of the input without one.
+    fn list (&mut self, closing: Option<&str>) -> Result<List, ParseError> {
+        let mut list = List::default();
+
+        loop {
+            self.skip_newlines();
+
+            if self.peek().is_none() {
+                if closing.is_some() { return Err(ParseError::Incomplete); }
+                break;
+            }
+            if self.at_closing(closing) { break; }
+
+            let mut and_or = self.and_or()?;
+
+            match self.peek() {
+                Some(Token::Op(";")) | Some(Token::Op("\n")) => { self.pos += 1; }
+                Some(Token::Op("&")) => {
+                    and_or.background = true;
+                    self.pos += 1;
+                }
+                None => {}
+                _ if self.at_closing(closing) => {}
+                token => return Err(unexpected(token)),
+            }
+
+            list.items.push(and_or);
+        }
+
+        Ok(list)
+    }
+
+    fn and_or (&mut self) -> Result<AndOr, ParseError> {
+        let first = self.pipeline()?;
+        let mut rest = Vec::new();
+
+        loop {
+            let connector = match self.peek() {
+                Some(Token::Op("&&")) => Connector::And,
+                Some(Token::Op("||")) => Connector::Or,
+                _ => break,
+            };
+
+            self.pos += 1;
+            self.skip_newlines();
+            rest.push((connector, self.pipeline()?));
+        }
+
+        Ok(AndOr { first, rest, background: false })
+    }
+
+    fn pipeline (&mut self) -> Result<Pipeline, ParseError> {
+        let mut pipeline = Pipeline { commands: vec![self.command()?] };
+
+        while self.peek() == Some(&Token::Op("|")) {
+            self.pos += 1;
+            self.skip_newlines();
+            pipeline.commands.push(self.command()?);
+        }
+
+        Ok(pipeline)
+    }
+
+    /// Replaces the word at the current position with the tokens of the alias it names, if it's one.
+    fn expand_alias (&mut self) {
+        // the alias can start with another alias
+        loop {
+            let Some(Token::Word(word)) = self.peek() else { return };
+            if word.contains(['\'', '"', '\\']) { return; }
+
+            let pos = self.pos;
+            if self.expanding.iter().any(|(name, end)| name == word && *end > pos) { return; }
+
+            let Some(value) = self.aliases.get(word) else { return };
+            let name = word.clone();
+            let tokens = lexer::lex(value).tokens;
+            let count = tokens.len();
+
+            self.tokens.splice(pos..pos + 1, tokens);
+
+            for (_, end) in &mut self.expanding {
+                if *end > pos { *end = *end + count - 1; }
+            }
+            self.expanding.push((name, pos + count));
+
+            if value.ends_with([' ', '\t']) {
+                self.expand_next = Some(pos + count);
+            } else if let Some(next) = self.expand_next.as_mut().filter(|next| **next > pos) {
+                *next = *next + count - 1;
+            }
+        }
+    }
+
+    fn command (&mut self) -> Result<Command, ParseError> {
+        self.expand_alias();
+
+        match self.peek() {
+            Some(Token::Op("(")) => {
+                self.pos += 1;
+                let list = self.compound_body(")")?;
+                Ok(Command::Subshell(list, self.redirects()?))
+            }
+            Some(Token::Word(word)) if word == "{" => {
+                self.pos += 1;
+                let list = self.compound_body("}")?;
+                Ok(Command::Group(list, self.redirects()?))
+            }
+            Some(Token::Word(word)) if word == "[[" => {
+                self.pos += 1;
+                let words = self.conditional()?;
+                Ok(Command::Conditional(words, self.redirects()?))
+            }
+            _ => self.simple_command().map(Command::Simple),
+        }
+    }
+
+    /// The list inside `( )` or `{ }`, consuming the closing token.
+    fn compound_body (&mut self, closing: &'static str) -> Result<List, ParseError> {
+        let list = self.list(Some(closing))?;
+
+        let token = self.next();
+        if list.items.is_empty() {
+            return Err(unexpected(token.as_ref()));
+        }
+
+        Ok(list)
+    }
+
+    /// The words of a `[[ ]]`, consuming the `]]`. `&&`, `||`, parentheses, `<` and `>`
+    /// are operators of the expression in there, not of the shell.
+    fn conditional (&mut self) -> Result<Vec<String>, ParseError> {
+        let mut words = Vec::new();
+
+        loop {
+            match self.next() {
+                Some(Token::Word(word)) if word == "]]" && !words.is_empty() => return Ok(words),
+                Some(Token::Word(word)) if word == "]]" => return Err(unexpected(Some(&Token::Word(word)))),
+                Some(Token::Word(word)) => words.push(word),
+                Some(Token::IoNumber(fd)) => words.push(fd.to_string()),
+                Some(Token::Op("\n")) => {}
+                Some(Token::Op(op)) if ["&&", "||", "(", ")", "<", ">"].contains(&op) => words.push(op.to_string()),
+                None => return Err(ParseError::Incomplete),
+                token => return Err(unexpected(token.as_ref())),
+            }
+        }
+    }
+
+    /// Redirections after a `( )` or `{ }`.
+    fn redirects (&mut self) -> Result<Vec<Redirect>, ParseError> {
+        let mut redirects = Vec::new();
+
+        while let Some(redirect) = self.redirect()? {
+            redirects.push(redirect);
+        }
+
+        Ok(redirects)
+    }
+
+    /// A redirection if one starts here, with the here-document body the lexer put after it.
+    fn redirect (&mut self) -> Result<Option<Redirect>, ParseError> {
+        let fd = match self.peek() {
+            Some(Token::IoNumber(fd)) => {
+                let fd = *fd;
+                self.pos += 1;
+                Some(fd)
+            }
+            _ => None,
+        };
+
+        let op = match self.peek() {
+            Some(Token::Op(op)) if RedirectOp::from_operator(op).is_some() => *op,
+            token if fd.is_some() => return Err(unexpected(token)),
+            _ => return Ok(None),
+        };
+        self.pos += 1;
+
+        let target = self.next();
+        let mut redirect = parse_redirect(fd, op, target.as_ref())?;
+
+        // the lexer puts a here-document's body right after its delimiter
+        if let Some(Token::HereDocBody(body)) = self.peek() {
+            redirect.body = Some(body.clone());
+            self.pos += 1;
+        }
+
+        Ok(Some(redirect))
+    }
+
+    fn simple_command (&mut self) -> Result<SimpleCommand, ParseError> {
+        let mut command = SimpleCommand::default();
+
+        loop {
+            if let Some(redirect) = self.redirect()? {
+                command.redirects.push(redirect);
+                continue;
+            }
+
+            if self.expand_next == Some(self.pos) {
+                self.expand_next = None;
+                self.expand_alias();
+            }
+
+            match self.peek() {
+                Some(Token::Word(word)) if command.words.is_empty() && is_assignment(word) => {
+                    command.assignments.push(word.clone());
+                    self.pos += 1;
+
+                    // `LANG=C ll` still uses the alias
+                    self.expand_next = Some(self.pos);
+                }
+                Some(Token::Word(word)) => {
+                    command.words.push(word.clone());
+                    self.pos += 1;
+                }
+                _ => break,
+            }
+        }
+
+        if command.assignments.is_empty() && command.words.is_empty() && command.redirects.is_empty() {
+            // `ls |` and `true &&` go on on the next line
+            return Err(match self.peek() {
+                None => ParseError::Incomplete,
+                token => unexpected(token),
+            });
+        }
+
+        Ok(command)
+    }
+}
+
+fn parse_redirect (fd: Option<i32>, op: &str, target: Option<&Token>) -> Result<Redirect, ParseError> {
+    let op = RedirectOp::from_operator(op).expect("checked by the caller");
+
+    match target {
+        Some(Token::Word(target)) => Ok(Redirect {
+            fd: fd.unwrap_or(op.default_fd()),
+            op,
+            target: target.clone(),
+            body: None,
+        }),
+        other => Err(unexpected(other)),
+    }
+}
+
+impl fmt::Display for Redirect {
+    fn fmt (&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self.op {
+            RedirectOp::Read => "<",
+            RedirectOp::Write => ">",
+            RedirectOp::Clobber => ">|",
+            RedirectOp::Append => ">>",
+            RedirectOp::ReadWrite => "<>",
+            RedirectOp::DupIn => "<&",
+            RedirectOp::DupOut => ">&",
+            RedirectOp::WriteAll => return write!(f, "&>{}", self.target),
+            RedirectOp::AppendAll => return write!(f, "&>>{}", self.target),
+            RedirectOp::HereDoc => "<<",
+            RedirectOp::HereDocStrip => "<<-",
+            RedirectOp::HereString => "<<<",
+        };
+
+        if self.fd == self.op.default_fd() {
+            write!(f, "{op}{}", self.target)
+        } else {
+            write!(f, "{}{op}{}", self.fd, self.target)
+        }
+    }
+}
+
+impl fmt::Display for SimpleCommand {
+    fn fmt (&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts: Vec<String> = self.assignments.iter().chain(&self.words).cloned()
+            .chain(self.redirects.iter().map(|r| r.to_string()))
+            .collect();
+
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt (&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (text, redirects) = match self {
+            Command::Simple(command) => return write!(f, "{command}"),
+            Command::Subshell(list, redirects) => (format!("({list})"), redirects),
+            Command::Group(list, redirects) if list.items.last().is_some_and(|item| item.background) => (format!("{{ {list} }}"), redirects),
+            Command::Group(list, redirects) => (format!("{{ {list}; }}"), redirects),
+            Command::Conditional(words, redirects) => (format!("[[ {} ]]", words.join(" ")), redirects),
+        };
+
+        write!(f, "{text}")?;
+        for redirect in redirects {
+            write!(f, " {redirect}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Pipeline {
+    fn fmt (&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let commands: Vec<String> = self.commands.iter().map(|c| c.to_string()).collect();
+        write!(f, "{}", commands.join(" | "))
+    }
+}
+
+impl fmt::Display for AndOr {
+    fn fmt (&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.first)?;
+
+        for (connector, pipeline) in &self.rest {
+            let op = match connector {
+                Connector::And => "&&",
+                Connector::Or => "||",
+            };
+            write!(f, " {op} {pipeline}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for List {
+    fn fmt (&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, item) in self.items.iter().enumerate() {
+            if idx > 0 { write!(f, " ")?; }
+            write!(f, "{item}")?;
+
+            if item.background {
+                write!(f, " &")?;
+            } else if idx + 1 < self.items.len() {
+                write!(f, ";")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod parser_tests {
+    use super::*;
+
+    fn parse (input: &str) -> Result<List, ParseError> {
+        super::parse(input, &HashMap::new())
+    }
+
+    fn simple (list: &List, item: usize) -> &SimpleCommand {
+        match &list.items[item].first.commands[0] {
+            Command::Simple(command) => command,
+            other => panic!("not a simple command: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn pipeline_with_redirections () {
+        let list = parse("sort < in 2>/dev/null | uniq -c >> out &").unwrap();
+        let and_or = &list.items[0];
+
+        assert!(and_or.background);
+        assert_eq!(and_or.first.commands.len(), 2);
+        assert_eq!(simple(&list, 0).words, vec!["sort"]);
+        assert_eq!(simple(&list, 0).redirects, vec![
+            Redirect { fd: 0, op: RedirectOp::Read, target: "in".to_string(), body: None },
+            Redirect { fd: 2, op: RedirectOp::Write, target: "/dev/null".to_string(), body: None },
+        ]);
+        assert_eq!(and_or.first.to_string(), "sort <in 2>/dev/null | uniq -c >>out");
+    }
+
+    #[test]
+    fn here_document_bodies () {
+        let list = parse("cat <<A 3<<-B\none\nA\n\ttwo\nB").unwrap();
+        let redirects = &simple(&list, 0).redirects;
+
+        assert_eq!(redirects[0].body.as_deref(), Some("one\n"));
+        assert_eq!(redirects[1].fd, 3);
+        assert_eq!(redirects[1].body.as_deref(), Some("two\n"));
+        assert_eq!(list.to_string(), "cat <<A 3<<-B");
+    }
+
+    #[test]
+    fn lists_and_groups () {
+        let list = parse("cd /tmp && ls || echo no; (cd dir && make) 2>&1 | tee log\n{ echo a; echo } ; } > out & echo }").unwrap();
+
+        assert_eq!(list.items.len(), 4);
+        assert_eq!(list.items[0].rest.len(), 2);
+        assert_eq!(list.items[0].rest[1].0, Connector::Or);
+        assert!(matches!(&list.items[1].first.commands[0], Command::Subshell(inner, redirects) if inner.items.len() == 1 && redirects.len() == 1));
+        assert!(matches!(&list.items[2].first.commands[0], Command::Group(inner, _) if inner.items.len() == 2));
+        assert!(list.items[2].background);
+        assert_eq!(simple(&list, 3).words, vec!["echo", "}"]);
+
+        assert_eq!(
+            list.to_string(),
+            "cd /tmp && ls || echo no; (cd dir && make) 2>&1 | tee log; { echo a; echo }; } >out & echo }"
+        );
+    }
+
+    #[test]
+    fn conditionals () {
+        let list = parse("[[ -n $a && ( $b < c || ! 2 > 1 ) ]] > out | cat; [[ $x =~ (a|b) ]]").unwrap();
+
+        assert!(matches!(
+            &list.items[0].first.commands[0],
+            Command::Conditional(words, redirects) if words.len() == 13 && redirects.len() == 1
+        ));
+        assert_eq!(list.to_string(), "[[ -n $a && ( $b < c || ! 2 > 1 ) ]] >out | cat; [[ $x =~ (a|b) ]]");
+
+        assert_eq!(parse("[[ -f x"), Err(ParseError::Incomplete));
+        assert_eq!(parse("[[ ]]"), Err(ParseError::Syntax("syntax error near unexpected token `]]'".to_string())));
+        assert_eq!(parse("[[ a ; ]]"), Err(ParseError::Syntax("syntax error near unexpected token `;'".to_string())));
+    }
+
+    #[test]
+    fn aliases () {
+        let aliases: HashMap<String, String> = [
+            ("ll", "ls -l"),
+            ("ls", "ls --color "),
+            ("sudo", "sudo "),
+            ("quiet", "> /dev/null"),
+            ("both", "echo a | cat"),
+            ("loop", "loop"),
+        ].into_iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+
+        let expand = |input: &str| super::parse(input, &aliases).unwrap().to_string();
+
+        assert_eq!(expand("ll /tmp"), "ls --color -l /tmp");
+        assert_eq!(expand("sudo ll"), "sudo ls --color -l");
+        assert_eq!(expand("echo ll; both && 'll'"), "echo ll; echo a | cat && 'll'");
+        assert_eq!(expand("quiet echo hi"), "echo hi >/dev/null");
+        assert_eq!(expand("loop"), "loop");
+        assert_eq!(expand("A=1 B='2 3' ll x=y"), "A=1 B='2 3' ls --color -l x=y");
+    }
+
+    #[test]
+    fn assignments () {
+        let list = parse("A=1 _b2=\"x y\" cmd C=3; 'D'=4 E").unwrap();
+
+        assert_eq!(simple(&list, 0).assignments, vec!["A=1", "_b2=\"x y\""]);
+        assert_eq!(simple(&list, 0).words, vec!["cmd", "C=3"]);
+        assert!(simple(&list, 1).assignments.is_empty());
+        assert!(!is_assignment("1x=2"));
+        assert!(!is_assignment("=2"));
+    }
+
+    #[test]
+    fn syntax_errors () {
+        let syntax = |message: &str| Err(ParseError::Syntax(message.to_string()));
+
+        assert_eq!(parse("| ls"), syntax("syntax error near unexpected token `|'"));
+        assert_eq!(parse("echo >"), syntax("syntax error near unexpected token `newline'"));
+        assert_eq!(parse("echo > | x"), syntax("syntax error near unexpected token `|'"));
+        assert_eq!(parse("ls ;; ls"), syntax("syntax error near unexpected token `;'"));
+        assert_eq!(parse("( )"), syntax("syntax error near unexpected token `)'"));
+        assert_eq!(parse("(ls) x"), syntax("syntax error near unexpected token `x'"));
+        assert_eq!(parse("ls )"), syntax("syntax error near unexpected token `)'"));
+        assert_eq!(parse("   "), Ok(List::default()));
+    }
+
+    #[test]
+    fn incomplete_input () {
+        for input in ["ls |", "true &&\n", "false ||", "{ ls", "(cd /\nls", "echo 'a", "echo a\\", "cat <<EOF"] {
+            assert_eq!(parse(input), Err(ParseError::Incomplete), "{input:?}");
+        }
+
+        let mut lines = vec!["x", "EOF", "wc -l", "b'"].into_iter().map(String::from);
+
+        let mut input = "cat <<EOF |".to_string();
+        assert!(complete_input(&mut input, &mut |_| lines.next()));
+        assert_eq!(input, "cat <<EOF |\nx\nEOF\nwc -l");
+
+        let mut input = "echo 'a".to_string();
+        assert!(complete_input(&mut input, &mut |_| lines.next()));
+        assert_eq!(input, "echo 'a\nb'");
+
+        let mut input = "{ echo".to_string();
+        assert!(!complete_input(&mut input, &mut |_| lines.next()));
+    }
+
+    #[test]
+    fn unclosed_keywords () {
+        assert_eq!(unclosed_keyword("if true; then"), Some("fi"));
+        assert_eq!(unclosed_keyword("if true; then echo; fi"), None);
+        assert_eq!(unclosed_keyword("for x in a b; do"), Some("done"));
+        assert_eq!(unclosed_keyword("while true\ndo\nif x; then y; fi"), Some("done"));
+        assert_eq!(unclosed_keyword("case x in a) if y; then z; fi;; esac"), None);
+
+        // only a word where a command starts is a keyword
+        for input in ["echo if", "echo 'if' while", "cat > if", "for if in do; do echo if; done", "X=1 done"] {
+            assert_eq!(unclosed_keyword(input), None, "{input:?}");
+        }
+
+        let mut lines = vec!["then echo yes", "fi", "do"].into_iter().map(String::from);
+        let mut input = "if true".to_string();
+        assert!(complete_input(&mut input, &mut |_| lines.next()));
+        assert_eq!(input, "if true\nthen echo yes\nfi");
+
+        let mut input = "while true".to_string();
+        assert!(!complete_input(&mut input, &mut |_| lines.next()));
+    }
+}
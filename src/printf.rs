@@ -0,0 +1,601 @@
+use std::ffi::CString;
+use std::io::{self, Write};
+
+use crate::utils;
+
+/// The problems `printf` ran into on the way.
+pub struct Formatted {
+    /// Messages for arguments that weren't numbers and formats it couldn't make sense of
+    pub errors: Vec<String>,
+    /// Why the output couldn't be written, nothing more was printed after that
+    pub write_error: Option<io::Error>
+}
+
+/// A converted argument, with `padding` copies of `fill` to go in at `at` to make it as wide as
+/// asked. The padding is only written out, a huge width never has to fit in memory.
+struct Padded {
+    text: Vec<u8>,
+    at: usize,
+    padding: usize,
+    fill: u8
+}
+
+/// A `%` conversion, with `*` widths and precisions already taken from the arguments.
+struct Spec {
+    flags: String,
+    width: Option<usize>,
+    precision: Option<usize>,
+    conversion: char,
+    // the strftime format of a `%(...)T`
+    time_format: String
+}
+
+/// Replaces the backslash escape starting after the `\` at `idx`, returns where the text goes on,
+/// None for a `\c`. `echo -e` and `%b` take octal as `\0NNN`, the format of printf as `\NNN`.
+fn escape (chars: &[char], idx: usize, echo: bool, output: &mut Vec<u8>) -> Option<usize> {
+    let Some(&ch) = chars.get(idx) else {
+        output.push(b'\\');
+        return Some(idx);
+    };
+
+    let byte = match ch {
+        'a' => 0x07,
+        'b' => 0x08,
+        'e' | 'E' => 0x1b,
+        'f' => 0x0c,
+        'n' => b'\n',
+        'r' => b'\r',
+        't' => b'\t',
+        'v' => 0x0b,
+        '\\' => b'\\',
+        '\'' | '"' | '?' if !echo => ch as u8,
+        'c' => return None,
+        '0'..='7' => {
+            let start = if echo && ch == '0' { idx + 1 } else { idx };
+            let (value, end) = digits(chars, start, 8, 3);
+            output.push(value as u8);
+            return Some(end);
+        }
+        'x' | 'u' | 'U' => {
+            let max = match ch { 'x' => 2, 'u' => 4, _ => 8 };
+            let (value, end) = digits(chars, idx + 1, 16, max);
+
+            if end == idx + 1 {
+                output.push(b'\\');
+                return Some(idx);
+            }
+
+            if ch == 'x' {
+                output.push(value as u8);
+            } else {
+                let ch = char::from_u32(value).unwrap_or(char::REPLACEMENT_CHARACTER);
+                output.extend(ch.to_string().as_bytes());
+            }
+            return Some(end);
+        }
+        _ => {
+            output.push(b'\\');
+            return Some(idx);
+        }
+    };
+
+    output.push(byte);
+    Some(idx + 1)
+}
+
+/// Reads up to `max` digits in `radix` from `start`, returns their value and where they end.
+fn digits (chars: &[char], start: usize, radix: u32, max: usize) -> (u32, usize) {
+    let mut value = 0u32;
+    let mut idx = start;
+
+    while idx < chars.len() && idx - start < max {
+        let Some(digit) = chars[idx].to_digit(radix) else { break };
+        value = value * radix + digit;
+        idx += 1;
+    }
+
+    (value, idx)
+}
+
+/// Reads the width or precision written at `start`, which has to fit in an int as it does in C.
+/// Returns it, if there are digits, and where they end.
+fn size (chars: &[char], start: usize) -> Result<(Option<usize>, usize), String> {
+    let end = start + chars[start..].iter().take_while(|ch| ch.is_ascii_digit()).count();
+    let text: String = chars[start..end].iter().collect();
+
+    match text.parse::<i32>() {
+        Ok(value) => Ok((Some(value as usize), end)),
+        Err(_) if text.is_empty() => Ok((None, end)),
+        Err(_) => Err(format!("{text}: {}", utils::strerror(libc::ERANGE))),
+    }
+}
+
+/// The escapes `echo -e` and `%b` know about replaced in `text`.
+/// The bool is false if a `\c` asked for the output to stop there.
+pub fn unescape (text: &str) -> (Vec<u8>, bool) {
+    let chars: Vec<char> = text.chars().collect();
+    let mut output = Vec::new();
+    let mut idx = 0;
+
+    while idx < chars.len() {
+        if chars[idx] != '\\' {
+            let mut buf = [0; 4];
+            output.extend(chars[idx].encode_utf8(&mut buf).as_bytes());
+            idx += 1;
+            continue;
+        }
+
+        match escape(&chars, idx + 1, true, &mut output) {
+            Some(next) => idx = next,
+            None => return (output, false),
+        }
+    }
+
+    (output, true)
+}
+
+/// Quotes `text` so the shell reads it back as the same word.
+fn quote (text: &str) -> String {
+    if text.is_empty() {
+        return "''".to_string();
+    }
+
+    if text.chars().any(|ch| ch.is_control()) {
+        let mut quoted = String::from("$'");
+
+        for ch in text.chars() {
+            match ch {
+                '\n' => quoted.push_str("\\n"),
+                '\t' => quoted.push_str("\\t"),
+                '\r' => quoted.push_str("\\r"),
+                '\x1b' => quoted.push_str("\\E"),
+                '\'' | '\\' => {
+                    quoted.push('\\');
+                    quoted.push(ch);
+                }
+                ch if ch.is_control() => quoted.push_str(&format!("\\{:03o}", ch as u32)),
+                ch => quoted.push(ch),
+            }
+        }
+
+        quoted.push('\'');
+        return quoted;
+    }
+
+    let mut quoted = String::new();
+    for ch in text.chars() {
+        if !ch.is_alphanumeric() && !"_-./,:@%+=^".contains(ch) {
+            quoted.push('\\');
+        }
+        quoted.push(ch);
+    }
+
+    quoted
+}
+
+/// Reads a number the way printf wants it: decimal, `0x` hex, `0` octal or `'c` for a character's code.
+/// What could be read is still used when the rest isn't a number.
+fn integer (arg: &str) -> Result<i64, i64> {
+    let trimmed = arg.trim_start();
+
+    if let Some(ch) = trimmed.strip_prefix(['\'', '"']).and_then(|rest| rest.chars().next()) {
+        return Ok(ch as i64);
+    }
+
+    let (negative, rest) = match trimmed.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, trimmed.strip_prefix('+').unwrap_or(trimmed)),
+    };
+
+    let (radix, digits) = if let Some(hex) = rest.strip_prefix("0x").or_else(|| rest.strip_prefix("0X")) {
+        (16, hex)
+    } else if rest.len() > 1 && rest.starts_with('0') {
+        (8, &rest[1..])
+    } else {
+        (10, rest)
+    };
+
+    let end = digits.find(|ch: char| !ch.is_digit(radix)).unwrap_or(digits.len());
+    let value = i64::from_str_radix(&digits[..end], radix).unwrap_or(if end == 0 { 0 } else { i64::MAX });
+    let value = if negative { value.wrapping_neg() } else { value };
+
+    if arg.is_empty() || (end == digits.len() && end > 0) { Ok(value) } else { Err(value) }
+}
+
+fn float (arg: &str) -> Result<f64, f64> {
+    let trimmed = arg.trim_start();
+
+    if let Some(ch) = trimmed.strip_prefix(['\'', '"']).and_then(|rest| rest.chars().next()) {
+        return Ok(ch as u32 as f64);
+    }
+    if arg.is_empty() {
+        return Ok(0.0);
+    }
+
+    trimmed.parse::<f64>().or_else(|_| integer(arg).map(|value| value as f64).map_err(|value| value as f64))
+}
+
+/// Formats one number with the C library, so flags, width and precision mean exactly what they do in C.
+fn c_format_integer (spec: &str, value: i64) -> String {
+    let Ok(spec) = CString::new(spec) else { return String::new() };
+
+    unsafe {
+        let len = libc::snprintf(std::ptr::null_mut(), 0, spec.as_ptr(), value as libc::c_longlong);
+        let mut buf = vec![0u8; len.max(0) as usize + 1];
+        libc::snprintf(buf.as_mut_ptr().cast(), buf.len(), spec.as_ptr(), value as libc::c_longlong);
+        buf.truncate(len.max(0) as usize);
+        String::from_utf8_lossy(&buf).to_string()
+    }
+}
+
+fn c_format_float (spec: &str, value: f64) -> String {
+    let Ok(spec) = CString::new(spec) else { return String::new() };
+
+    unsafe {
+        let len = libc::snprintf(std::ptr::null_mut(), 0, spec.as_ptr(), value);
+        let mut buf = vec![0u8; len.max(0) as usize + 1];
+        libc::snprintf(buf.as_mut_ptr().cast(), buf.len(), spec.as_ptr(), value);
+        buf.truncate(len.max(0) as usize);
+        String::from_utf8_lossy(&buf).to_string()
+    }
+}
+
+/// Cuts `text` to the precision and pads it to the width, counting characters when it's valid UTF-8.
+fn pad (text: &[u8], spec: &Spec) -> Padded {
+    let units: Vec<&[u8]> = match std::str::from_utf8(text) {
+        Ok(text) => text.char_indices().map(|(idx, ch)| &text.as_bytes()[idx..idx + ch.len_utf8()]).collect(),
+        Err(_) => text.chunks(1).collect(),
+    };
+
+    let count = spec.precision.map_or(units.len(), |precision| precision.min(units.len()));
+    let text = units[..count].concat();
+    let at = if spec.flags.contains('-') { text.len() } else { 0 };
+
+    Padded { text, at, padding: spec.width.unwrap_or(0).saturating_sub(count), fill: b' ' }
+}
+
+/// Pads a number C formatted without its width the way C would have: `-` pads on the right,
+/// `0` with zeros after the sign and `0x`, unless it's inf or nan or an integer with a precision.
+fn pad_number (text: String, spec: &Spec) -> Padded {
+    let padding = spec.width.unwrap_or(0).saturating_sub(text.len());
+    let integer = "diouxX".contains(spec.conversion);
+    let zeros = spec.flags.contains('0') && text.bytes().any(|byte| byte.is_ascii_digit()) && !(integer && spec.precision.is_some());
+
+    let (at, fill) = if spec.flags.contains('-') {
+        (text.len(), b' ')
+    } else if zeros {
+        let sign = usize::from(text.starts_with(['+', '-', ' ']));
+        let prefix = if text[sign..].starts_with("0x") || text[sign..].starts_with("0X") { 2 } else { 0 };
+        (sign + prefix, b'0')
+    } else {
+        (0, b' ')
+    };
+
+    Padded { text: text.into_bytes(), at, padding, fill }
+}
+
+struct Printer<'a> {
+    args: &'a [&'a str],
+    next: usize,
+    out: &'a mut dyn Write,
+    formatted: Formatted
+}
+
+impl Printer<'_> {
+    /// Writes `bytes` out, false once that failed.
+    fn emit (&mut self, bytes: &[u8]) -> bool {
+        if self.formatted.write_error.is_some() { return false; }
+
+        match self.out.write_all(bytes) {
+            Ok(()) => true,
+            Err(error) => {
+                self.formatted.write_error = Some(error);
+                false
+            }
+        }
+    }
+
+    fn emit_padded (&mut self, padded: &Padded) -> bool {
+        let (before, after) = padded.text.split_at(padded.at);
+        if !self.emit(before) { return false; }
+
+        let chunk = [padded.fill; 4096];
+        let mut left = padded.padding;
+        while left > 0 {
+            let len = left.min(chunk.len());
+            if !self.emit(&chunk[..len]) { return false; }
+            left -= len;
+        }
+
+        self.emit(after)
+    }
+
+    fn arg (&mut self) -> Option<&str> {
+        let arg = self.args.get(self.next).copied();
+        self.next += 1;
+        arg
+    }
+
+    fn integer_arg (&mut self) -> i64 {
+        let arg = self.arg().unwrap_or_default().to_string();
+
+        integer(&arg).unwrap_or_else(|value| {
+            self.formatted.errors.push(format!("{arg}: invalid number"));
+            value
+        })
+    }
+
+    /// A `*` width or precision, out of an int's range is an error rather than a huge allocation.
+    fn size_arg (&mut self) -> Result<i32, String> {
+        let arg = self.arg().unwrap_or_default().to_string();
+        let value = integer(&arg).unwrap_or_else(|value| {
+            self.formatted.errors.push(format!("{arg}: invalid number"));
+            value
+        });
+
+        i32::try_from(value).map_err(|_| format!("{arg}: {}", utils::strerror(libc::ERANGE)))
+    }
+
+    fn float_arg (&mut self) -> f64 {
+        let arg = self.arg().unwrap_or_default().to_string();
+
+        float(&arg).unwrap_or_else(|value| {
+            self.formatted.errors.push(format!("{arg}: invalid number"));
+            value
+        })
+    }
+
+    /// Reads the conversion after the `%` at `idx`, returns it and where the format goes on.
+    fn spec (&mut self, chars: &[char], mut idx: usize) -> Result<(Spec, usize), String> {
+        let mut spec = Spec { flags: String::new(), width: None, precision: None, conversion: '%', time_format: String::new() };
+
+        while let Some(&ch) = chars.get(idx).filter(|ch| "-+ #0".contains(**ch)) {
+            spec.flags.push(ch);
+            idx += 1;
+        }
+
+        if chars.get(idx) == Some(&'*') {
+            let width = self.size_arg()?;
+            if width < 0 { spec.flags.push('-'); }
+            spec.width = Some(width.unsigned_abs() as usize);
+            idx += 1;
+        } else {
+            (spec.width, idx) = size(chars, idx)?;
+        }
+
+        if chars.get(idx) == Some(&'.') {
+            idx += 1;
+
+            if chars.get(idx) == Some(&'*') {
+                // a negative precision is as good as none
+                spec.precision = usize::try_from(self.size_arg()?).ok();
+                idx += 1;
+            } else {
+                let (precision, end) = size(chars, idx)?;
+                spec.precision = Some(precision.unwrap_or(0));
+                idx = end;
+            }
+        }
+
+        // length modifiers don't change anything here
+        while chars.get(idx).is_some_and(|ch| "hlLjzt".contains(*ch)) {
+            idx += 1;
+        }
+
+        if chars.get(idx) == Some(&'(') {
+            let close = chars[idx..].iter().position(|&ch| ch == ')').ok_or("`(': missing closing parenthesis")?;
+            spec.time_format = chars[idx + 1..idx + close].iter().collect();
+            idx += close + 1;
+
+            if chars.get(idx) != Some(&'T') {
+                return Err("`T': missing after the time format".to_string());
+            }
+        }
+
+        match chars.get(idx) {
+            Some(&ch) => spec.conversion = ch,
+            None => return Err("`%': missing format character".to_string()),
+        }
+
+        Ok((spec, idx + 1))
+    }
+
+    /// Formats the next argument, the bool is false if a `\c` in a `%b` argument stops the output.
+    fn convert (&mut self, spec: &Spec) -> Result<(Padded, bool), String> {
+        // the width is left to `pad_number`
+        let c_spec = |length: &str| {
+            let mut c_spec = format!("%{}", spec.flags);
+            if let Some(precision) = spec.precision { c_spec.push_str(&format!(".{precision}")); }
+            format!("{c_spec}{length}{}", spec.conversion)
+        };
+
+        let output = match spec.conversion {
+            'd' | 'i' | 'o' | 'u' | 'x' | 'X' => pad_number(c_format_integer(&c_spec("ll"), self.integer_arg()), spec),
+            'f' | 'F' | 'e' | 'E' | 'g' | 'G' | 'a' | 'A' => pad_number(c_format_float(&c_spec(""), self.float_arg()), spec),
+            's' => pad(self.arg().unwrap_or_default().as_bytes(), spec),
+            'c' => pad(self.arg().unwrap_or_default().chars().next().map(String::from).unwrap_or_default().as_bytes(), spec),
+            'q' => pad(quote(self.arg().unwrap_or_default()).as_bytes(), spec),
+            'b' => {
+                let (text, go_on) = unescape(self.arg().unwrap_or_default());
+                return Ok((pad(&text, spec), go_on));
+            }
+            'T' => {
+                // no argument, or -1, is now; -2 would be when the shell started
+                let seconds = match self.arg() {
+                    None | Some("") => -1,
+                    Some(arg) => integer(arg).map_err(|_| format!("{arg}: invalid number"))?,
+                };
+                let seconds = if seconds < 0 { unsafe { libc::time(std::ptr::null_mut()) } } else { seconds };
+
+                pad(utils::format_time(&spec.time_format, seconds).as_bytes(), spec)
+            }
+            ch => return Err(format!("`{ch}': invalid format character")),
+        };
+
+        Ok((output, true))
+    }
+
+    /// Goes through the format once, false if it has to stop there.
+    fn print (&mut self, format: &[char]) -> bool {
+        let mut idx = 0;
+
+        while idx < format.len() {
+            match format[idx] {
+                '\\' => {
+                    let mut output = Vec::new();
+                    let next = escape(format, idx + 1, false, &mut output);
+
+                    if !self.emit(&output) { return false; }
+                    match next {
+                        Some(next) => idx = next,
+                        None => return false,
+                    }
+                }
+                '%' if format.get(idx + 1) == Some(&'%') => {
+                    if !self.emit(b"%") { return false; }
+                    idx += 2;
+                }
+                '%' => {
+                    let converted = self.spec(format, idx + 1)
+                        .and_then(|(spec, next)| self.convert(&spec).map(|output| (output, next)));
+
+                    match converted {
+                        Ok(((output, go_on), next)) => {
+                            if !self.emit_padded(&output) || !go_on { return false; }
+                            idx = next;
+                        }
+                        Err(error) => {
+                            self.formatted.errors.push(error);
+                            return false;
+                        }
+                    }
+                }
+                ch => {
+                    let mut buf = [0; 4];
+                    if !self.emit(ch.encode_utf8(&mut buf).as_bytes()) { return false; }
+                    idx += 1;
+                }
+            }
+        }
+
+        true
+    }
+}
+
+/// Formats `args` with `format` into `out`, the format is used again as long as arguments are left.
+/// Missing arguments are empty strings, or 0 for numbers.
+pub fn printf (format: &str, args: &[&str], out: &mut dyn Write) -> Formatted {
+    let format: Vec<char> = format.chars().collect();
+    let mut printer = Printer { args, next: 0, out, formatted: Formatted { errors: Vec::new(), write_error: None } };
+
+    loop {
+        let start = printer.next;
+        if !printer.print(&format) { break; }
+
+        // a format without conversions doesn't eat arguments, it's printed once
+        if printer.next == start || printer.next >= args.len() { break; }
+    }
+
+    printer.formatted
+}
+
+#[cfg(test)]
+mod printf_tests {
+    use super::*;
+
+    fn printf (format: &str, args: &[&str]) -> (String, Vec<String>) {
+        let mut output = Vec::new();
+        let formatted = super::printf(format, args, &mut output);
+        (String::from_utf8_lossy(&output).to_string(), formatted.errors)
+    }
+
+    fn output (format: &str, args: &[&str]) -> String {
+        printf(format, args).0
+    }
+
+    #[test]
+    fn conversions () {
+        assert_eq!(output("%d|%5s|%-5s|%.2s|%c", &["42", "ab", "cd", "xyz", "qrs"]), "42|   ab|cd   |xy|q");
+        assert_eq!(output("%x %X %o %u %#x", &["255", "255", "8", "-1", "16"]), "ff FF 10 18446744073709551615 0x10");
+        assert_eq!(output("%05.1f|%e|%g", &["3.14159", "1000", "0.5"]), "003.1|1.000000e+03|0.5");
+        assert_eq!(output("%d %d %d", &["0x10", "010", "'A"]), "16 8 65");
+        assert_eq!(output("%*d|%-*s|%.*s", &["4", "7", "3", "a", "1", "xyz"]), "   7|a  |x");
+        assert_eq!(output("100%%\\n", &[]), "100%\n");
+        assert_eq!(output("%s,%d;", &[]), ",0;");
+    }
+
+    #[test]
+    fn padding () {
+        assert_eq!(output("%010.3f|%#08x|%08.3d|%-5d|", &["-3.14159", "255", "5", "42"]), "-00003.142|0x0000ff|     005|42   |");
+        assert_eq!(output("%+05d|% 05d|%05f|%-05d|", &["42", "42", "inf", "7"]), "+0042| 0042|  inf|7    |");
+        assert_eq!(output("%5.1s|%-3c|", &["héllo", "é"]), "    h|é  |");
+    }
+
+    // counts what's written without keeping it
+    struct Counter(usize);
+
+    impl Write for Counter {
+        fn write (&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush (&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn huge_widths () {
+        for format in ["%1000000000s", "%1000000000d", "%-1000000000.2f"] {
+            let mut counter = Counter(0);
+            assert!(super::printf(format, &["1"], &mut counter).errors.is_empty());
+            assert_eq!(counter.0, 1_000_000_000, "{format}");
+        }
+
+        // a write that fails stops the output
+        let mut full: &mut [u8] = &mut [0; 4];
+        let formatted = super::printf("%s and more", &["abc"], &mut full);
+        assert!(formatted.write_error.is_some());
+    }
+
+    #[test]
+    fn format_reuse () {
+        assert_eq!(output("%s=%s\\n", &["a", "1", "b", "2", "c"]), "a=1\nb=2\nc=\n");
+        assert_eq!(output("hi\\n", &["ignored"]), "hi\n");
+    }
+
+    #[test]
+    fn escapes () {
+        assert_eq!(output("\\101\\x41\\u00e9\\t|", &[]), "AAé\t|");
+        assert_eq!(output("%b|", &["a\\tb\\0101"]), "a\tbA|");
+        assert_eq!(output("%s %b %s", &["one", "two\\cthree", "four"]), "one two");
+        assert_eq!(output("a\\cb", &[]), "a");
+        assert_eq!(unescape("x\\ny\\c z"), (b"x\ny".to_vec(), false));
+    }
+
+    #[test]
+    fn quoting () {
+        assert_eq!(output("%q %q %q %q", &["plain/path.txt", "it's a $var", "", "tab\there"]), r"plain/path.txt it\'s\ a\ \$var '' $'tab\there'");
+    }
+
+    #[test]
+    fn times () {
+        assert_eq!(output("%(%Y)T", &["0"]).len(), 4);
+        assert_eq!(output("[%(%%)T]", &["0"]), "[%]");
+    }
+
+    #[test]
+    fn errors () {
+        assert_eq!(printf("%d|", &["12abc"]), ("12|".to_string(), vec!["12abc: invalid number".to_string()]));
+        assert_eq!(printf("a%kb", &[]), ("a".to_string(), vec!["`k': invalid format character".to_string()]));
+        assert_eq!(printf("%", &[]).1, vec!["`%': missing format character".to_string()]);
+
+        // widths and precisions have to fit in an int
+        let range = format!("99999999999999999999: {}", utils::strerror(libc::ERANGE));
+        assert_eq!(printf("a%*sb", &["99999999999999999999", "x"]), ("a".to_string(), vec![range.clone()]));
+        assert_eq!(printf("%.*s", &["99999999999999999999", "x"]).1, vec![range.clone()]);
+        assert_eq!(printf("%99999999999999999999s", &["x"]).1, vec![range]);
+        assert_eq!(output("%*s|", &["-3", "x"]), "x  |");
+    }
+}
@@ -0,0 +1,244 @@
+use crate::dirs;
+use crate::utils;
+
+// what `\[` and `\]` turn into, the terminal never sees them
+const START_IGNORE: char = '\x01';
+const END_IGNORE: char = '\x02';
+
+/// What the backslash escapes of a prompt stand for.
+pub struct PromptInfo {
+    pub user: String,
+    pub host: String,
+    pub pwd: String,
+    /// `\j`
+    pub jobs: usize,
+    /// `\!`, the number the command about to be typed gets in the history
+    pub history: usize,
+    pub root: bool,
+    pub time: i64,
+    /// `\g`, the git branch and status of the directory
+    pub git: String
+}
+
+/// What a `$` in a prompt asks for.
+pub enum Expansion<'a> {
+    /// `$name` or `${name}`
+    Variable(&'a str),
+    /// `$(command)`
+    Command(&'a str)
+}
+
+/// Where the `)` closing a `$(` that starts at `start` is, if there's one.
+fn command_end (chars: &[char], start: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut quote = None;
+
+    for (idx, &ch) in chars.iter().enumerate().skip(start) {
+        match (quote, ch) {
+            (Some(q), ch) if ch == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(ch),
+            (None, '(') => depth += 1,
+            (None, ')') => {
+                depth -= 1;
+                if depth == 0 { return Some(idx); }
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// The last component of `pwd`, `~` for $HOME itself.
+fn basename (pwd: &str) -> String {
+    match dirs::abbreviate(pwd).as_str() {
+        "~" => "~".to_string(),
+        _ if pwd == "/" => "/".to_string(),
+        _ => pwd.trim_end_matches('/').rsplit('/').next().unwrap_or(pwd).to_string(),
+    }
+}
+
+/// Turns the text of $PS1 (or $PS2, $PS4) into what's shown: backslash escapes are replaced,
+/// and so are `$name`, `${name}` and `$(command)`. Nothing is expanded twice, a directory
+/// called `$HOME` shows up as such. Non-printing parts are kept between `\x01` and `\x02`.
+pub fn expand (prompt: &str, info: &PromptInfo, expand: &mut dyn FnMut(Expansion) -> String) -> String {
+    let chars: Vec<char> = prompt.chars().collect();
+    let mut out = String::new();
+    let mut idx = 0;
+
+    while idx < chars.len() {
+        let ch = chars[idx];
+        idx += 1;
+
+        match ch {
+            '\\' if idx < chars.len() => {
+                let escape = chars[idx];
+                idx += 1;
+
+                match escape {
+                    'u' => out.push_str(&info.user),
+                    'h' => out.push_str(info.host.split('.').next().unwrap_or_default()),
+                    'H' => out.push_str(&info.host),
+                    'w' => out.push_str(&dirs::abbreviate(&info.pwd)),
+                    'W' => out.push_str(&basename(&info.pwd)),
+                    '$' => out.push(if info.root { '#' } else { '$' }),
+                    'j' => out.push_str(&info.jobs.to_string()),
+                    '!' => out.push_str(&info.history.to_string()),
+                    's' => out.push_str("nyash"),
+                    'g' => out.push_str(&info.git),
+                    't' => out.push_str(&utils::format_time("%H:%M:%S", info.time)),
+                    'T' => out.push_str(&utils::format_time("%I:%M:%S", info.time)),
+                    '@' => out.push_str(&utils::format_time("%I:%M %p", info.time)),
+                    'A' => out.push_str(&utils::format_time("%H:%M", info.time)),
+                    'd' => out.push_str(&utils::format_time("%a %b %d", info.time)),
+                    'D' if chars.get(idx) == Some(&'{') => {
+                        let Some(len) = chars[idx..].iter().position(|&ch| ch == '}') else {
+                            out.push_str("\\D");
+                            continue;
+                        };
+                        let format: String = chars[idx + 1..idx + len].iter().collect();
+                        let format = if format.is_empty() { "%X".to_string() } else { format };
+
+                        out.push_str(&utils::format_time(&format, info.time));
+                        idx += len + 1;
+                    }
+                    'n' => out.push('\n'),
+                    'r' => out.push('\r'),
+                    'a' => out.push('\x07'),
+                    'e' => out.push('\x1b'),
+                    '\\' => out.push('\\'),
+                    '[' => out.push(START_IGNORE),
+                    ']' => out.push(END_IGNORE),
+                    '0'..='7' => {
+                        let digits = chars[idx - 1..].iter().take(3).take_while(|ch| ('0'..='7').contains(ch)).count();
+                        let code: String = chars[idx - 1..idx - 1 + digits].iter().collect();
+
+                        out.extend(u32::from_str_radix(&code, 8).ok().and_then(char::from_u32));
+                        idx += digits - 1;
+                    }
+                    escape => {
+                        out.push('\\');
+                        out.push(escape);
+                    }
+                }
+            }
+            '$' if chars.get(idx) == Some(&'(') => match command_end(&chars, idx) {
+                Some(end) => {
+                    let command: String = chars[idx + 1..end].iter().collect();
+                    out.push_str(&expand(Expansion::Command(&command)));
+                    idx = end + 1;
+                }
+                None => out.push(ch),
+            },
+            '$' if chars.get(idx) == Some(&'{') => match chars[idx..].iter().position(|&ch| ch == '}') {
+                Some(len) => {
+                    let name: String = chars[idx + 1..idx + len].iter().collect();
+                    out.push_str(&expand(Expansion::Variable(&name)));
+                    idx += len + 1;
+                }
+                None => out.push(ch),
+            },
+            '$' if chars.get(idx).is_some_and(|ch| "?$!#0123456789".contains(*ch)) => {
+                out.push_str(&expand(Expansion::Variable(&chars[idx].to_string())));
+                idx += 1;
+            }
+            '$' if chars.get(idx).is_some_and(|ch| ch.is_ascii_alphabetic() || *ch == '_') => {
+                let len = chars[idx..].iter().take_while(|ch| ch.is_ascii_alphanumeric() || **ch == '_').count();
+                let name: String = chars[idx..idx + len].iter().collect();
+
+                out.push_str(&expand(Expansion::Variable(&name)));
+                idx += len;
+            }
+            ch => out.push(ch),
+        }
+    }
+
+    out
+}
+
+/// How many columns the last line of an expanded prompt takes on the terminal.
+/// What's between `\[` and `\]` doesn't count, and neither do escape sequences left outside them.
+pub fn visible_width (prompt: &str) -> usize {
+    let mut width = 0;
+    let mut chars = prompt.chars();
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            START_IGNORE => {
+                for ch in chars.by_ref() {
+                    if ch == END_IGNORE { break; }
+                }
+            }
+            // a CSI sequence ends with its first letter-ish byte, other escapes are two characters
+            '\x1b' => if chars.next() == Some('[') {
+                for ch in chars.by_ref() {
+                    if ('\x40'..='\x7e').contains(&ch) { break; }
+                }
+            },
+            '\n' | '\r' => width = 0,
+            ch if ch.is_control() => {}
+            _ => width += 1,
+        }
+    }
+
+    width
+}
+
+/// The prompt as it's written to the terminal, without the `\[` `\]` markers.
+pub fn printable (prompt: &str) -> String {
+    prompt.chars().filter(|&ch| ch != START_IGNORE && ch != END_IGNORE).collect()
+}
+
+#[cfg(test)]
+mod prompt_tests {
+    use super::*;
+
+    fn info () -> PromptInfo {
+        PromptInfo {
+            user: "nya".to_string(),
+            host: "box.example.org".to_string(),
+            pwd: "/usr/local/src".to_string(),
+            jobs: 2,
+            history: 41,
+            root: false,
+            time: 0,
+            git: "main*".to_string(),
+        }
+    }
+
+    fn expanded (prompt: &str) -> String {
+        expand(prompt, &info(), &mut |expansion| match expansion {
+            Expansion::Variable("?") => "1".to_string(),
+            Expansion::Variable(name) => format!("<{name}>"),
+            Expansion::Command(command) => format!("[{command}]"),
+        })
+    }
+
+    #[test]
+    fn escapes () {
+        assert_eq!(expanded(r"\u@\h:\W\$ "), "nya@box:src$ ");
+        assert_eq!(expanded(r"\H \w \j \! \s \g"), "box.example.org /usr/local/src 2 41 nyash main*");
+        assert_eq!(expanded(r"a\nb\\c\101\q"), "a\nb\\cA\\q");
+        assert_eq!(expanded(r"\[\e[1m\]x"), "\x01\x1b[1m\x02x");
+        assert_eq!(expanded(r"\D{}").len(), utils::format_time("%X", 0).len());
+    }
+
+    #[test]
+    fn expansions () {
+        assert_eq!(expanded("$? ${#name} $USER_1x $(echo \"a)\" (b)) $ $"), "1 <#name> <USER_1x> [echo \"a)\" (b)] $ $");
+        assert_eq!(expanded("$(unclosed"), "$(unclosed");
+
+        // what an expansion gives isn't looked at again
+        let info = PromptInfo { pwd: "/tmp/$HOME".to_string(), ..info() };
+        assert_eq!(expand(r"\W", &info, &mut |_| "home".to_string()), "$HOME");
+    }
+
+    #[test]
+    fn widths () {
+        assert_eq!(visible_width("\x01\x1b[32m\x02user\x01\x1b[0m\x02$ "), 6);
+        assert_eq!(visible_width("\x1b[1;31mred\x1b[0m "), 4);
+        assert_eq!(visible_width("first line\n> "), 2);
+        assert_eq!(printable("\x01\x1b[32m\x02$ "), "\x1b[32m$ ");
+    }
+}
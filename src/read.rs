@@ -0,0 +1,327 @@
+use std::time::{Duration, Instant};
+
+use crate::utils::{disable_raw_mode, enable_raw_mode};
+
+#[derive(Clone, Copy)]
+pub struct ReadOptions {
+    /// `-r`: a backslash is just a backslash
+    pub raw: bool,
+    /// `-s`: typed characters aren't echoed
+    pub silent: bool,
+    /// `-n N`: stop after N characters
+    pub nchars: Option<usize>,
+    pub delimiter: u8,
+    /// `-t`: give up after this long
+    pub timeout: Option<Duration>,
+    pub fd: i32
+}
+
+/// The arguments of `read` once its options are taken out.
+pub struct ReadArgs<'a> {
+    pub options: ReadOptions,
+    pub prompt: Option<&'a str>,
+    /// `-a name`: the fields go into an array
+    pub array: Option<&'a str>,
+    pub names: &'a [&'a str]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ending {
+    Delimiter,
+    /// `-n` characters were read
+    Count,
+    Eof,
+    Timeout,
+    /// Ctrl-C on the terminal
+    Interrupt
+}
+
+/// What `read` got, each byte with whether a backslash escaped it.
+pub struct Input {
+    pub text: Vec<(u8, bool)>,
+    pub ending: Ending
+}
+
+/// Takes the options off the front of `argv`, they can be grouped (`-rs`) and take
+/// their value from the same argument (`-d,`) or the next one.
+pub fn parse_options <'a>(argv: &'a [&'a str]) -> Result<ReadArgs<'a>, String> {
+    let mut args = ReadArgs {
+        options: ReadOptions { raw: false, silent: false, nchars: None, delimiter: b'\n', timeout: None, fd: 0 },
+        prompt: None,
+        array: None,
+        names: &[],
+    };
+
+    let mut idx = 0;
+    while let Some(arg) = argv.get(idx).filter(|arg| arg.len() > 1 && arg.starts_with('-')) {
+        idx += 1;
+        if *arg == "--" { break; }
+
+        for (pos, flag) in arg.char_indices().skip(1) {
+            match flag {
+                'r' => args.options.raw = true,
+                's' => args.options.silent = true,
+                'p' | 'n' | 'd' | 't' | 'a' | 'u' => {
+                    let rest = &arg[pos + 1..];
+                    let value = if !rest.is_empty() {
+                        rest
+                    } else {
+                        idx += 1;
+                        argv.get(idx - 1).ok_or(format!("-{flag}: option requires an argument"))?
+                    };
+
+                    match flag {
+                        'p' => args.prompt = Some(value),
+                        'a' => args.array = Some(value),
+                        // an empty delimiter means NUL
+                        'd' => args.options.delimiter = value.bytes().next().unwrap_or(0),
+                        'n' => args.options.nchars = Some(value.parse().map_err(|_| format!("{value}: invalid number"))?),
+                        // negative, infinite or too far away to ever come isn't a timeout
+                        't' => args.options.timeout = Some(value.parse().ok()
+                            .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                            .filter(|timeout| Instant::now().checked_add(*timeout).is_some())
+                            .ok_or(format!("{value}: invalid timeout specification"))?),
+                        _ => args.options.fd = value.parse().ok()
+                            .filter(|fd: &i32| unsafe { libc::fcntl(*fd, libc::F_GETFD) } >= 0)
+                            .ok_or(format!("{value}: invalid file descriptor specification"))?,
+                    }
+                    break;
+                }
+                flag => return Err(format!("-{flag}: invalid option")),
+            }
+        }
+    }
+
+    args.names = &argv[idx..];
+    Ok(args)
+}
+
+/// Whether `fd` has input waiting (or is at its end), within `timeout` milliseconds.
+pub fn input_ready (fd: i32, timeout: i32) -> bool {
+    let mut pollfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+
+    loop {
+        let ready = unsafe { libc::poll(&mut pollfd, 1, timeout) };
+        if ready < 0 && std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+            continue;
+        }
+        return ready > 0;
+    }
+}
+
+/// Reads one byte, None at the end of the input. One byte at a time so whatever comes
+/// after the delimiter is still there for the next command reading from a pipe.
+fn read_byte (fd: i32, deadline: Option<Instant>) -> Result<Option<u8>, Ending> {
+    if let Some(deadline) = deadline {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if !input_ready(fd, left.as_millis().try_into().unwrap_or(i32::MAX)) {
+            return Err(Ending::Timeout);
+        }
+    }
+
+    let mut byte = 0u8;
+    loop {
+        let n = unsafe { libc::read(fd, (&mut byte as *mut u8).cast(), 1) };
+
+        if n < 0 && std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+            continue;
+        }
+        return Ok((n > 0).then_some(byte));
+    }
+}
+
+fn echo (bytes: &[u8]) {
+    unsafe { libc::write(libc::STDERR_FILENO, bytes.as_ptr().cast(), bytes.len()); }
+}
+
+/// Reads up to the delimiter. On a terminal the characters are taken one by one,
+/// so `-n`, `-s`, `-d` and Ctrl-C work without waiting for a newline.
+pub fn read_input (options: &ReadOptions) -> Input {
+    let deadline = options.timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+
+    let tty = unsafe { libc::isatty(options.fd) } == 1;
+    let original = tty.then(|| enable_raw_mode(options.fd));
+    let shown = |bytes: &[u8]| if tty && !options.silent { echo(bytes) };
+
+    let mut text: Vec<(u8, bool)> = Vec::new();
+    let mut count = 0;
+
+    let ending = loop {
+        if options.nchars.is_some_and(|nchars| count >= nchars) { break Ending::Count; }
+
+        let byte = match read_byte(options.fd, deadline) {
+            Ok(Some(byte)) => byte,
+            Ok(None) => break Ending::Eof,
+            Err(ending) => break ending,
+        };
+
+        if tty {
+            match byte {
+                0x03 => {
+                    echo(b"^C\n");
+                    break Ending::Interrupt;
+                }
+                0x04 if text.is_empty() => break Ending::Eof,
+                0x7F | 0x08 => {
+                    // a character can be several bytes
+                    while text.last().is_some_and(|(byte, _)| byte & 0xC0 == 0x80) { text.pop(); }
+                    if text.pop().is_some() {
+                        count -= 1;
+                        shown(b"\x08 \x08");
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+        }
+
+        if byte == options.delimiter {
+            shown(&[byte]);
+            break Ending::Delimiter;
+        }
+
+        if byte == b'\\' && !options.raw {
+            shown(&[byte]);
+
+            match read_byte(options.fd, deadline) {
+                // a line continuation
+                Ok(Some(b'\n')) => shown(b"\n"),
+                Ok(Some(byte)) => {
+                    shown(&[byte]);
+                    text.push((byte, true));
+                    count += 1;
+                }
+                Ok(None) => break Ending::Eof,
+                Err(ending) => break ending,
+            }
+            continue;
+        }
+
+        text.push((byte, false));
+
+        // the rest of a multibyte character, it counts once
+        let continuation = (byte.leading_ones() as usize).saturating_sub(1).min(3);
+        let mut bytes = vec![byte];
+        for _ in 0..continuation {
+            let Ok(Some(byte)) = read_byte(options.fd, deadline) else { break };
+            text.push((byte, false));
+            bytes.push(byte);
+        }
+
+        shown(&bytes);
+        count += 1;
+    };
+
+    if let Some(original) = original {
+        disable_raw_mode(options.fd, &original);
+    }
+
+    Input { text, ending }
+}
+
+/// Splits `text` on the characters of `ifs`, one field per variable with the last one taking
+/// the rest of the line, or as many fields as there are when `count` is 0.
+/// Whitespace in IFS is trimmed and runs of it separate once, other characters separate each time.
+pub fn split_fields (text: &[(u8, bool)], ifs: &str, count: usize) -> Vec<String> {
+    let is_ifs = |&(byte, escaped): &(u8, bool)| !escaped && ifs.as_bytes().contains(&byte);
+    let is_blank = |entry: &(u8, bool)| is_ifs(entry) && entry.0.is_ascii_whitespace();
+    let string = |entries: &[(u8, bool)]| {
+        String::from_utf8_lossy(&entries.iter().map(|(byte, _)| *byte).collect::<Vec<u8>>()).to_string()
+    };
+
+    if ifs.is_empty() {
+        return vec![string(text)];
+    }
+
+    let mut fields = Vec::new();
+    let mut idx = 0;
+
+    while idx < text.len() && is_blank(&text[idx]) { idx += 1; }
+
+    while idx < text.len() {
+        if count > 0 && fields.len() == count - 1 {
+            let mut end = text.len();
+            while end > idx && is_blank(&text[end - 1]) { end -= 1; }
+
+            fields.push(string(&text[idx..end]));
+            return fields;
+        }
+
+        let start = idx;
+        while idx < text.len() && !is_ifs(&text[idx]) { idx += 1; }
+        fields.push(string(&text[start..idx]));
+
+        // the separator: blanks around at most one other IFS character
+        while idx < text.len() && is_blank(&text[idx]) { idx += 1; }
+        if idx < text.len() && is_ifs(&text[idx]) {
+            idx += 1;
+            while idx < text.len() && is_blank(&text[idx]) { idx += 1; }
+        }
+    }
+
+    fields
+}
+
+#[cfg(test)]
+mod read_tests {
+    use super::*;
+
+    fn split (text: &str, ifs: &str, count: usize) -> Vec<String> {
+        let text: Vec<(u8, bool)> = text.bytes().map(|byte| (byte, false)).collect();
+        split_fields(&text, ifs, count)
+    }
+
+    #[test]
+    fn options () {
+        let argv = ["-rs", "-p", "name? ", "-d,", "-n3", "--", "a", "b"];
+        let args = parse_options(&argv).unwrap();
+
+        assert!(args.options.raw && args.options.silent);
+        assert_eq!(args.prompt, Some("name? "));
+        assert_eq!(args.options.delimiter, b',');
+        assert_eq!(args.options.nchars, Some(3));
+        assert_eq!(args.names, ["a", "b"]);
+
+        assert_eq!(parse_options(&["-d", ""]).unwrap().options.delimiter, 0);
+        assert_eq!(parse_options(&["-t", "x"]).err(), Some("x: invalid timeout specification".to_string()));
+        assert_eq!(parse_options(&["-t1.5"]).unwrap().options.timeout, Some(Duration::from_millis(1500)));
+        for timeout in ["-1", "inf", "NaN", "1e20"] {
+            assert_eq!(parse_options(&["-t", timeout]).err(), Some(format!("{timeout}: invalid timeout specification")));
+        }
+        assert_eq!(parse_options(&["-u"]).err(), Some("-u: option requires an argument".to_string()));
+        assert_eq!(parse_options(&["-q"]).err(), Some("-q: invalid option".to_string()));
+    }
+
+    #[test]
+    fn field_splitting () {
+        assert_eq!(split("  one  two   three four  ", " \t\n", 3), vec!["one", "two", "three four"]);
+        assert_eq!(split("one", " \t\n", 3), vec!["one"]);
+        assert_eq!(split("a,,b , c", ",", 0), vec!["a", "", "b ", " c"]);
+        assert_eq!(split("a : b:c", " :", 0), vec!["a", "b", "c"]);
+        assert_eq!(split("  keep  it ", "", 2), vec!["  keep  it "]);
+
+        let escaped: Vec<(u8, bool)> = vec![(b'a', false), (b' ', true), (b'b', false), (b' ', false), (b'c', false)];
+        assert_eq!(split_fields(&escaped, " ", 0), vec!["a b", "c"]);
+    }
+
+    #[test]
+    fn reads_up_to_the_delimiter () {
+        let mut fds = [0; 2];
+        unsafe { libc::pipe(fds.as_mut_ptr()); }
+        let data = b"first\\ line \\\ngoes on\nsecond";
+        unsafe { libc::write(fds[1], data.as_ptr().cast(), data.len()); libc::close(fds[1]); }
+
+        let options = ReadOptions { raw: false, silent: false, nchars: None, delimiter: b'\n', timeout: None, fd: fds[0] };
+        let input = read_input(&options);
+        assert_eq!(input.ending, Ending::Delimiter);
+        assert_eq!(split_fields(&input.text, " ", 0), vec!["first line", "goes", "on"]);
+
+        let input = read_input(&ReadOptions { nchars: Some(3), ..options });
+        assert_eq!((input.text.len(), input.ending), (3, Ending::Count));
+
+        let input = read_input(&ReadOptions { nchars: None, ..options });
+        assert_eq!((input.text.len(), input.ending), (3, Ending::Eof));
+
+        unsafe { libc::close(fds[0]); }
+    }
+}
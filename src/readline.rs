@@ -1,112 +1,852 @@
+use std::collections::HashSet;
+use std::env::split_paths;
 use std::io::Write;
 use std::io::stdout;
-use bytes::BufMut;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
 
+use crate::dirs;
+use crate::highlight::{self, Colours, Kind};
+use crate::jobs;
+use crate::jump;
+use crate::parser;
+use crate::prompt;
+use crate::read;
+use crate::signals;
 use crate::utils;
 use crate::utils::{disable_raw_mode, enable_raw_mode};
+use crate::vi::{self, Action, Mode, Vi};
 
 use crate::trie::Trie;
 
+// the cursor shows the vi mode: a bar while inserting, a block otherwise
+const INSERT_CURSOR: &str = "\x1b[6 q";
+const NORMAL_CURSOR: &str = "\x1b[2 q";
+const DEFAULT_CURSOR: &str = "\x1b[0 q";
+
+/// The line being edited as it is on the terminal. Changes to the input are drawn by
+/// rewriting only what differs from what's there, positions are worked out from the
+/// terminal width so the input can wrap over as many rows as it needs.
+struct Screen {
+    prompt: String,
+    // the columns the prompt's last line takes, and the rows of it above that one
+    width: usize,
+    rows_above: usize,
+    right: String,
+    right_width: usize,
+    right_shown: bool,
+    columns: usize,
+    colours: Option<Colours>,
+    // the input as it was last drawn, with the kind of each character, and where the
+    // terminal's cursor is in it
+    drawn: Vec<char>,
+    kinds: Vec<Kind>,
+    cursor: usize,
+    out: String
+}
+
+impl Screen {
+    /// `prompt` and `right` are expanded prompts, their `\[` `\]` parts take no room.
+    /// The input is drawn in `colours`, if there are any.
+    fn new (prompt: &str, right: &str, columns: usize, colours: Option<Colours>) -> Self {
+        let mut screen = Self {
+            prompt: prompt::printable(prompt),
+            width: 0,
+            rows_above: 0,
+            right: prompt::printable(right),
+            right_width: prompt::visible_width(right),
+            right_shown: false,
+            columns,
+            colours,
+            drawn: Vec::new(),
+            kinds: Vec::new(),
+            cursor: 0,
+            out: String::new(),
+        };
+        screen.measure(prompt);
+        screen
+    }
+
+    fn measure (&mut self, prompt: &str) {
+        let mut lines: Vec<&str> = prompt.split('\n').collect();
+        let last = lines.pop().unwrap_or_default();
+
+        // lines of the prompt wider than the terminal wrap too
+        self.width = prompt::visible_width(last);
+        self.rows_above = lines.iter().map(|line| prompt::visible_width(line).max(1).div_ceil(self.columns)).sum();
+    }
+
+    /// The row (counted from the prompt's last line) and column of the input's `idx`th character.
+    fn position (&self, idx: usize) -> (usize, usize) {
+        ((self.width + idx) / self.columns, (self.width + idx) % self.columns)
+    }
+
+    /// Moves the cursor to before the input's `idx`th character.
+    fn move_to (&mut self, idx: usize) {
+        let (from_row, from_col) = self.position(self.cursor);
+        let (row, col) = self.position(idx);
+
+        if row < from_row { self.out.push_str(&format!("\x1b[{}A", from_row - row)); }
+        if row > from_row { self.out.push_str(&format!("\x1b[{}B", row - from_row)); }
+
+        if col < from_col {
+            self.out.push_str(&format!("\x1b[{}D", from_col - col));
+        } else if col > from_col {
+            self.out.push_str(&format!("\x1b[{}C", col - from_col));
+        }
+
+        self.cursor = idx;
+    }
+
+    /// Writes `text` from `from` on, each character in the colour of its kind. Without `kinds`
+    /// it's all plain.
+    fn write (&mut self, text: &[char], kinds: &[Kind], from: usize) {
+        let mut current = Kind::Plain;
+
+        for (idx, &ch) in text.iter().enumerate().skip(from) {
+            let kind = kinds.get(idx).copied().unwrap_or(Kind::Plain);
+
+            if kind != current {
+                self.out.push_str("\x1b[0m");
+                if let Some(colours) = &self.colours { self.out.push_str(&colours.start(kind)); }
+                current = kind;
+            }
+            self.out.push(ch);
+        }
+
+        if current != Kind::Plain { self.out.push_str("\x1b[0m"); }
+    }
+
+    /// Draws the prompt and `text`, as if nothing was there.
+    fn draw (&mut self, text: &[char], kinds: &[Kind], cursor: usize) {
+        self.out.push_str(&self.prompt.clone());
+        self.drawn.clear();
+        self.kinds.clear();
+        self.cursor = 0;
+        self.right_shown = false;
+
+        // a prompt that fills its last line leaves the cursor waiting to wrap
+        if self.width > 0 && self.width % self.columns == 0 { self.out.push_str("\r\n"); }
+
+        self.update(text, kinds, cursor);
+    }
+
+    /// Brings the terminal from what was drawn to `text` with the cursor before `cursor`.
+    /// A character that's there already is written again if its kind changed.
+    fn update (&mut self, text: &[char], kinds: &[Kind], cursor: usize) {
+        let kind = |kinds: &[Kind], idx: usize| kinds.get(idx).copied().unwrap_or(Kind::Plain);
+        let mut same = self.drawn.iter().zip(text).enumerate()
+            .take_while(|&(idx, (a, b))| a == b && kind(&self.kinds, idx) == kind(kinds, idx))
+            .count();
+
+        // the right prompt stays as long as the input is on its row with a space left before it
+        let right_fits = !self.right.is_empty() && self.width + text.len() < self.columns
+            && self.width + text.len() + 1 + self.right_width <= self.columns;
+
+        if self.right_shown && !right_fits {
+            // cleared from where it starts, and the input that was there is drawn again
+            let start = self.columns - self.right_width;
+            self.move_to(0);
+            self.out.push_str(&format!("\x1b7\x1b[{}G\x1b[K\x1b8", start + 1));
+            self.right_shown = false;
+            same = same.min(start.saturating_sub(self.width));
+        }
+
+        if same < text.len() || same < self.drawn.len() {
+            self.move_to(same);
+            self.write(text, kinds, same);
+            self.cursor = text.len();
+
+            // the cursor goes to the next row as soon as one is full, so it's never stuck in the
+            // last column where it isn't clear which side of the wrap it is on
+            if text.len() > same && (self.width + text.len()) % self.columns == 0 {
+                self.out.push_str("\r\n");
+            }
+
+            // what's left of a longer input goes, blanked out if it's all on one row so
+            // the right prompt can stay
+            if self.drawn.len() > text.len() {
+                let extra = self.drawn.len() - text.len();
+
+                if self.width + self.drawn.len() < self.columns {
+                    self.out.push_str(&" ".repeat(extra));
+                    self.cursor += extra;
+                } else {
+                    self.out.push_str("\x1b[J");
+                    if self.position(text.len()).0 == 0 { self.right_shown = false; }
+                }
+            }
+        }
+
+        self.drawn = text.to_vec();
+        self.kinds = kinds.to_vec();
+        self.move_to(cursor);
+
+        if right_fits && !self.right_shown {
+            self.out.push_str(&format!("\x1b7\x1b[{}G{}\x1b8", self.columns - self.right_width + 1, self.right));
+            self.right_shown = true;
+        }
+    }
+
+    /// Moves to the row after the input, what's printed next starts on its own line.
+    fn leave (&mut self) {
+        self.move_to(self.drawn.len());
+
+        let end = self.width + self.drawn.len();
+        if end == 0 || end % self.columns != 0 {
+            self.out.push_str("\r\n");
+        }
+    }
+
+    /// Goes back to where the prompt starts and clears everything from there.
+    fn erase (&mut self) {
+        self.move_to(0);
+        let rows = self.rows_above + self.position(0).0;
+        if rows > 0 { self.out.push_str(&format!("\x1b[{rows}A")); }
+
+        self.out.push_str("\r\x1b[J");
+    }
+
+    /// The terminal is `columns` wide now. Where the cursor ended up is worked out with the old
+    /// width, the terminal may have rewrapped the rows since, but that's as good as it gets.
+    fn resize (&mut self, columns: usize, prompt: &str) {
+        let (text, kinds, cursor) = (self.drawn.clone(), self.kinds.clone(), self.cursor);
+
+        self.erase();
+        self.columns = columns;
+        self.measure(prompt);
+        self.draw(&text, &kinds, cursor);
+    }
+
+    fn flush (&mut self) {
+        let _ = stdout().write_all(self.out.as_bytes());
+        let _ = stdout().flush();
+        self.out.clear();
+    }
+}
+
+/// The prompts of a command, expanded.
+pub struct Prompts {
+    /// $PS1
+    pub primary: String,
+    /// $PS2, for the lines after the first one of an incomplete command
+    pub continuation: String,
+    /// $RPROMPT, right-aligned on the first line
+    pub right: String,
+    /// $TRANSIENT_PROMPT, what the first line's prompt turns into once it's entered
+    pub transient: Option<String>
+}
+
+/// A `PATH` directory as it was when we last listed it.
+struct PathDir {
+    dir: PathBuf,
+    mtime: Option<i64>,
+    entries: Vec<String>
+}
+
 pub struct Reader {
-    command_tree: Trie
+    command_tree: Trie,
+    // builtins and functions, these stay completable whatever PATH says
+    shell_words: HashSet<String>,
+    aliases: HashSet<String>,
+    binaries: HashSet<String>,
+    path: Option<String>,
+    path_dirs: Vec<PathDir>,
+    colours: Option<Colours>,
+    // `set -o vi`, and the entries its `k`, `j` and `/` go through
+    vi: bool,
+    history: Vec<String>
+}
+
+// escape sequences only make sense on a terminal
+fn stdout_is_tty () -> bool {
+    unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 }
 }
 
 impl Reader {
     pub fn new () -> Self {
-        Self { command_tree: Trie::new() }
+        Self {
+            command_tree: Trie::new(),
+            shell_words: HashSet::new(),
+            aliases: HashSet::new(),
+            binaries: HashSet::new(),
+            path: None,
+            path_dirs: Vec::new(),
+            colours: stdout_is_tty().then(Colours::default),
+            vi: false,
+            history: Vec::new()
+        }
     }
 
-    pub fn update_trie <T>(&mut self, words: &Vec<T>) 
+    pub fn update_trie <T>(&mut self, words: &[T]) 
     where
         T: AsRef<str>
     {
         for word in words {
+            self.shell_words.insert(word.as_ref().to_string());
             self.command_tree.insert(word.as_ref());
         }
     }
 
-    pub fn read_line (&self, prompt: &str) -> String {
+    /// Makes the completion trie follow `alias` and `unalias`.
+    pub fn update_aliases <'a>(&mut self, names: impl Iterator<Item = &'a String>) {
+        let aliases: HashSet<String> = names.cloned().collect();
+        if aliases == self.aliases { return; }
+
+        for gone in self.aliases.difference(&aliases) {
+            if !self.shell_words.contains(gone) && !self.binaries.contains(gone) {
+                self.command_tree.remove(gone);
+            }
+        }
+
+        for new in aliases.difference(&self.aliases) {
+            self.command_tree.insert(new);
+        }
+
+        self.aliases = aliases;
+    }
+
+    /// Brings the PATH binaries in the completion trie up to date.
+    /// Only the directories whose mtime changed are listed again, unless PATH itself changed.
+    pub fn refresh_binaries (&mut self) {
+        let path = utils::get_environment("PATH").unwrap_or("").to_string();
+        let mut changed = false;
+
+        if self.path.as_ref() != Some(&path) {
+            self.path_dirs = split_paths(&path)
+                .map(|dir| PathDir { dir, mtime: None, entries: Vec::new() })
+                .collect();
+            self.path = Some(path);
+            changed = true;
+
+            for dir in &mut self.path_dirs {
+                dir.mtime = utils::get_mtime(&dir.dir);
+                dir.entries = utils::get_directory_binaries(&dir.dir);
+            }
+        } else {
+            for dir in &mut self.path_dirs {
+                let mtime = utils::get_mtime(&dir.dir);
+
+                if mtime != dir.mtime {
+                    dir.mtime = mtime;
+                    dir.entries = utils::get_directory_binaries(&dir.dir);
+                    changed = true;
+                }
+            }
+        }
+
+        if !changed { return; }
+
+        let binaries: HashSet<String> = self.path_dirs.iter()
+            .flat_map(|dir| dir.entries.iter().cloned())
+            .collect();
+
+        for gone in self.binaries.difference(&binaries) {
+            if !self.shell_words.contains(gone) && !self.aliases.contains(gone) {
+                self.command_tree.remove(gone);
+            }
+        }
+
+        for new in binaries.difference(&self.binaries) {
+            self.command_tree.insert(new);
+        }
+
+        self.binaries = binaries;
+    }
+
+    /// Takes the colours of the input from $NYASH_HIGHLIGHT, the default ones when it isn't set.
+    /// Nothing is coloured when the output isn't a terminal.
+    pub fn set_colours (&mut self, spec: Option<&str>) {
+        if !stdout_is_tty() {
+            self.colours = None;
+            return;
+        }
+        self.colours = spec.map_or_else(|| Some(Colours::default()), Colours::parse);
+    }
+
+    /// Reads lines with the vi keys, or the default ones.
+    pub fn set_vi_mode (&mut self, vi: bool) {
+        self.vi = vi;
+    }
+
+    pub fn set_history (&mut self, history: Vec<String>) {
+        self.history = history;
+    }
+
+    /// The kind of each character of `line`, nothing when it's not coloured.
+    fn highlight (&self, before: &str, line: &[char]) -> Vec<Kind> {
+        if self.colours.is_none() { return Vec::new(); }
+
+        let line: String = line.iter().collect();
+        highlight::highlight(before, &line, &|name| {
+            self.shell_words.contains(name) || self.aliases.contains(name) || self.binaries.contains(name)
+        })
+    }
+
+    /// Where the word being completed starts in `input`, and what it can be.
+    /// The first word is a command, the argument of `cd`, `pushd` and `j` a directory: one
+    /// under the typed path if there's any, otherwise the `j` directories with the word in them.
+    fn completions (&self, input: &str) -> (usize, Vec<String>) {
+        let Some((command, word)) = input.rsplit_once(' ') else {
+            let mut commands = self.command_tree.with_prefix(input);
+            commands.sort();
+            return (0, commands);
+        };
+
+        let start = input.len() - word.len();
+        if !matches!(command.trim_start(), "cd" | "pushd" | "j") {
+            return (start, Vec::new());
+        }
+
+        let dirs = dirs::complete_dir(word);
+        if !dirs.is_empty() || word.is_empty() {
+            return (start, dirs);
+        }
+
+        let Some(file) = jump::database_path() else { return (start, dirs) };
+        let db = jump::Database::load(&file);
+        let jumps = db.matches(&[word], jump::now()).into_iter()
+            .map(|(_, path)| path)
+            .filter(|path| dirs::is_directory(path))
+            .map(|path| format!("{path}/"))
+            .collect();
+
+        (start, jumps)
+    }
+
+    /// Reads a whole command, prompting with the continuation prompt for as long as it's incomplete.
+    pub fn read_command (&self, prompts: &Prompts) -> Option<String> {
+        let mut input = self.read_line(&prompts.primary, &prompts.right, prompts.transient.as_deref(), "")?;
+
+        parser::complete_input(&mut input, &mut |before| self.read_line(&prompts.continuation, "", None, before));
+
+        Some(input)
+    }
+
+    /// Reads a line of input, None once the input is exhausted (or Ctrl-D on an empty line).
+    /// The prompts are expanded ones, what's between their `\[` `\]` markers takes no room.
+    /// `right` is shown at the end of the line until the input gets too close to it, and
+    /// `transient` takes the place of `prompt` once the line is entered. The line is coloured as
+    /// the part of the command that comes after `previous`, the lines of it entered already.
+    pub fn read_line (&self, prompt: &str, right: &str, transient: Option<&str>, previous: &str) -> Option<String> {
         const STDIN_D: i32 = 0;
 
         let original = enable_raw_mode(STDIN_D);
-        let _ = write!(stdout(), "{prompt}");
-        let _ = stdout().flush();
+        let mut screen = Screen::new(prompt, right, utils::terminal_columns(libc::STDOUT_FILENO), self.colours.clone());
 
+        let mut text: Vec<char> = Vec::new();
+        let mut cursor = 0;
+        // the bytes of a character that isn't complete yet
+        let mut pending: Vec<u8> = Vec::new();
+        // a key typed right after ESC, in vi mode it's a key of its own
+        let mut queued: Option<char> = None;
         let mut bell = false;
 
-        let mut buf = [0u8, 1];
-        let mut input = Vec::<u8>::new();
+        let mut vi = self.vi.then(Vi::new);
+        // where `k` and `j` got to in the history, and the line typed before going there
+        let mut entry = self.history.len();
+        let mut typed: Vec<char> = Vec::new();
+
+        if vi.is_some() { screen.out.push_str(INSERT_CURSOR); }
+        screen.draw(&text, &[], cursor);
+        screen.flush();
 
         loop {
-            let n = unsafe { libc::read(STDIN_D, buf.as_mut_ptr().cast(), 1) };
+            let (key, escape) = match queued.take() {
+                Some(key) => (key, None),
+                None => {
+                    let byte = match read_key_byte(STDIN_D) {
+                        Ok(Some(byte)) => byte,
+                        Ok(None) if text.is_empty() => {
+                            if vi.is_some() { print!("{DEFAULT_CURSOR}"); }
+                            disable_raw_mode(STDIN_D, &original);
+                            return None;
+                        }
+                        Ok(None) => break,
+                        Err(()) => {
+                            if signals::take_resized() {
+                                screen.resize(utils::terminal_columns(libc::STDOUT_FILENO), prompt);
+                                screen.flush();
+                            }
+                            continue;
+                        }
+                    };
+
+                    // a multibyte character goes in once all of it is there
+                    pending.push(byte);
+                    let expected = if pending[0] >= 0xC0 { pending[0].leading_ones() as usize } else { 1 };
+                    if pending.len() < expected.min(4) { continue; }
+
+                    let key = String::from_utf8_lossy(&pending).chars().next().unwrap_or(char::REPLACEMENT_CHARACTER);
+                    pending.clear();
+
+                    let escape = if key == '\x1b' { read_escape(STDIN_D) } else { None };
+                    (key, escape)
+                }
+            };
+
+            if key != '\t' { bell = false; }
+
+            if let Some(vi) = vi.as_mut() {
+                let mode = vi.mode;
+
+                // only the arrows and the like are escape sequences, ESC and a key are two keys
+                let action = match escape.as_deref() {
+                    Some(sequence) if sequence.starts_with(['[', 'O']) => Action::Pass,
+                    Some(sequence) => {
+                        queued = sequence.chars().next();
+                        vi.key(key, &mut text, &mut cursor)
+                    }
+                    None => vi.key(key, &mut text, &mut cursor),
+                };
 
-            if n <= 0 {
-                break;
+                match action {
+                    Action::Pass | Action::Done => {}
+                    Action::Bell => screen.out.push('\x07'),
+                    Action::History(_) | Action::Search(..) => match history_target(&self.history, entry, &action) {
+                        Some(to) => {
+                            if entry == self.history.len() { typed = text.clone(); }
+                            entry = to;
+                            text = self.history.get(to).map_or_else(|| typed.clone(), |line| line.chars().collect());
+                            cursor = 0;
+                        }
+                        None => screen.out.push('\x07'),
+                    },
+                    Action::Edit => {
+                        screen.out.push_str(DEFAULT_CURSOR);
+                        screen.leave();
+                        screen.flush();
+                        disable_raw_mode(STDIN_D, &original);
+
+                        // what the editor saved runs right away, it's shown first like it was typed
+                        let line: String = text.iter().collect();
+                        if let Some(edited) = edit_in_editor(&line) {
+                            println!("{edited}");
+                            return Some(edited);
+                        }
+
+                        enable_raw_mode(STDIN_D);
+                        screen.out.push_str(INSERT_CURSOR);
+                        vi.mode = Mode::Insert;
+                        screen.draw(&text, &self.highlight(previous, &text), cursor);
+                    }
+                }
+
+                if vi.mode != mode {
+                    screen.out.push_str(if vi.mode == Mode::Normal { NORMAL_CURSOR } else { INSERT_CURSOR });
+                }
+
+                if action != Action::Pass {
+                    self.redraw(&mut screen, Some(vi), &text, cursor, previous);
+                    continue;
+                }
             }
 
-            match buf[0] {
-                b'\n' | b'\r' => {
-                    print!("\n");
+            match key {
+                // Ctrl-C throws the current line away
+                '\x03' => {
+                    screen.move_to(text.len());
+                    screen.out.push_str("^C");
+                    screen.leave();
+
+                    text.clear();
+                    cursor = 0;
+                    screen.draw(&text, &[], cursor);
+
+                    if let Some(vi) = vi.as_mut() {
+                        *vi = Vi::new();
+                        screen.out.push_str(INSERT_CURSOR);
+                    }
+                }
+                '\x04' => {
+                    if text.is_empty() {
+                        if vi.is_some() { print!("{DEFAULT_CURSOR}"); }
+                        disable_raw_mode(STDIN_D, &original);
+                        return None;
+                    }
+                    if cursor < text.len() { text.remove(cursor); }
+                }
+                // Ctrl-Z and Ctrl-\ have nothing to stop or quit here
+                '\x1A' | '\x1C' => {}
+                '\n' | '\r' => {
+                    // the line again, after the short prompt
+                    if let Some(transient) = transient {
+                        screen.erase();
+                        screen.out.push_str(&prompt::printable(transient));
+                        screen.write(&text, &self.highlight(previous, &text), 0);
+                        screen.out.push_str("\r\n");
+                    } else {
+                        screen.leave();
+                    }
+
+                    if vi.is_some() { screen.out.push_str(DEFAULT_CURSOR); }
+                    screen.flush();
                     break;
                 }
-                0x7F => {
-                    if !input.is_empty() {
-                        input.pop();
-                        print!("\x08 \x08");
-                        let _ = stdout().flush();
+                '\x7F' | '\x08' => {
+                    if cursor > 0 {
+                        cursor -= 1;
+                        text.remove(cursor);
                     }
                 }
-                b'\t' => {
-                        let inp = String::from_utf8_lossy(&input).to_string();
-                        let mut completions = self.command_tree.with_prefix(&inp);
-                        completions.sort();
-
-                        if completions.len() == 1 {
-                            let out = &completions[0][inp.len()..];
-                            input.put_slice(out.as_bytes());
-                            input.push(b' ');
-
-                            print!("{} ", out);
-                        } else if completions.len() > 1 {
-                            if bell {
-                                print!("\n");
-                                for comp in completions {
-                                    print!("{comp}  ");
-                                }
-                                print!("\n");
-                                
-                                print!("{prompt}");
-                                print!("{}", inp);
-                            } else {
-                                let lcp = utils::longest_common_prefix(&inp, &completions);
+                '\x01' => cursor = 0,
+                '\x05' => cursor = text.len(),
+                '\x02' => cursor = cursor.saturating_sub(1),
+                '\x06' => cursor = (cursor + 1).min(text.len()),
+                '\x0B' => text.truncate(cursor),
+                '\x15' => {
+                    text.drain(..cursor);
+                    cursor = 0;
+                }
+                // Ctrl-L starts again at the top of a clear screen
+                '\x0C' => {
+                    screen.out.push_str("\x1b[H\x1b[2J");
+                    screen.draw(&text, &self.highlight(previous, &text), cursor);
+                }
+                '\x1b' => match escape.as_deref() {
+                    Some("[D" | "OD") => cursor = cursor.saturating_sub(1),
+                    Some("[C" | "OC") => cursor = (cursor + 1).min(text.len()),
+                    Some("[H" | "OH" | "[1~" | "[7~") => cursor = 0,
+                    Some("[F" | "OF" | "[4~" | "[8~") => cursor = text.len(),
+                    Some("[3~") if cursor < text.len() => { text.remove(cursor); }
+                    _ => {}
+                },
+                '\t' => {
+                    let before: String = text[..cursor].iter().collect();
+                    let (start, completions) = self.completions(&before);
+                    let word = &before[start..];
 
-                                if lcp == inp {
-                                    bell = true;
-                                    print!("\x07");
-                                } else {
-                                    let out = &lcp[inp.len()..];
-                                    input.put_slice(out.as_bytes());
+                    let mut insert = |replaced: usize, new: &str| {
+                        text.splice(cursor - replaced..cursor, new.chars());
+                        cursor = cursor - replaced + new.chars().count();
+                    };
 
-                                    print!("{}", out);
-                                }
+                    if completions.first().is_some_and(|comp| !comp.starts_with(word)) {
+                        // a `j` directory, the word is only part of it so it's typed over
+                        insert(word.chars().count(), &completions[0]);
+                    } else if completions.len() == 1 {
+                        let out = &completions[0][word.len()..];
+
+                        // a directory can go on with what's in it
+                        if out.ends_with('/') {
+                            insert(0, out);
+                        } else {
+                            insert(0, &format!("{out} "));
+                        }
+                    } else if completions.len() > 1 {
+                        if bell {
+                            screen.leave();
+                            for comp in completions {
+                                screen.out.push_str(&format!("{comp}  "));
                             }
+                            screen.out.push_str("\r\n");
+                            screen.draw(&text, &self.highlight(previous, &text), cursor);
                         } else {
-                            print!("\x07");
+                            let lcp = utils::longest_common_prefix(word, &completions);
+
+                            if lcp == word {
+                                bell = true;
+                                screen.out.push('\x07');
+                            } else {
+                                insert(0, &lcp[word.len()..]);
+                            }
                         }
-                    
-                    let _ = stdout().flush();
-                    
+                    } else {
+                        screen.out.push('\x07');
+                    }
                 }
-                byte => {
-                    input.push(byte);
-                    print!("{}", byte as char);
-                    let _ = stdout().flush();
+                key if key.is_control() => {}
+                key => {
+                    text.insert(cursor, key);
+                    cursor += 1;
                 }
             }
+
+            // End, ^E and the like can't leave normal mode's cursor after the line
+            if vi.as_ref().is_some_and(|vi| vi.mode == Mode::Normal) {
+                cursor = vi::clamp(&text, cursor);
+            }
+
+            self.redraw(&mut screen, vi.as_ref(), &text, cursor, previous);
         }
 
         disable_raw_mode(STDIN_D, &original);
-        String::from_utf8_lossy(&input).to_string()
+        Some(text.into_iter().collect())
+    }
+
+    /// Brings the screen up to date with the line, or with the `/` search while one is typed.
+    fn redraw (&self, screen: &mut Screen, vi: Option<&Vi>, text: &[char], cursor: usize, previous: &str) {
+        match vi.and_then(Vi::search_text) {
+            Some(search) => {
+                let shown: Vec<char> = format!("/{search}").chars().collect();
+                screen.update(&shown, &[], shown.len());
+            }
+            None => screen.update(text, &self.highlight(previous, text), cursor),
+        }
+        screen.flush();
+    }
+}
+
+/// Where `k`, `j` or a `/` search lands in `history` from `entry`, the line being typed is one
+/// past the last entry. None if there's nothing there.
+fn history_target (history: &[String], entry: usize, action: &Action) -> Option<usize> {
+    match action {
+        Action::History(steps) => entry.checked_add_signed(-steps).filter(|&to| to <= history.len()),
+        Action::Search(search, true) => (0..entry).rev().find(|&idx| history[idx].contains(search.as_str())),
+        Action::Search(search, false) => (entry + 1..history.len()).find(|&idx| history[idx].contains(search.as_str())),
+        _ => None,
+    }
+}
+
+/// Writes `line` to a new file in $TMPDIR for the editor, returning its path. mkstemps makes it
+/// only readable by us and never opens what someone else left at the path, like a symlink.
+fn temp_script (line: &str) -> Option<String> {
+    let dir = utils::get_environment("TMPDIR").unwrap_or("/tmp");
+    let template = std::ffi::CString::new(format!("{dir}/nyash-edit-XXXXXX.sh")).ok()?.into_raw();
+
+    let fd = unsafe { libc::mkstemps(template, 3) };
+    let path = unsafe { std::ffi::CString::from_raw(template) }.into_string().ok()?;
+    if fd < 0 { return None; }
+
+    let mut file = unsafe { <std::fs::File as std::os::fd::FromRawFd>::from_raw_fd(fd) };
+    if writeln!(file, "{line}").is_err() {
+        let _ = std::fs::remove_file(&path);
+        return None;
+    }
+
+    Some(path)
+}
+
+/// Lets $VISUAL or $EDITOR (vi if neither is set) change `line`, what it saves is the new line.
+/// None if it couldn't be run or it failed.
+fn edit_in_editor (line: &str) -> Option<String> {
+    let editor = utils::get_environment("VISUAL").or_else(|| utils::get_environment("EDITOR")).unwrap_or("vi");
+    let file = temp_script(line)?;
+
+    let mut words = editor.split_whitespace();
+    let mut command = std::process::Command::new(words.next()?);
+    command.args(words).arg(&file);
+
+    // the editor shouldn't ignore Ctrl-C and Ctrl-Z like the shell does
+    unsafe {
+        command.pre_exec(|| {
+            signals::reset_for_child(&[]);
+            Ok(())
+        });
+    }
+
+    let status = command.spawn().ok().and_then(|child| jobs::wait_child(child.id() as i32));
+    let edited = std::fs::read_to_string(&file);
+    let _ = std::fs::remove_file(&file);
+
+    if status != Some(0) { return None; }
+    Some(edited.ok()?.trim_end_matches('\n').to_string())
+}
+
+/// The next byte from the terminal, None at the end of the input. Err when a signal
+/// came in first, a resize has to be drawn before waiting again.
+fn read_key_byte (fd: i32) -> Result<Option<u8>, ()> {
+    let mut byte = 0u8;
+    let n = unsafe { libc::read(fd, (&mut byte as *mut u8).cast(), 1) };
+
+    if n < 0 && std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+        return Err(());
+    }
+
+    Ok((n > 0).then_some(byte))
+}
+
+/// What follows an ESC, like `[D` for the left arrow. Keys send the whole sequence at once,
+/// an ESC with nothing right behind it was the Escape key itself (None).
+fn read_escape (fd: i32) -> Option<String> {
+    let next = || loop {
+        if !read::input_ready(fd, 50) { return None; }
+
+        match read_key_byte(fd) {
+            Ok(byte) => return byte,
+            Err(()) => continue,
+        }
+    };
+
+    let mut sequence = String::from(next()? as char);
+    if sequence != "[" && sequence != "O" { return Some(sequence); }
+
+    // parameters, up to the final byte
+    loop {
+        let byte = next()?;
+        sequence.push(byte as char);
+        if (0x40..=0x7E).contains(&byte) { return Some(sequence); }
+    }
+}
+
+#[cfg(test)]
+mod screen_tests {
+    use super::*;
+
+    fn chars (text: &str) -> Vec<char> {
+        text.chars().collect()
+    }
+
+    fn drawn (prompt: &str, text: &str, columns: usize) -> Screen {
+        let mut screen = Screen::new(prompt, "", columns, None);
+        screen.draw(&chars(text), &[], text.len());
+        screen.out.clear();
+        screen
+    }
+
+    #[test]
+    fn typing () {
+        let mut screen = drawn("$ ", "ech", 10);
+        screen.update(&chars("echo"), &[], 4);
+        assert_eq!(screen.out, "o");
+
+        // filling the row moves on to the next one
+        let mut screen = drawn("$ ", "echo hi", 10);
+        screen.update(&chars("echo hi!"), &[], 8);
+        assert_eq!(screen.out, "!\r\n");
+    }
+
+    #[test]
+    fn editing () {
+        // only what follows the change is written again
+        let mut screen = drawn("$ ", "echo hi", 20);
+        screen.update(&chars("echo  hi"), &[], 5);
+        assert_eq!(screen.out, "\x1b[2D hi\x1b[3D");
+
+        let mut screen = drawn("$ ", "echo hi", 20);
+        screen.update(&chars("echo"), &[], 4);
+        assert_eq!(screen.out, "\x1b[3D   \x1b[3D");
+
+        // over several rows, what's below goes with a single clear
+        let mut screen = drawn("$ ", "echo abcdefghij", 6);
+        assert_eq!(screen.position(15), (2, 5));
+        screen.update(&chars("echo a"), &[], 6);
+        assert_eq!(screen.out, "\x1b[1A\x1b[3D\x1b[J");
+    }
+
+    #[test]
+    fn colours () {
+        let mut screen = Screen::new("$ ", "", 20, Some(Colours::default()));
+        screen.draw(&chars("ech"), &[Kind::Unknown; 3], 3);
+        assert!(screen.out.ends_with("\x1b[0m\x1b[31mech\x1b[0m"));
+
+        // the word is the same but it's a command now, so all of it goes again
+        screen.out.clear();
+        screen.update(&chars("echo"), &[Kind::Command; 4], 4);
+        assert_eq!(screen.out, "\x1b[3D\x1b[0m\x1b[32mecho\x1b[0m");
+    }
+
+    #[test]
+    fn right_prompt () {
+        let mut screen = Screen::new("$ ", "\x01\x1b[2m\x02[r]\x01\x1b[0m\x02", 12, None);
+        screen.draw(&chars("ls"), &[], 2);
+        assert!(screen.right_shown);
+        assert!(screen.out.ends_with("\x1b7\x1b[10G\x1b[2m[r]\x1b[0m\x1b8"));
+
+        screen.out.clear();
+        screen.update(&chars("ls -la ~"), &[], 8);
+        assert!(!screen.right_shown);
+        assert!(screen.out.contains("\x1b[10G\x1b[K"));
     }
 }
 
@@ -118,8 +858,24 @@ mod io_tests {
     fn readline () {
         let reader = Reader::new();
 
-        let input = reader.read_line("Hey: ");
+        let input = reader.read_line("Hey: ", "", None, "");
 
-        print!("{input}");
+        print!("{}", input.unwrap_or_default());
+    }
+
+    #[test]
+    fn temp_scripts () {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (first, second) = (temp_script("echo hi").unwrap(), temp_script("echo hi").unwrap());
+        assert_ne!(first, second);
+        assert!(first.contains("/nyash-edit-") && first.ends_with(".sh"), "{first}");
+
+        assert_eq!(std::fs::read_to_string(&first).unwrap(), "echo hi\n");
+        assert_eq!(std::fs::metadata(&first).unwrap().permissions().mode() & 0o777, 0o600);
+
+        for path in [first, second] {
+            std::fs::remove_file(path).unwrap();
+        }
     }
 }
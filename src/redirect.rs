@@ -0,0 +1,358 @@
+use std::ffi::CString;
+
+use libc::{ O_RDONLY, O_WRONLY, O_RDWR, O_CREAT, O_TRUNC, O_APPEND, O_EXCL };
+
+use crate::utils;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RedirectOp {
+    /// `<`
+    Read,
+    /// `>`, refuses to overwrite a file with `noclobber`
+    Write,
+    /// `>|`
+    Clobber,
+    /// `>>`
+    Append,
+    /// `<>`
+    ReadWrite,
+    /// `<&`
+    DupIn,
+    /// `>&`
+    DupOut,
+    /// `&>`
+    WriteAll,
+    /// `&>>`
+    AppendAll,
+    /// `<<`
+    HereDoc,
+    /// `<<-`, leading tabs are stripped from the body
+    HereDocStrip,
+    /// `<<<`
+    HereString
+}
+
+impl RedirectOp {
+    pub fn from_operator (op: &str) -> Option<Self> {
+        Some(match op {
+            "<" => RedirectOp::Read,
+            ">" => RedirectOp::Write,
+            ">|" => RedirectOp::Clobber,
+            ">>" => RedirectOp::Append,
+            "<>" => RedirectOp::ReadWrite,
+            "<&" => RedirectOp::DupIn,
+            ">&" => RedirectOp::DupOut,
+            "&>" => RedirectOp::WriteAll,
+            "&>>" => RedirectOp::AppendAll,
+            "<<" => RedirectOp::HereDoc,
+            "<<-" => RedirectOp::HereDocStrip,
+            "<<<" => RedirectOp::HereString,
+            _ => return None
+        })
+    }
+
+    /// The descriptor used when none is written in front of the operator.
+    pub fn default_fd (&self) -> i32 {
+        match self {
+            RedirectOp::Read | RedirectOp::ReadWrite | RedirectOp::DupIn
+                | RedirectOp::HereDoc | RedirectOp::HereDocStrip | RedirectOp::HereString => 0,
+            _ => 1
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redirect {
+    pub fd: i32,
+    pub op: RedirectOp,
+    /// The file, descriptor or word after the operator. For here-documents, the delimiter.
+    pub target: String,
+    /// The lines of a here-document
+    pub body: Option<String>
+}
+
+impl Redirect {
+    /// Every descriptor applying the redirection replaces.
+    fn affected_fds (&self) -> Vec<i32> {
+        match self.op {
+            RedirectOp::WriteAll | RedirectOp::AppendAll => vec![1, 2],
+            RedirectOp::DupOut if self.fd == 1 && self.target.parse::<i32>().is_err() && !self.target.ends_with('-') => vec![1, 2],
+            _ => vec![self.fd],
+        }
+    }
+}
+
+/// Copies of the descriptors a builtin's redirections replaced, they're put back when this is dropped.
+pub struct SavedFds {
+    // (fd, copy), the copy is -1 if the fd wasn't open to begin with
+    saved: Vec<(i32, i32)>
+}
+
+impl SavedFds {
+    /// Applies `redirects` to the shell itself, remembering how to undo them.
+    /// Whatever was already applied is undone if one of them fails.
+    pub fn apply (redirects: &[Redirect], noclobber: bool) -> Result<Self, String> {
+        let mut saved = SavedFds { saved: Vec::new() };
+
+        // make sure nothing buffered ends up in the redirected file
+        let _ = std::io::Write::flush(&mut std::io::stdout());
+
+        for redirect in redirects {
+            for fd in redirect.affected_fds() {
+                if saved.saved.iter().any(|(saved_fd, _)| *saved_fd == fd) { continue; }
+
+                let copy = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 10) };
+                saved.saved.push((fd, copy));
+            }
+
+            apply(redirect, noclobber)?;
+        }
+
+        Ok(saved)
+    }
+}
+
+impl Drop for SavedFds {
+    fn drop (&mut self) {
+        // output that couldn't be written (`> /dev/full`) would otherwise stay buffered and show up
+        // on the restored stdout, so it's thrown away instead. Only when stdout is restored below,
+        // the shell's own one stays where it is
+        let restores_stdout = self.saved.iter().any(|&(fd, _)| fd == 1);
+        if restores_stdout && std::io::Write::flush(&mut std::io::stdout()).is_err() {
+            if let Ok(null) = open_fd("/dev/null", O_WRONLY) {
+                move_fd(null, 1);
+                let _ = std::io::Write::flush(&mut std::io::stdout());
+            }
+        }
+
+        for &(fd, copy) in self.saved.iter().rev() {
+            unsafe {
+                if copy < 0 {
+                    libc::close(fd);
+                } else {
+                    libc::dup2(copy, fd);
+                    libc::close(copy);
+                }
+            }
+        }
+    }
+}
+
+fn open_fd (file: &str, flags: i32) -> Result<i32, i32> {
+    let c_file = CString::new(file).map_err(|_| libc::EINVAL)?;
+    let fd = unsafe { libc::open(c_file.as_ptr(), flags, 0o666) };
+
+    if fd < 0 { Err(utils::errno()) } else { Ok(fd) }
+}
+
+/// Opens `file` for a redirection, returning the error message the way the shell reports it.
+fn open_target (file: &str, flags: i32) -> Result<i32, String> {
+    open_fd(file, flags).map_err(|errno| format!("{file}: {}", utils::strerror(errno)))
+}
+
+/// `>` with noclobber only fails for regular files, writing to /dev/null is still fine.
+fn open_no_clobber (file: &str) -> Result<i32, String> {
+    match open_fd(file, O_WRONLY | O_CREAT | O_EXCL) {
+        Ok(fd) => Ok(fd),
+        Err(libc::EEXIST) => {
+            let regular = utils::stat(file).is_some_and(|st| st.st_mode & libc::S_IFMT == libc::S_IFREG);
+
+            if regular {
+                Err(format!("{file}: cannot overwrite existing file"))
+            } else {
+                open_target(file, O_WRONLY)
+            }
+        }
+        Err(errno) => Err(format!("{file}: {}", utils::strerror(errno)))
+    }
+}
+
+fn open_write (file: &str, noclobber: bool) -> Result<i32, String> {
+    if noclobber {
+        open_no_clobber(file)
+    } else {
+        open_target(file, O_WRONLY | O_CREAT | O_TRUNC)
+    }
+}
+
+fn move_fd (from: i32, to: i32) {
+    if from != to {
+        unsafe {
+            libc::dup2(from, to);
+            libc::close(from);
+        }
+    }
+}
+
+/// A descriptor reading `text`: a pipe if it fits in one, an already deleted temp file otherwise.
+fn here_document (text: &str) -> Result<i32, String> {
+    let error = || format!("cannot create here-document: {}", utils::strerror(utils::errno()));
+    let mut fds = [0; 2];
+
+    unsafe {
+        if libc::pipe(fds.as_mut_ptr()) != 0 {
+            return Err(error());
+        }
+
+        // nobody reads the pipe before we're done writing, so it must not fill up
+        if text.len() <= libc::fcntl(fds[1], libc::F_GETPIPE_SZ) as usize {
+            write_all(fds[1], text.as_bytes());
+            libc::close(fds[1]);
+            return Ok(fds[0]);
+        }
+
+        libc::close(fds[0]);
+        libc::close(fds[1]);
+
+        let dir = utils::get_environment("TMPDIR").unwrap_or("/tmp");
+        let template = CString::new(format!("{dir}/nyash-heredoc-XXXXXX")).map_err(|_| "cannot create here-document".to_string())?;
+        let template = template.into_raw();
+
+        let fd = libc::mkstemp(template);
+        let template = CString::from_raw(template);
+        if fd < 0 {
+            return Err(error());
+        }
+
+        libc::unlink(template.as_ptr());
+        write_all(fd, text.as_bytes());
+        libc::lseek(fd, 0, libc::SEEK_SET);
+
+        Ok(fd)
+    }
+}
+
+fn write_all (fd: i32, mut bytes: &[u8]) {
+    while !bytes.is_empty() {
+        let written = unsafe { libc::write(fd, bytes.as_ptr().cast(), bytes.len()) };
+
+        if written < 0 && utils::errno() == libc::EINTR { continue; }
+        if written <= 0 { break; }
+
+        bytes = &bytes[written as usize..];
+    }
+}
+
+/// Points `redirect.fd` wherever the redirection says, in the current process.
+pub fn apply (redirect: &Redirect, noclobber: bool) -> Result<(), String> {
+    let Redirect { fd, op, target, body } = redirect;
+    let fd = *fd;
+
+    match op {
+        RedirectOp::Read => move_fd(open_target(target, O_RDONLY)?, fd),
+        RedirectOp::Write => move_fd(open_write(target, noclobber)?, fd),
+        RedirectOp::Clobber => move_fd(open_write(target, false)?, fd),
+        RedirectOp::Append => move_fd(open_target(target, O_WRONLY | O_CREAT | O_APPEND)?, fd),
+        RedirectOp::ReadWrite => move_fd(open_target(target, O_RDWR | O_CREAT)?, fd),
+        RedirectOp::WriteAll => apply_all(open_write(target, noclobber)?),
+        RedirectOp::AppendAll => apply_all(open_target(target, O_WRONLY | O_CREAT | O_APPEND)?),
+        RedirectOp::HereDoc | RedirectOp::HereDocStrip => move_fd(here_document(body.as_deref().unwrap_or_default())?, fd),
+        RedirectOp::HereString => move_fd(here_document(&format!("{target}\n"))?, fd),
+        RedirectOp::DupIn | RedirectOp::DupOut => {
+            if target == "-" {
+                unsafe { libc::close(fd); }
+                return Ok(());
+            }
+
+            // `n>&m-` moves the descriptor instead of copying it
+            let (source, close) = match target.strip_suffix('-') {
+                Some(source) => (source, true),
+                None => (target.as_str(), false),
+            };
+
+            let Ok(source) = source.parse::<i32>() else {
+                // `>&file` is an old spelling of `&>file`
+                if *op == RedirectOp::DupOut && fd == 1 {
+                    apply_all(open_write(target, noclobber)?);
+                    return Ok(());
+                }
+                return Err(format!("{target}: ambiguous redirect"));
+            };
+
+            if source != fd && unsafe { libc::dup2(source, fd) } < 0 {
+                return Err(format!("{source}: {}", utils::strerror(utils::errno())));
+            }
+
+            if close && source != fd {
+                unsafe { libc::close(source); }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn apply_all (file: i32) {
+    unsafe {
+        libc::dup2(file, 1);
+        libc::dup2(file, 2);
+        if file > 2 { libc::close(file); }
+    }
+}
+
+#[cfg(test)]
+mod redirect_tests {
+    use super::*;
+    use crate::utils::TestDir;
+
+    #[test]
+    fn operators () {
+        assert_eq!(RedirectOp::from_operator("&>>"), Some(RedirectOp::AppendAll));
+        assert_eq!(RedirectOp::from_operator("|"), None);
+        assert_eq!(RedirectOp::from_operator("<>").unwrap().default_fd(), 0);
+        assert_eq!(RedirectOp::from_operator(">&").unwrap().default_fd(), 1);
+    }
+
+    #[test]
+    fn saved_fds_are_restored () {
+        let dir = TestDir::new("saved");
+        let file = dir.join("out");
+        let target = file.to_string_lossy().to_string();
+
+        unsafe {
+            let before = libc::fcntl(42, libc::F_GETFD);
+            assert!(before < 0, "fd 42 shouldn't be open yet");
+
+            let redirects = [Redirect { fd: 42, op: RedirectOp::Write, target, body: None }];
+            let saved = SavedFds::apply(&redirects, false).unwrap();
+
+            assert!(libc::fcntl(42, libc::F_GETFD) >= 0);
+            libc::write(42, b"hi".as_ptr().cast(), 2);
+
+            drop(saved);
+            assert!(libc::fcntl(42, libc::F_GETFD) < 0, "fd 42 should be closed again");
+        }
+
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "hi");
+    }
+
+    #[test]
+    fn here_documents_of_any_size () {
+        for size in [0, 10, 1 << 20] {
+            let text = "x".repeat(size);
+            let fd = here_document(&text).unwrap();
+
+            let mut file = unsafe { <std::fs::File as std::os::fd::FromRawFd>::from_raw_fd(fd) };
+            let mut read = String::new();
+            std::io::Read::read_to_string(&mut file, &mut read).unwrap();
+
+            assert_eq!(read.len(), size);
+        }
+    }
+
+    #[test]
+    fn noclobber_refuses_regular_files () {
+        let dir = TestDir::new("clobber");
+        let file = dir.join("file");
+        std::fs::write(&file, "keep").unwrap();
+        let file = file.to_string_lossy().to_string();
+
+        let err = open_no_clobber(&file).unwrap_err();
+        assert!(err.ends_with("cannot overwrite existing file"), "{err}");
+
+        let fd = open_no_clobber("/dev/null").unwrap();
+        unsafe { libc::close(fd); }
+
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep");
+    }
+}
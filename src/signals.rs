@@ -0,0 +1,249 @@
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+
+use libc::c_int;
+
+pub const SIGNALS: &[(&str, c_int)] = &[
+    ("HUP", libc::SIGHUP), ("INT", libc::SIGINT), ("QUIT", libc::SIGQUIT), ("ILL", libc::SIGILL),
+    ("TRAP", libc::SIGTRAP), ("ABRT", libc::SIGABRT), ("BUS", libc::SIGBUS), ("FPE", libc::SIGFPE),
+    ("KILL", libc::SIGKILL), ("USR1", libc::SIGUSR1), ("SEGV", libc::SIGSEGV), ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE), ("ALRM", libc::SIGALRM), ("TERM", libc::SIGTERM), ("CHLD", libc::SIGCHLD),
+    ("CONT", libc::SIGCONT), ("STOP", libc::SIGSTOP), ("TSTP", libc::SIGTSTP), ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU), ("URG", libc::SIGURG), ("XCPU", libc::SIGXCPU), ("XFSZ", libc::SIGXFSZ),
+    ("VTALRM", libc::SIGVTALRM), ("PROF", libc::SIGPROF), ("WINCH", libc::SIGWINCH), ("IO", libc::SIGIO),
+    ("SYS", libc::SIGSYS),
+];
+
+// what an interactive shell ignores, children get the default behaviour back
+const SHELL_IGNORED: &[c_int] = &[libc::SIGINT, libc::SIGQUIT, libc::SIGTSTP, libc::SIGTTIN, libc::SIGTTOU];
+
+/// Something `trap` can attach a command to: a real signal or one of the shell's pseudo-signals.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Trap {
+    Exit,
+    Err,
+    Debug,
+    Return,
+    Signal(c_int)
+}
+
+impl Trap {
+    /// Accepts `INT`, `SIGINT`, `int`, `2`, and the `EXIT`/`ERR`/`DEBUG`/`RETURN` pseudo-signals.
+    pub fn parse (spec: &str) -> Option<Self> {
+        let upper = spec.to_ascii_uppercase();
+
+        match upper.as_str() {
+            "0" | "EXIT" => return Some(Trap::Exit),
+            "ERR" => return Some(Trap::Err),
+            "DEBUG" => return Some(Trap::Debug),
+            "RETURN" => return Some(Trap::Return),
+            _ => {}
+        }
+
+        if let Ok(num) = upper.parse::<c_int>() {
+            return SIGNALS.iter().find(|(_, sig)| *sig == num).map(|(_, sig)| Trap::Signal(*sig));
+        }
+
+        let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+        SIGNALS.iter().find(|(n, _)| *n == name).map(|(_, sig)| Trap::Signal(*sig))
+    }
+
+    pub fn name (&self) -> String {
+        match self {
+            Trap::Exit => "EXIT".to_string(),
+            Trap::Err => "ERR".to_string(),
+            Trap::Debug => "DEBUG".to_string(),
+            Trap::Return => "RETURN".to_string(),
+            Trap::Signal(sig) => match SIGNALS.iter().find(|(_, s)| s == sig) {
+                Some((name, _)) => format!("SIG{name}"),
+                None => sig.to_string(),
+            }
+        }
+    }
+}
+
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+extern "C" fn record_signal (sig: c_int) {
+    PENDING.fetch_or(1 << sig, Ordering::SeqCst);
+}
+
+/// Signals caught since the last call, in signal number order.
+pub fn take_pending () -> Vec<c_int> {
+    let pending = PENDING.swap(0, Ordering::SeqCst);
+    (1..64).filter(|sig| pending & (1 << sig) != 0).collect()
+}
+
+static RESIZED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn record_resize (_sig: c_int) {
+    RESIZED.store(true, Ordering::SeqCst);
+}
+
+/// Whether the terminal changed size since the last call.
+pub fn take_resized () -> bool {
+    RESIZED.swap(false, Ordering::SeqCst)
+}
+
+// statuses collected by the SIGCHLD handler until the job table asks for them
+struct Reaped {
+    pid: AtomicI32,
+    status: AtomicI32,
+    seq: AtomicU64
+}
+
+const REAPED_SLOTS: usize = 256;
+static REAPED: [Reaped; REAPED_SLOTS] = [const { Reaped { pid: AtomicI32::new(0), status: AtomicI32::new(0), seq: AtomicU64::new(0) } }; REAPED_SLOTS];
+static REAPED_SEQ: AtomicU64 = AtomicU64::new(1);
+
+extern "C" fn reap_children (sig: c_int) {
+    unsafe {
+        let errno = *libc::__errno_location();
+
+        loop {
+            let mut status = 0;
+            let pid = libc::waitpid(-1, &mut status, libc::WNOHANG | libc::WUNTRACED | libc::WCONTINUED);
+            if pid <= 0 { break; }
+
+            // if every slot is taken the status is lost and the job will look like it exited with 0
+            if let Some(slot) = REAPED.iter().find(|slot| slot.pid.load(Ordering::Acquire) == 0) {
+                slot.status.store(status, Ordering::Relaxed);
+                slot.seq.store(REAPED_SEQ.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+                slot.pid.store(pid, Ordering::Release);
+            }
+        }
+
+        *libc::__errno_location() = errno;
+    }
+
+    record_signal(sig);
+}
+
+/// The oldest status the SIGCHLD handler collected for `pid`, if any.
+pub fn take_reaped (pid: i32) -> Option<i32> {
+    let slot = REAPED.iter()
+        .filter(|slot| slot.pid.load(Ordering::Acquire) == pid)
+        .min_by_key(|slot| slot.seq.load(Ordering::Relaxed))?;
+
+    let status = slot.status.load(Ordering::Relaxed);
+    slot.pid.store(0, Ordering::Release);
+
+    Some(status)
+}
+
+/// Keeps SIGCHLD blocked while alive, so the handler can't steal a status we're about to wait for.
+pub struct ChildSignalsBlocked {
+    old: libc::sigset_t
+}
+
+impl ChildSignalsBlocked {
+    pub fn new () -> Self {
+        unsafe {
+            let mut set = std::mem::zeroed();
+            let mut old = std::mem::zeroed();
+
+            libc::sigemptyset(&mut set);
+            libc::sigaddset(&mut set, libc::SIGCHLD);
+            libc::sigprocmask(libc::SIG_BLOCK, &set, &mut old);
+
+            Self { old }
+        }
+    }
+}
+
+impl Drop for ChildSignalsBlocked {
+    fn drop (&mut self) {
+        unsafe {
+            libc::sigprocmask(libc::SIG_SETMASK, &self.old, std::ptr::null_mut());
+        }
+    }
+}
+
+fn set_handler (sig: c_int, handler: libc::sighandler_t) {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        libc::sigaction(sig, &action, std::ptr::null_mut());
+    }
+}
+
+/// Reaps children as soon as they exit, so background jobs don't linger as zombies.
+pub fn install_child_handler () {
+    set_handler(libc::SIGCHLD, reap_children as extern "C" fn(c_int) as libc::sighandler_t);
+}
+
+/// Notes SIGWINCH for the line editor. Without SA_RESTART, so a read waiting for a key
+/// returns and the line can be redrawn right away.
+pub fn catch_resize () {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = record_resize as extern "C" fn(c_int) as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        libc::sigaction(libc::SIGWINCH, &action, std::ptr::null_mut());
+    }
+}
+
+/// Dispositions of an interactive shell: Ctrl-C, Ctrl-\ and Ctrl-Z only affect the foreground job.
+pub fn ignore_interactive () {
+    for &sig in SHELL_IGNORED {
+        set_handler(sig, libc::SIG_IGN);
+    }
+}
+
+pub fn catch (sig: c_int) {
+    if sig == libc::SIGCHLD { return; }
+    set_handler(sig, record_signal as extern "C" fn(c_int) as libc::sighandler_t);
+}
+
+pub fn ignore (sig: c_int) {
+    set_handler(sig, libc::SIG_IGN);
+}
+
+/// Puts `sig` back to how the shell handles it when nothing is trapped.
+pub fn restore (sig: c_int, interactive: bool) {
+    if sig == libc::SIGCHLD {
+        install_child_handler();
+    } else if sig == libc::SIGWINCH && interactive {
+        catch_resize();
+    } else if interactive && SHELL_IGNORED.contains(&sig) {
+        set_handler(sig, libc::SIG_IGN);
+    } else {
+        set_handler(sig, libc::SIG_DFL);
+    }
+}
+
+/// Called right after fork: children start with default dispositions, except for signals the user
+/// explicitly ignored with `trap '' SIG`.
+pub fn reset_for_child (ignored: &[c_int]) {
+    for &(_, sig) in SIGNALS {
+        if sig == libc::SIGKILL || sig == libc::SIGSTOP { continue; }
+
+        if ignored.contains(&sig) {
+            set_handler(sig, libc::SIG_IGN);
+        } else {
+            set_handler(sig, libc::SIG_DFL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod signal_tests {
+    use super::*;
+
+    #[test]
+    fn parse_trap_specs () {
+        assert_eq!(Trap::parse("INT"), Some(Trap::Signal(libc::SIGINT)));
+        assert_eq!(Trap::parse("sigterm"), Some(Trap::Signal(libc::SIGTERM)));
+        assert_eq!(Trap::parse("1"), Some(Trap::Signal(libc::SIGHUP)));
+        assert_eq!(Trap::parse("0"), Some(Trap::Exit));
+        assert_eq!(Trap::parse("exit"), Some(Trap::Exit));
+        assert_eq!(Trap::parse("ERR"), Some(Trap::Err));
+        assert_eq!(Trap::parse("SIGNOPE"), None);
+
+        assert_eq!(Trap::Signal(libc::SIGUSR1).name(), "SIGUSR1");
+        assert_eq!(Trap::Return.name(), "RETURN");
+    }
+}
@@ -18,12 +18,36 @@ impl Trie {
         let mut curr = self;
 
         for ch in word.chars() {
-            curr = curr.children.entry(ch).or_insert_with(Self::new);
+            curr = curr.children.entry(ch).or_default();
         }
 
         curr.end_of_word = true;
     }
 
+    /// Removes `word` from the trie, pruning the branches that no longer lead to any word.
+    /// Returns false if the word wasn't there.
+    pub fn remove (&mut self, word: &str) -> bool {
+        let chars: Vec<char> = word.chars().collect();
+        Self::remove_chars(self, &chars)
+    }
+
+    fn remove_chars (node: &mut Self, chars: &[char]) -> bool {
+        let Some((ch, rest)) = chars.split_first() else {
+            let found = node.end_of_word;
+            node.end_of_word = false;
+            return found;
+        };
+
+        let Some(next) = node.children.get_mut(ch) else { return false };
+        let found = Self::remove_chars(next, rest);
+
+        if found && !next.end_of_word && next.children.is_empty() {
+            node.children.remove(ch);
+        }
+
+        found
+    }
+
     pub fn with_prefix (&self, word: &str) -> Vec<String> {
         let mut curr = self;
         let mut res = Vec::new();
@@ -73,4 +97,21 @@ mod trie_tests {
             "Hi mother".to_string()
         ]);
     }
+
+    #[test]
+    fn trie_remove_test () {
+        let mut tr = Trie::new();
+
+        tr.insert("cargo");
+        tr.insert("cargo-clippy");
+        tr.insert("cat");
+
+        assert!(tr.remove("cargo"));
+        assert!(!tr.remove("cargo"));
+        assert_eq!(tr.with_prefix("cargo"), vec!["cargo-clippy".to_string()]);
+
+        assert!(tr.remove("cargo-clippy"));
+        assert_eq!(tr.with_prefix("car"), Vec::<String>::new());
+        assert_eq!(tr.with_prefix("ca"), vec!["cat".to_string()]);
+    }
 }
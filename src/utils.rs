@@ -1,9 +1,7 @@
 use std::ffi::{ CStr, CString };
 use std::env::split_paths;
-use std::os::fd::RawFd;
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
-use std::io;
 use std::str::Chars;
 
 use anyhow::Result;
@@ -24,6 +22,17 @@ pub fn get_environment (var: &str) -> Option<&str> {
     }
 }
 
+/// Sets (and exports) an environment variable.
+pub fn set_environment (var: &str, value: &str) {
+    let (Ok(var), Ok(value)) = (CString::new(var), CString::new(value)) else { return };
+    unsafe { libc::setenv(var.as_ptr(), value.as_ptr(), 1); }
+}
+
+pub fn unset_environment (var: &str) {
+    let Ok(var) = CString::new(var) else { return };
+    unsafe { libc::unsetenv(var.as_ptr()); }
+}
+
 #[derive(Debug)]
 pub struct DirIter {
     dir: *mut libc::DIR
@@ -52,12 +61,21 @@ impl Iterator for DirIter {
     }
 }
 
+/// Opens a file the shell keeps for itself. It's moved out of the way of the low descriptors
+/// users redirect (`3>file`) and closed on exec.
 pub fn open_file <T: AsRef<str>>(path: T) -> i32 {
-    use libc::{ O_RDWR, O_CREAT, O_APPEND };
+    use libc::{ O_RDWR, O_CREAT, O_APPEND, O_CLOEXEC, F_DUPFD_CLOEXEC };
     use libc::{ S_IRUSR, S_IWUSR, S_IRGRP, S_IROTH };
     let path = CString::new(path.as_ref()).expect("Path contained a null byte");
     unsafe {
-        libc::open(path.as_ptr().cast(), O_RDWR | O_CREAT | O_APPEND, S_IRUSR | S_IWUSR | S_IRGRP | S_IROTH)
+        let fd = libc::open(path.as_ptr().cast(), O_RDWR | O_CREAT | O_APPEND | O_CLOEXEC, S_IRUSR | S_IWUSR | S_IRGRP | S_IROTH);
+        if fd < 0 { return fd; }
+
+        let high = libc::fcntl(fd, F_DUPFD_CLOEXEC, 10);
+        if high < 0 { return fd; }
+
+        libc::close(fd);
+        high
     }
 }
 
@@ -80,64 +98,82 @@ where
 }
 
 pub fn get_executable (cmd: &str) -> Option<String> {
-    let path = get_environment("PATH").unwrap_or("");
-    let dirs =  split_paths(path);
+    find_executable(cmd, get_environment("PATH").unwrap_or(""))
+}
 
-    for dir in dirs {
-        if let Ok(iter) = read_directory(&dir) {
-            let executables: Vec<String> = iter.filter(|e| e != "." && e != "..").collect();
+/// Searches `path` for `cmd` the way execvp would: the first regular file we're allowed to execute wins.
+pub fn find_executable (cmd: &str, path: &str) -> Option<String> {
+    if cmd.is_empty() { return None; }
 
-            if executables.contains(&cmd.to_string()) {
-                return Some(dir.join(cmd).to_string_lossy().to_string());
-            }
+    for dir in split_paths(path) {
+        let candidate = dir.join(cmd);
+
+        if is_executable(&candidate) {
+            return Some(candidate.to_string_lossy().to_string());
         }
     }
 
     None
 }
 
-pub fn get_system_binaries () -> Vec<String> {
-    let path = get_environment("PATH").unwrap_or("");
-    let dirs = split_paths(path);
-    let mut res = Vec::new();
+pub fn is_executable<P: AsRef<Path>> (path: P) -> bool {
+    let Some(st) = stat(&path) else { return false };
+    if st.st_mode & libc::S_IFMT != libc::S_IFREG { return false; }
+
+    let Ok(c_path) = CString::new(path.as_ref().as_os_str().as_bytes()) else { return false };
+    unsafe { libc::access(c_path.as_ptr(), libc::X_OK) == 0 }
+}
 
-    for dir in dirs {
-        if let Ok(iter) = read_directory(&dir) {
-            let mut executables: Vec<String> = iter.filter(|e| e != "." && e != "..").collect();
+/// Returns the entries of a single `PATH` directory, or nothing if it can't be read.
+pub fn get_directory_binaries<P: AsRef<Path>> (dir: P) -> Vec<String> {
+    match read_directory(dir) {
+        Ok(iter) => iter.filter(|e| e != "." && e != "..").collect(),
+        Err(_) => Vec::new(),
+    }
+}
 
-            res.append(&mut executables);
+pub fn stat<P: AsRef<Path>> (path: P) -> Option<libc::stat> {
+    let c_path = CString::new(path.as_ref().as_os_str().as_bytes()).ok()?;
+
+    unsafe {
+        let mut st: libc::stat = std::mem::zeroed();
+        if libc::stat(c_path.as_ptr(), &mut st) != 0 {
+            return None;
         }
+        Some(st)
     }
+}
 
-    res
+/// Modification time of `path` in nanoseconds, used to notice when a directory's content changed.
+pub fn get_mtime<P: AsRef<Path>> (path: P) -> Option<i64> {
+    stat(path).map(|st| st.st_mtime * 1_000_000_000 + st.st_mtime_nsec)
 }
 
-pub fn longest_common_prefix <T: AsRef<str>>(word: &str, completions: &Vec<T>) -> String {
-    let mut i = word.len();
+pub fn longest_common_prefix <T: AsRef<str>>(word: &str, completions: &[T]) -> String {
+    let i = word.len();
     let mut lcp = String::from(word);
     let mut completions = completions.iter().map(|e| e.as_ref().chars()).collect::<Vec<Chars<'_>>>();
 
     for comp in &mut completions {
-        comp.nth(i - 1).unwrap();
+        if i > 0 { comp.nth(i - 1).unwrap(); }
     }
 
     loop {
         let mut ch = None::<char>;
         for comp in &mut completions {
             if let Some(character) = comp.next() {
-                if ch.is_none() {
-                    ch = Some(character);
-                } else if character != ch.unwrap() {
-                    return lcp;
-                };
+                match ch {
+                    None => ch = Some(character),
+                    Some(c) if c != character => return lcp,
+                    _ => {}
+                }
             } else {
                 return lcp;
             }
         };
 
-        lcp.push(ch.unwrap());
-
-        i += 1;
+        let Some(ch) = ch else { return lcp };
+        lcp.push(ch);
     }
 }
 
@@ -158,30 +194,94 @@ pub fn get_pwd () -> String {
     }
 }
 
-/// Redirects current process's io streams to a separate file
-pub fn redirect_io (file: &str, flags: i32, stream_fd: RawFd) -> Result<()> {
-    let c_file = CString::new(file)?;
-    let fd = unsafe { libc::open(
-            c_file.as_ptr(), 
-            flags, 
-            0o644
-        ) 
-    };
-
-    if fd < 0 {
-        return Err(io::Error::new(io::ErrorKind::Other, "failed to get file descriptor").into());
+/// The name of the user we run as, $USER if the password database doesn't know it.
+pub fn get_user () -> String {
+    unsafe {
+        let passwd = libc::getpwuid(libc::geteuid());
+
+        if passwd.is_null() {
+            get_environment("USER").unwrap_or_default().to_string()
+        } else {
+            CStr::from_ptr((*passwd).pw_name).to_string_lossy().to_string()
+        }
     }
+}
+
+pub fn get_hostname () -> String {
+    let mut buf = [0u8; 256];
 
     unsafe {
-        libc::dup2(fd, stream_fd);
-        libc::close(fd);
+        if libc::gethostname(buf.as_mut_ptr().cast(), buf.len() - 1) != 0 {
+            return String::new();
+        }
+        CStr::from_ptr(buf.as_ptr().cast()).to_string_lossy().to_string()
     }
+}
 
-    Ok(())
+/// Formats the time `seconds` after the epoch with strftime, in local time.
+pub fn format_time (format: &str, seconds: i64) -> String {
+    let Ok(c_format) = CString::new(format) else { return String::new() };
+
+    unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&(seconds as libc::time_t), &mut tm);
+
+        // strftime can't tell a full buffer from an empty result, so grow until it fits
+        let mut size = 256;
+        loop {
+            let mut buf = vec![0u8; size];
+            let len = libc::strftime(buf.as_mut_ptr().cast(), size, c_format.as_ptr(), &tm);
+
+            if len > 0 || size > 64 * format.len() + 256 {
+                buf.truncate(len);
+                return String::from_utf8_lossy(&buf).to_string();
+            }
+            size *= 4;
+        }
+    }
+}
+
+pub fn errno () -> i32 {
+    unsafe { *libc::__errno_location() }
+}
+
+/// The system's message for `errnum`, without the "(os error N)" Rust appends.
+pub fn strerror (errnum: i32) -> String {
+    unsafe {
+        let msg = libc::strerror(errnum);
+        if msg.is_null() { return format!("error {errnum}"); }
+        CStr::from_ptr(msg).to_string_lossy().to_string()
+    }
+}
+
+/// Turns a raw `waitpid` status into the number `$?` would report.
+pub fn exit_status (status: i32) -> i32 {
+    if libc::WIFEXITED(status) {
+        libc::WEXITSTATUS(status)
+    } else if libc::WIFSIGNALED(status) {
+        128 + libc::WTERMSIG(status)
+    } else if libc::WIFSTOPPED(status) {
+        128 + libc::WSTOPSIG(status)
+    } else {
+        status
+    }
+}
+
+/// How wide the terminal on `fd` is, 80 columns if it can't tell.
+pub fn terminal_columns (fd: i32) -> usize {
+    unsafe {
+        let mut size: libc::winsize = std::mem::zeroed();
+
+        if libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) == 0 && size.ws_col > 0 {
+            size.ws_col as usize
+        } else {
+            80
+        }
+    }
 }
 
 pub fn enable_raw_mode (fd: i32) -> libc::termios {
-    use libc::{ TCSANOW, VMIN, ECHO, ICANON, VTIME };
+    use libc::{ TCSANOW, VMIN, ECHO, ICANON, ISIG, VTIME };
 
     unsafe {
         let mut term = std::mem::zeroed();
@@ -189,7 +289,8 @@ pub fn enable_raw_mode (fd: i32) -> libc::termios {
 
         let original = term;
 
-        term.c_lflag &= !(ICANON | ECHO);
+        // without ISIG, Ctrl-C and Ctrl-Z reach us as plain bytes
+        term.c_lflag &= !(ICANON | ECHO | ISIG);
         term.c_cc[VMIN] = 1;
         term.c_cc[VTIME] = 0;
 
@@ -210,6 +311,37 @@ pub struct PipeLine {
     pub fds: [i32; 2]
 }
 
+/// A directory for a test to work in, it's removed with what's in it when the test ends,
+/// whether the test passed or not.
+#[cfg(test)]
+pub struct TestDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    /// Creates `nyash-<name>-<pid>` in the temporary directory.
+    pub fn new (name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("nyash-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TestDir {
+    type Target = Path;
+
+    fn deref (&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop (&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
 #[cfg(test)]
 mod utility_tests {
     use super::*;
@@ -253,12 +385,29 @@ mod utility_tests {
         }
     }
 
+    #[test]
+    fn find_executable_test () {
+        let dir = TestDir::new("find");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("plain"), "").unwrap();
+        std::fs::write(dir.join("tool"), "").unwrap();
+
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(dir.join("tool"), std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let path = format!("/nonexistent:{}", dir.display());
+
+        assert_eq!(find_executable("tool", &path), Some(dir.join("tool").to_string_lossy().to_string()));
+        assert_eq!(find_executable("plain", &path), None, "no execute bit");
+        assert_eq!(find_executable("sub", &path), None, "directories aren't commands");
+    }
+
     #[test]
     fn get_lcp () {
         let word = "clear-";
         let completions = ["clear-fbo", "clear-fbo-scissor", "clear-fbo-tex"];
 
-        let lcp = longest_common_prefix(word, &completions.to_vec());
+        let lcp = longest_common_prefix(word, &completions);
 
         dbg!(&lcp);
 
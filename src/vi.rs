@@ -0,0 +1,594 @@
+// the keys of vi's normal mode, for `set -o vi`
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Insert,
+    Normal
+}
+
+/// What's left for the line editor to do once a key went through `Vi::key`.
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    /// The key was taken care of, or a command is waiting for the rest of its keys
+    Done,
+    /// Not a key vi has a use for here, like Enter or Tab
+    Pass,
+    Bell,
+    /// Go this many entries back in the history, forward if it's negative
+    History(isize),
+    /// Look for an entry with the text in it, towards older entries or newer ones
+    Search(String, bool),
+    /// Open the line in $EDITOR
+    Edit
+}
+
+/// A normal mode command once all of its keys are there, like `3dw` or `fx`.
+struct Command {
+    count: usize,
+    operator: Option<char>,
+    key: char,
+    // the character `f`, `t` and `r` take
+    arg: Option<char>
+}
+
+// how a normal mode command goes on, once a key is added
+enum Parsed {
+    Complete(Command),
+    Incomplete,
+    Invalid
+}
+
+const MOTIONS: &str = "hlwbeWBE0^$fFtT;, \x7F\x08";
+
+// a bigger count does nothing more on one line, it only makes `p` and `.` run away
+const MAX_COUNT: usize = 10_000;
+
+/// Reads a normal mode command: an optional count, an optional operator (`d`, `c` or `y`) with
+/// a count of its own, then a motion or a command key.
+fn parse (keys: &[char]) -> Parsed {
+    let mut idx = 0;
+
+    let count = |idx: &mut usize| -> Option<usize> {
+        let digits = keys[*idx..].iter()
+            .enumerate()
+            .take_while(|&(pos, ch)| ch.is_ascii_digit() && !(pos == 0 && *ch == '0'))
+            .count();
+        let number: String = keys[*idx..*idx + digits].iter().collect();
+        *idx += digits;
+        (digits > 0).then(|| number.parse().unwrap_or(usize::MAX))
+    };
+
+    let first = count(&mut idx);
+    let Some(&key) = keys.get(idx) else { return Parsed::Incomplete };
+    idx += 1;
+
+    let (operator, second, key) = match key {
+        'd' | 'c' | 'y' => {
+            let second = count(&mut idx);
+            let Some(&motion) = keys.get(idx) else { return Parsed::Incomplete };
+            idx += 1;
+
+            if motion != key && !MOTIONS.contains(motion) { return Parsed::Invalid; }
+            (Some(key), second, motion)
+        }
+        key => (None, None, key),
+    };
+
+    let arg = match key {
+        'f' | 'F' | 't' | 'T' | 'r' => match keys.get(idx) {
+            Some(&arg) => Some(arg),
+            None => return Parsed::Incomplete,
+        },
+        _ => None,
+    };
+
+    let count = first.unwrap_or(1).saturating_mul(second.unwrap_or(1)).min(MAX_COUNT);
+    Parsed::Complete(Command { count, operator, key, arg })
+}
+
+// blanks, words of letters, digits and `_`, and runs of anything else; only blanks and the rest
+// for the WORD motions
+fn class (ch: char, big: bool) -> u8 {
+    if ch.is_whitespace() { 0 } else if big || ch.is_alphanumeric() || ch == '_' { 1 } else { 2 }
+}
+
+fn next_word (text: &[char], from: usize, big: bool) -> usize {
+    let mut idx = from;
+    let Some(&ch) = text.get(idx) else { return text.len() };
+
+    let word = class(ch, big);
+    while word != 0 && idx < text.len() && class(text[idx], big) == word { idx += 1; }
+    while idx < text.len() && class(text[idx], big) == 0 { idx += 1; }
+
+    idx
+}
+
+fn previous_word (text: &[char], from: usize, big: bool) -> usize {
+    let mut idx = from;
+    while idx > 0 && class(text[idx - 1], big) == 0 { idx -= 1; }
+    if idx == 0 { return 0; }
+
+    let word = class(text[idx - 1], big);
+    while idx > 0 && class(text[idx - 1], big) == word { idx -= 1; }
+
+    idx
+}
+
+/// The last character of the word `idx` is in.
+fn end_of_word (text: &[char], mut idx: usize, big: bool) -> usize {
+    let word = class(text[idx], big);
+    while idx + 1 < text.len() && class(text[idx + 1], big) == word { idx += 1; }
+
+    idx
+}
+
+fn word_end (text: &[char], from: usize, big: bool) -> usize {
+    let mut idx = from + 1;
+    while idx < text.len() && class(text[idx], big) == 0 { idx += 1; }
+    if idx >= text.len() { return text.len().saturating_sub(1); }
+
+    end_of_word(text, idx, big)
+}
+
+fn first_non_blank (text: &[char]) -> usize {
+    text.iter().position(|ch| !ch.is_whitespace()).unwrap_or(text.len())
+}
+
+/// Where the cursor can be in normal mode: on a character, never after the last one.
+pub fn clamp (text: &[char], cursor: usize) -> usize {
+    cursor.min(text.len().saturating_sub(1))
+}
+
+/// The state of the vi keys over a line being read.
+pub struct Vi {
+    pub mode: Mode,
+    // the normal mode command being typed
+    command: Vec<char>,
+    // the text after a `/`, while it's typed
+    search: Option<String>,
+    last_search: Option<String>,
+    // the last `f`, `F`, `t` or `T` and its character, for `;` and `,`
+    last_find: Option<(char, char)>,
+    // what `d`, `c`, `y` and `x` took, for `p`
+    register: Vec<char>,
+    undo: Vec<(Vec<char>, usize)>,
+    // the keys of the change being made, up to the ESC ending its text, and of the last one for `.`
+    change: Vec<char>,
+    recording: bool,
+    last_change: Vec<char>
+}
+
+impl Vi {
+    /// Lines start out in insert mode.
+    pub fn new () -> Self {
+        Self {
+            mode: Mode::Insert,
+            command: Vec::new(),
+            search: None,
+            last_search: None,
+            last_find: None,
+            register: Vec::new(),
+            undo: Vec::new(),
+            change: Vec::new(),
+            recording: false,
+            last_change: Vec::new(),
+        }
+    }
+
+    /// The `/` search being typed, it's shown instead of the line meanwhile.
+    pub fn search_text (&self) -> Option<&str> {
+        self.search.as_deref()
+    }
+
+    /// Handles `key` on the line being edited, `'\x1b'` is a lone ESC.
+    pub fn key (&mut self, key: char, text: &mut Vec<char>, cursor: &mut usize) -> Action {
+        if let Some(search) = &mut self.search {
+            match key {
+                '\r' | '\n' => {
+                    let search = self.search.take().unwrap_or_default();
+                    // an empty search is the last one again
+                    let search = if search.is_empty() { self.last_search.clone() } else { Some(search) };
+                    self.last_search = search.clone();
+
+                    return search.map_or(Action::Bell, |search| Action::Search(search, true));
+                }
+                '\x1b' | '\x03' => self.search = None,
+                // backspace on an empty search leaves it
+                '\x7F' | '\x08' if search.is_empty() => self.search = None,
+                '\x7F' | '\x08' => { search.pop(); }
+                key if !key.is_control() => search.push(key),
+                _ => {}
+            }
+            return Action::Done;
+        }
+
+        match self.mode {
+            Mode::Insert => self.insert(key, text, cursor),
+            Mode::Normal => {
+                // the keys passed on to the line editor, like End, leave the cursor after the line
+                *cursor = clamp(text, *cursor);
+                self.normal(key, text, cursor)
+            }
+        }
+    }
+
+    fn insert (&mut self, key: char, text: &mut Vec<char>, cursor: &mut usize) -> Action {
+        match key {
+            '\x1b' => {
+                self.mode = Mode::Normal;
+                *cursor = cursor.saturating_sub(1);
+
+                if self.recording {
+                    self.change.push(key);
+                    self.last_change = std::mem::take(&mut self.change);
+                    self.recording = false;
+                }
+            }
+            '\x7F' | '\x08' => {
+                if *cursor > 0 {
+                    *cursor -= 1;
+                    text.remove(*cursor);
+                }
+                if self.recording { self.change.push(key); }
+            }
+            key if !key.is_control() => {
+                text.insert(*cursor, key);
+                *cursor += 1;
+                if self.recording { self.change.push(key); }
+            }
+            _ => return Action::Pass,
+        }
+
+        Action::Done
+    }
+
+    fn normal (&mut self, key: char, text: &mut Vec<char>, cursor: &mut usize) -> Action {
+        if self.command.is_empty() && (key == '\x1b' || key.is_control() && key != '\x7F' && key != '\x08') {
+            // Enter, Ctrl-C and the like are the line editor's
+            return if key == '\x1b' { Action::Done } else { Action::Pass };
+        }
+
+        if key == '\x1b' {
+            self.command.clear();
+            return Action::Done;
+        }
+
+        self.command.push(key);
+
+        match parse(&self.command) {
+            Parsed::Incomplete => Action::Done,
+            Parsed::Invalid => {
+                self.command.clear();
+                Action::Bell
+            }
+            Parsed::Complete(command) => {
+                let keys = std::mem::take(&mut self.command);
+                self.run(command, keys, text, cursor)
+            }
+        }
+    }
+
+    /// Where `key` takes the cursor, and whether the character it lands on counts for an operator.
+    fn motion (&mut self, key: char, arg: Option<char>, count: usize, text: &[char], cursor: usize) -> Option<(usize, bool)> {
+        let repeat = |step: &dyn Fn(usize) -> usize| (0..count).fold(cursor, |idx, _| step(idx));
+
+        let target = match key {
+            'h' | '\x7F' | '\x08' => (cursor.saturating_sub(count), false),
+            'l' | ' ' => ((cursor + count).min(text.len()), false),
+            'w' | 'W' => (repeat(&|idx| next_word(text, idx, key == 'W')), false),
+            'b' | 'B' => (repeat(&|idx| previous_word(text, idx, key == 'B')), false),
+            'e' | 'E' => (repeat(&|idx| word_end(text, idx, key == 'E')), true),
+            '0' => (0, false),
+            '^' => (first_non_blank(text), false),
+            '$' => (text.len(), false),
+            'f' | 'F' | 't' | 'T' => {
+                let arg = arg?;
+                self.last_find = Some((key, arg));
+                return find(key, arg, count, text, cursor);
+            }
+            ';' | ',' => {
+                let (find_key, arg) = self.last_find?;
+                let find_key = match (key, find_key) {
+                    (';', find_key) => find_key,
+                    (_, 'f') => 'F',
+                    (_, 'F') => 'f',
+                    (_, 't') => 'T',
+                    _ => 't',
+                };
+                // a `t` again would stay where it is, it goes on from the character it stopped at
+                let from = match find_key {
+                    't' if text.get(cursor + 1) == Some(&arg) => cursor + 1,
+                    'T' if cursor > 0 && text.get(cursor - 1) == Some(&arg) => cursor - 1,
+                    _ => cursor,
+                };
+                return find(find_key, arg, count, text, from);
+            }
+            _ => return None,
+        };
+
+        Some(target)
+    }
+
+    fn run (&mut self, command: Command, keys: Vec<char>, text: &mut Vec<char>, cursor: &mut usize) -> Action {
+        let Command { count, operator, key, arg } = command;
+        let changes = operator.is_some_and(|op| op != 'y') || "xXsSrDCpPiaIA~".contains(key);
+
+        if changes {
+            self.undo.push((text.clone(), *cursor));
+            self.change = keys;
+            self.recording = true;
+        }
+
+        let action = match operator {
+            Some(operator) => self.operate(operator, count, key, arg, text, cursor),
+            None => self.command_key(count, key, arg, text, cursor),
+        };
+
+        if action == Action::Bell && changes {
+            self.undo.pop();
+            self.recording = false;
+        } else if self.recording && self.mode == Mode::Normal {
+            // nothing gets typed after it, the change is complete
+            self.last_change = std::mem::take(&mut self.change);
+            self.recording = false;
+        }
+
+        action
+    }
+
+    /// `d`, `c` or `y` over what `key` moves across, the whole line for `dd`, `cc` and `yy`.
+    fn operate (&mut self, operator: char, count: usize, key: char, arg: Option<char>, text: &mut Vec<char>, cursor: &mut usize) -> Action {
+        let (from, to) = if key == operator {
+            (0, text.len())
+        } else {
+            // `cw` on a word only changes up to its end, the blanks after it stay
+            let on_word = text.get(*cursor).is_some_and(|ch| !ch.is_whitespace());
+            let motion = if operator == 'c' && on_word && matches!(key, 'w' | 'W') {
+                let big = key == 'W';
+                Some(((1..count).fold(end_of_word(text, *cursor, big), |idx, _| word_end(text, idx, big)), true))
+            } else {
+                self.motion(key, arg, count, text, *cursor)
+            };
+
+            let Some((target, inclusive)) = motion else { return Action::Bell };
+            let (from, to) = if target < *cursor { (target, *cursor) } else { (*cursor, target) };
+            (from, if inclusive { (to + 1).min(text.len()) } else { to })
+        };
+
+        match operator {
+            'y' => {
+                self.register = text[from..to].to_vec();
+                if key != operator { *cursor = from; }
+            }
+            operator => {
+                self.register = text.drain(from..to).collect();
+                *cursor = from;
+
+                if operator == 'c' {
+                    self.mode = Mode::Insert;
+                    return Action::Done;
+                }
+            }
+        }
+
+        *cursor = clamp(text, *cursor);
+        Action::Done
+    }
+
+    fn command_key (&mut self, count: usize, key: char, arg: Option<char>, text: &mut Vec<char>, cursor: &mut usize) -> Action {
+        match key {
+            'i' => self.mode = Mode::Insert,
+            'a' => {
+                *cursor = (*cursor + 1).min(text.len());
+                self.mode = Mode::Insert;
+            }
+            'I' => {
+                *cursor = first_non_blank(text);
+                self.mode = Mode::Insert;
+            }
+            'A' => {
+                *cursor = text.len();
+                self.mode = Mode::Insert;
+            }
+            'x' | 's' if *cursor < text.len() => {
+                self.register = text.drain(*cursor..(*cursor + count).min(text.len())).collect();
+                if key == 's' {
+                    self.mode = Mode::Insert;
+                } else {
+                    *cursor = clamp(text, *cursor);
+                }
+            }
+            'X' if *cursor > 0 => {
+                let from = cursor.saturating_sub(count);
+                self.register = text.drain(from..*cursor).collect();
+                *cursor = from;
+            }
+            'S' => return self.operate('c', 1, 'c', None, text, cursor),
+            'D' => return self.operate('d', 1, '$', None, text, cursor),
+            'C' => return self.operate('c', 1, '$', None, text, cursor),
+            'r' if *cursor + count <= text.len() => {
+                let arg = arg.unwrap_or(' ');
+                text[*cursor..*cursor + count].fill(arg);
+                *cursor += count - 1;
+            }
+            '~' if *cursor < text.len() => {
+                let end = (*cursor + count).min(text.len());
+                for ch in &mut text[*cursor..end] {
+                    *ch = if ch.is_uppercase() { ch.to_lowercase().next() } else { ch.to_uppercase().next() }.unwrap_or(*ch);
+                }
+                *cursor = clamp(text, end);
+            }
+            'p' | 'P' if !self.register.is_empty() => {
+                let at = if key == 'p' && !text.is_empty() { (*cursor + 1).min(text.len()) } else { *cursor };
+                let pasted = self.register.repeat(count);
+
+                text.splice(at..at, pasted.iter().copied());
+                *cursor = at + pasted.len() - 1;
+            }
+            'u' => match self.undo.pop() {
+                Some((previous, at)) => {
+                    *text = previous;
+                    *cursor = clamp(text, at);
+                }
+                None => return Action::Bell,
+            },
+            '.' if !self.last_change.is_empty() => {
+                let keys = self.last_change.clone();
+                for _ in 0..count {
+                    for &key in &keys {
+                        self.key(key, text, cursor);
+                    }
+                }
+            }
+            'v' => return Action::Edit,
+            '/' => self.search = Some(String::new()),
+            'n' | 'N' => match &self.last_search {
+                Some(search) => return Action::Search(search.clone(), key == 'n'),
+                None => return Action::Bell,
+            },
+            'k' | '-' => return Action::History(count as isize),
+            'j' | '+' => return Action::History(-(count as isize)),
+            key if MOTIONS.contains(key) => match self.motion(key, arg, count, text, *cursor) {
+                Some((target, _)) => *cursor = clamp(text, target),
+                None => return Action::Bell,
+            },
+            _ => return Action::Bell,
+        }
+
+        Action::Done
+    }
+}
+
+/// Where `f`, `F`, `t` or `T` with `arg` goes, the `count`th one from the cursor.
+fn find (key: char, arg: char, count: usize, text: &[char], cursor: usize) -> Option<(usize, bool)> {
+    match key {
+        'f' | 't' => {
+            let found = text.iter().enumerate().skip(cursor + 1).filter(|&(_, &ch)| ch == arg).nth(count - 1)?.0;
+            Some((if key == 't' { found - 1 } else { found }, true))
+        }
+        _ => {
+            let found = text[..cursor].iter().enumerate().rev().filter(|&(_, &ch)| ch == arg).nth(count - 1)?.0;
+            Some((if key == 'T' { found + 1 } else { found }, false))
+        }
+    }
+}
+
+#[cfg(test)]
+mod vi_tests {
+    use super::*;
+
+    // types `keys` in normal mode on `text`, with the cursor under the `|` in it
+    fn typed (text: &str, keys: &str) -> (String, Vi) {
+        let mut cursor = text.find('|').unwrap();
+        let mut text: Vec<char> = text.replace('|', "").chars().collect();
+        let mut vi = Vi::new();
+        vi.mode = Mode::Normal;
+
+        for key in keys.chars() {
+            vi.key(key, &mut text, &mut cursor);
+        }
+
+        text.insert(cursor, '|');
+        (text.into_iter().collect(), vi)
+    }
+
+    fn edited (text: &str, keys: &str) -> String {
+        typed(text, keys).0
+    }
+
+    #[test]
+    fn motions () {
+        assert_eq!(edited("|echo foo.bar baz", "w"), "echo |foo.bar baz");
+        assert_eq!(edited("|echo foo.bar baz", "3w"), "echo foo.|bar baz");
+        assert_eq!(edited("|echo foo.bar baz", "2W"), "echo foo.bar |baz");
+        assert_eq!(edited("echo foo.bar |baz", "b"), "echo foo.|bar baz");
+        assert_eq!(edited("|echo foo.bar baz", "e"), "ech|o foo.bar baz");
+        assert_eq!(edited("  ec|ho", "0"), "|  echo");
+        assert_eq!(edited("  ec|ho", "^$"), "  ech|o");
+        assert_eq!(edited("|a,b,c,d", "2f,"), "a,b|,c,d");
+        assert_eq!(edited("|a,b,c,d", "t,;"), "a,|b,c,d");
+        assert_eq!(edited("|a,b,c,d", "f,;;,"), "a,b|,c,d");
+        assert_eq!(edited("a,b,c,|d", "Fb"), "a,|b,c,d");
+    }
+
+    #[test]
+    fn operators () {
+        assert_eq!(edited("|echo foo bar baz", "dw"), "|foo bar baz");
+        assert_eq!(edited("|echo foo bar baz", "d2w"), "|bar baz");
+        assert_eq!(edited("|echo foo bar baz", "2d2w"), "|");
+        assert_eq!(edited("echo foo |bar", "db"), "echo |bar");
+        assert_eq!(edited("echo |foo bar", "d$"), "echo| ");
+        assert_eq!(edited("echo |foo bar", "dtr"), "echo |r");
+        assert_eq!(edited("echo |foo bar", "cwx\x1b"), "echo |x bar");
+        assert_eq!(edited("echo |foo bar", "ccls\x1b"), "l|s");
+        assert_eq!(edited("echo |foo bar", "ywP"), "echo foo| foo bar");
+        assert_eq!(edited("echo |foo", "dd"), "|");
+        assert_eq!(edited("echo |foo", "dq"), "echo |foo");
+    }
+
+    #[test]
+    fn commands () {
+        assert_eq!(edited("|abcd", "2x"), "|cd");
+        assert_eq!(edited("ab|cd", "X"), "a|cd");
+        assert_eq!(edited("|abcd", "xp"), "b|acd");
+        assert_eq!(edited("|abcd", "3rx"), "xx|xd");
+        assert_eq!(edited("|abCd", "3~"), "ABc|d");
+        assert_eq!(edited("a|bcd", "DAe\x1b"), "a|e");
+        assert_eq!(edited("|echo", "A hi\x1bIx \x1b"), "x| echo hi");
+    }
+
+    #[test]
+    fn undo_and_repeat () {
+        assert_eq!(edited("|one two three", "dwu"), "|one two three");
+        assert_eq!(edited("|one two three", "dwxuu"), "|one two three");
+        assert_eq!(edited("|one two three", "dw."), "|three");
+        assert_eq!(edited("|a b c d", "cwx\x1bww."), "x b |x d");
+        assert_eq!(edited("|abcdef", "x2."), "|def");
+        assert_eq!(edited("|abc", "u"), "|abc");
+    }
+
+    #[test]
+    fn large_counts () {
+        assert_eq!(edited("|abc", "99999999999999999999x"), "|");
+        assert_eq!(edited("|abc", "99999d99999l"), "|");
+        assert_eq!(edited("|abc", "99999999999999999999l"), "ab|c");
+        assert_eq!(edited("|ab", "yl99999p").len(), 3 + MAX_COUNT);
+    }
+
+    #[test]
+    fn cursor_after_the_line () {
+        let mut text: Vec<char> = "abc".chars().collect();
+        let mut cursor = 0;
+        let mut vi = Vi::new();
+        vi.mode = Mode::Normal;
+
+        vi.key('y', &mut text, &mut cursor);
+        vi.key('l', &mut text, &mut cursor);
+
+        // End was handled by the line editor
+        cursor = text.len();
+        vi.key('p', &mut text, &mut cursor);
+        assert_eq!((text.iter().collect::<String>(), cursor), ("abca".to_string(), 3));
+    }
+
+    #[test]
+    fn searching () {
+        let mut text = Vec::new();
+        let mut cursor = 0;
+        let mut vi = Vi::new();
+
+        assert_eq!(vi.key('\x1b', &mut text, &mut cursor), Action::Done);
+        assert_eq!(vi.key('\r', &mut text, &mut cursor), Action::Pass);
+        assert_eq!(vi.key('n', &mut text, &mut cursor), Action::Bell);
+
+        for key in "/mak".chars() {
+            vi.key(key, &mut text, &mut cursor);
+        }
+        assert_eq!(vi.search_text(), Some("mak"));
+        assert_eq!(vi.key('\r', &mut text, &mut cursor), Action::Search("mak".to_string(), true));
+        assert_eq!(vi.key('N', &mut text, &mut cursor), Action::Search("mak".to_string(), false));
+        assert_eq!(vi.key('3', &mut text, &mut cursor), Action::Done);
+        assert_eq!(vi.key('k', &mut text, &mut cursor), Action::History(3));
+        assert_eq!(vi.key('v', &mut text, &mut cursor), Action::Edit);
+    }
+}